@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+/// A `/ToUnicode` CMap (9.10.3): the Unicode text for each character code of a font
+#[derive(Debug, Default)]
+pub struct ToUnicodeCMap {
+    /// Bytes per character code, taken from the widest source code in the map
+    pub code_length: usize,
+    pub mappings: HashMap<u32, String>
+}
+
+#[derive(Debug, PartialEq)]
+enum CMapToken {
+    Hex(Vec<u8>),
+    ArrayStart,
+    ArrayEnd,
+    Keyword(String)
+}
+
+fn hex_to_bytes(hex: &[u8]) -> Vec<u8> {
+    let digits: Vec<u8> = hex
+        .iter()
+        .filter_map(|digit| (*digit as char).to_digit(16).map(|digit| digit as u8))
+        .collect();
+
+    digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+        .collect()
+}
+
+fn tokenize(source: &[u8]) -> Vec<CMapToken> {
+    let mut tokens: Vec<CMapToken> = vec![];
+    let mut position = 0;
+
+    while position < source.len() {
+        match source[position] {
+            b'<' if source.get(position + 1) == Some(&b'<') => position += 2,
+            b'>' if source.get(position + 1) == Some(&b'>') => position += 2,
+            b'<' => {
+                let end = source[position..]
+                    .iter()
+                    .position(|byte| *byte == b'>')
+                    .map_or(source.len(), |end| position + end);
+                tokens.push(CMapToken::Hex(hex_to_bytes(&source[position + 1..end])));
+                position = end + 1;
+            },
+            b'[' => {
+                tokens.push(CMapToken::ArrayStart);
+                position += 1;
+            },
+            b']' => {
+                tokens.push(CMapToken::ArrayEnd);
+                position += 1;
+            },
+            b'%' => {
+                while position < source.len() && source[position] != b'\n' && source[position] != b'\r' {
+                    position += 1;
+                }
+            },
+            b'(' => {
+                // Literal strings only appear in the CMap header (registry, ordering)
+                while position < source.len() && source[position] != b')' {
+                    position += 1;
+                }
+                position += 1;
+            },
+            byte if byte.is_ascii_whitespace() => position += 1,
+            _ => {
+                let start = position;
+                while position < source.len() && !source[position].is_ascii_whitespace() && !b"<>[]()%".contains(&source[position]) {
+                    position += 1;
+                }
+                tokens.push(CMapToken::Keyword(String::from_utf8_lossy(&source[start..position]).to_string()));
+            }
+        }
+    }
+
+    tokens
+}
+
+fn bytes_to_code(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |code, byte| code << 8 | *byte as u32)
+}
+
+/// Destination strings are UTF-16BE
+fn utf16_to_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| (pair[0] as u16) << 8 | pair.get(1).copied().unwrap_or(0) as u16)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Adds `offset` to the last UTF-16 code unit, as bfrange destinations increment per code
+fn offset_destination(bytes: &[u8], offset: u32) -> String {
+    let mut bytes = bytes.to_vec();
+    if bytes.len() >= 2 {
+        let last = bytes.len() - 2;
+        let unit = ((bytes[last] as u32) << 8 | bytes[last + 1] as u32) + offset;
+        bytes[last] = (unit >> 8) as u8;
+        bytes[last + 1] = unit as u8;
+    }
+    utf16_to_string(&bytes)
+}
+
+pub fn parse_to_unicode(source: &[u8]) -> ToUnicodeCMap {
+    let tokens = tokenize(source);
+    let mut cmap = ToUnicodeCMap::default();
+    let mut position = 0;
+
+    while position < tokens.len() {
+        match &tokens[position] {
+            CMapToken::Keyword(keyword) if keyword == "beginbfchar" => {
+                position += 1;
+                while let (Some(CMapToken::Hex(source_code)), Some(CMapToken::Hex(destination))) = (tokens.get(position), tokens.get(position + 1)) {
+                    cmap.code_length = cmap.code_length.max(source_code.len());
+                    cmap.mappings.insert(bytes_to_code(source_code), utf16_to_string(destination));
+                    position += 2;
+                }
+            },
+            CMapToken::Keyword(keyword) if keyword == "beginbfrange" => {
+                position += 1;
+                while let (Some(CMapToken::Hex(low)), Some(CMapToken::Hex(high))) = (tokens.get(position), tokens.get(position + 1)) {
+                    cmap.code_length = cmap.code_length.max(low.len());
+                    let (low_code, high_code) = (bytes_to_code(low), bytes_to_code(high));
+                    position += 2;
+
+                    match tokens.get(position) {
+                        Some(CMapToken::Hex(destination)) => {
+                            for code in low_code..=high_code {
+                                cmap.mappings.insert(code, offset_destination(destination, code - low_code));
+                            }
+                            position += 1;
+                        },
+                        Some(CMapToken::ArrayStart) => {
+                            position += 1;
+                            let mut code = low_code;
+                            while let Some(CMapToken::Hex(destination)) = tokens.get(position) {
+                                cmap.mappings.insert(code, utf16_to_string(destination));
+                                code += 1;
+                                position += 1;
+                            }
+                            if tokens.get(position) == Some(&CMapToken::ArrayEnd) {
+                                position += 1;
+                            }
+                        },
+                        _ => break
+                    }
+                }
+            },
+            _ => position += 1
+        }
+    }
+
+    if cmap.code_length == 0 {
+        cmap.code_length = 1;
+    }
+
+    cmap
+}
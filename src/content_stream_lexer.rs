@@ -1,16 +1,29 @@
-
-use log::debug;
 use nom::{
     branch::alt,
-    bytes::complete::{take, tag, take_till1, take_until, is_not, escaped},
-    character::complete::{char, digit1, line_ending, multispace0, multispace1, u64, alpha1, newline, alphanumeric1, none_of},
-    combinator::{eof, recognize, map_res, map, verify},
-    multi::{many0, many_till, count, many1, fold_many0, fold_many1},
-    sequence::{delimited, pair, preceded, separated_pair, tuple},
-    IResult, Parser, number::complete::double,
+    bytes::complete::{tag, is_not, take_while1},
+    character::complete::{char, multispace0, multispace1, i64, u64, alphanumeric1, satisfy},
+    combinator::{map, not, opt, recognize},
+    multi::{many0, count, many1, many_m_n},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult, number::complete::double,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyList {
+    Named(String), // Entry in the resources' /Properties
+    Inline(Vec<(String, u64)>)
+}
+
+/// An element of a TJ array
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextArrayElement {
+    Text(String),
+    /// In thousandths of text space, subtracted from the horizontal position; large
+    /// negative values usually mean a word break
+    Adjustment(f64)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ContentToken {
     Cm(Vec<f64>),
     BeginMarkedContent(String),
@@ -18,21 +31,90 @@ pub enum ContentToken {
     StrokingColorSpaceGrey(f64),
     ColorSpaceGrey(f64),
     LineWidth(f64),
+    SetLineCap(i64), // J
+    SetLineJoin(i64), // j
+    SetMiterLimit(f64), // M
     Move((f64, f64)),
     Line((f64, f64)),
+    CurveTo(Vec<f64>), // c: x1 y1 x2 y2 x3 y3
+    CurveToV(Vec<f64>), // v: x2 y2 x3 y3, the first control point is the current point
+    CurveToY(Vec<f64>), // y: x1 y1 x3 y3, the second control point is the end point
+    Rectangle([f64; 4]), // re: x y width height
+    ClosePath, // h
+    Clip, // W
+    ClipEvenOdd, // W*
     StrokePath,
-    BeginMarkedContentWithProperties,
+    BeginMarkedContentWithProperties((String, PropertyList)),
     BeginTextObject,
     EndTextObject,
     SetTextMatrix(Vec<f64>), // Tm
+    MoveTextPosition((f64, f64)), // Td
+    MoveTextPositionSetLeading((f64, f64)), // TD, which also sets the leading to -ty
+    NextLine, // T*
+    SetCharSpacing(f64), // Tc
+    SetWordSpacing(f64), // Tw
+    SetHorizontalScaling(f64), // Tz, as a percentage
+    SetTextLeading(f64), // TL
+    SetTextRise(f64), // Ts
+    SetTextRenderMode(i64), // Tr
     TextFont((String, f64)),
     ShowTextString(String),
+    ShowTextArray(Vec<TextArrayElement>), // TJ
+    NextLineShowText(String), // '
+    NextLineShowTextSpacing { aw: f64, ac: f64, text: String }, // ", which also sets Tw to aw and Tc to ac
     SetFlatnessTolerance(f64),
     EndPath,
     FillPathEvenOdd,
+    FillPath, // f, or its obsolete synonym F
+    FillStrokePath, // B
+    FillStrokePathEvenOdd, // B*
+    CloseFillStrokePath, // b
+    CloseFillStrokePathEvenOdd, // b*
     SaveGraphicsState,
     RestoreGraphicsState,
-    PaintXObject(String)
+    PaintXObject(String),
+    SetExtGState(String), // gs
+    SetDashPattern { array: Vec<f64>, phase: f64 }, // d; an empty array means a solid line
+    /// BI ... ID ... EI. Parameter values are kept as their raw operand text, since they
+    /// may be names, numbers, arrays or dictionaries.
+    InlineImage { params: Vec<(String, String)>, data: Vec<u8> },
+    SetFillColorN(Vec<f64>, Option<String>), // scn
+    SetStrokeColorN(Vec<f64>, Option<String>), // SCN
+    SetFillColor(Vec<f64>), // sc
+    SetStrokeColor(Vec<f64>), // SC
+    FillColorRGB((f64, f64, f64)), // rg
+    StrokeColorRGB((f64, f64, f64)), // RG
+    FillColorCMYK([f64; 4]), // k
+    StrokeColorCMYK([f64; 4]), // K
+    SetFillColorSpace(String), // cs
+    SetStrokeColorSpace(String), // CS
+    Unknown(String)
+}
+
+fn is_regular_char(c: u8) -> bool {
+    !c.is_ascii_whitespace() && !b"()<>[]{}/%".contains(&c)
+}
+
+/// Matches an operator keyword, making sure it is not just the prefix of a longer one
+fn operator<'a>(name: &'static str) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
+    terminated(tag(name), not(satisfy(|c| is_regular_char(c as u8))))
+}
+
+/// Reads up to `components` colour operands, defaulting any that are missing to 0 so a
+/// malformed colour operator still produces a (black) colour instead of stalling the lexer
+fn parse_color_components<'a>(components: usize) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<f64>> {
+    map(
+        many_m_n(0, components, terminated(double, multispace1)),
+        move |mut values| {
+            values.resize(components, 0.0);
+            values
+        }
+    )
+}
+
+/// A single number followed by the operator `name`, as used by the text state operators
+fn single_operand<'a>(name: &'static str) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], f64> {
+    terminated(double, pair(multispace1, operator(name)))
 }
 
 fn parse_tag(start_inp: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -46,12 +128,108 @@ fn parse_tag(start_inp: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((inp, value))
 }
 
+/// The body of a literal string, which may contain balanced pairs of unescaped
+/// parentheses (7.3.4.2)
 fn parse_string(start_inp: &[u8]) -> IResult<&[u8], &[u8]> {
-    let esc = escaped(none_of("\\)("), '\\', alt((tag(")"), tag("("))));
-    let esc_or_empty = alt((esc, tag("")));
-    let res = delimited(tag("("), esc_or_empty, tag(")"))(start_inp)?;
+    let (inp, _) = char('(')(start_inp)?;
+    let mut depth = 0;
+    let mut position = 0;
+
+    while position < inp.len() {
+        match inp[position] {
+            b'\\' => position += 1,
+            b'(' => depth += 1,
+            b')' if depth == 0 => return Ok((&inp[position + 1..], &inp[..position])),
+            b')' => depth -= 1,
+            _ => {}
+        }
+        position += 1;
+    }
+
+    Err(nom::Err::Error(nom::error::Error::new(start_inp, nom::error::ErrorKind::Char)))
+}
+
+/// Resolves the escape sequences of a literal string body (7.3.4.2)
+fn unescape_literal_string(raw: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![];
+    let mut position = 0;
+
+    while position < raw.len() {
+        if raw[position] != b'\\' || position + 1 == raw.len() {
+            bytes.push(raw[position]);
+            position += 1;
+            continue;
+        }
+
+        position += 1;
+        match raw[position] {
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b't' => bytes.push(b'\t'),
+            b'b' => bytes.push(0x08),
+            b'f' => bytes.push(0x0C),
+            b'0'..=b'7' => {
+                let digits = raw[position..]
+                    .iter()
+                    .take(3)
+                    .take_while(|digit| (b'0'..=b'7').contains(*digit))
+                    .count();
+                let value = raw[position..position + digits]
+                    .iter()
+                    .fold(0u32, |value, digit| value * 8 + (digit - b'0') as u32);
+                bytes.push(value as u8);
+                position += digits;
+                continue;
+            },
+            // A backslash before an end of line continues the string on the next line
+            b'\r' => {
+                if raw.get(position + 1) == Some(&b'\n') {
+                    position += 1;
+                }
+            },
+            b'\n' => {},
+            other => bytes.push(other)
+        }
+        position += 1;
+    }
+
+    bytes
+}
+
+/// Content stream strings are character codes for the current font, not text, so each
+/// byte is kept as the char of the same value for the font to decode later
+fn codes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+/// The bytes of a hexadecimal string body, skipping white-space and padding an odd final
+/// digit with 0 (7.3.4.3)
+fn decode_hex_string(raw: &[u8]) -> Vec<u8> {
+    let digits: Vec<u8> = raw
+        .iter()
+        .filter_map(|digit| (*digit as char).to_digit(16).map(|value| value as u8))
+        .collect();
+    digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+        .collect()
+}
 
-    Ok(res)
+fn parse_hex_string(start_inp: &[u8]) -> IResult<&[u8], &[u8]> {
+    delimited(
+        char('<'),
+        recognize(many0(satisfy(|c| c.is_ascii_hexdigit() || c.is_ascii_whitespace()))),
+        char('>')
+    )(start_inp)
+}
+
+/// The character codes of a string operand, written either as a literal `(...)` or as
+/// hexadecimal `<...>`, which is how most CID-keyed text is shown
+fn parse_text_string(start_inp: &[u8]) -> IResult<&[u8], String> {
+    alt((
+        map(parse_string, |value| codes_to_string(&unescape_literal_string(value))),
+        map(parse_hex_string, |value| codes_to_string(&decode_hex_string(value)))
+    ))(start_inp)
 }
 
 fn parse_dictionary(start_inp: &[u8]) -> IResult<&[u8], Vec<(&[u8], u64)>> {
@@ -75,17 +253,29 @@ fn parse_dictionary(start_inp: &[u8]) -> IResult<&[u8], Vec<(&[u8], u64)>> {
 }
 
 fn parse_bdc(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let property_list = alt((
+        map(parse_dictionary, |entries| PropertyList::Inline(
+            entries
+                .into_iter()
+                .map(|(key, value)| (String::from_utf8_lossy(key).to_string(), value))
+                .collect()
+        )),
+        map(parse_tag, |name| PropertyList::Named(String::from_utf8_lossy(name).to_string()))
+    ));
+
     let (inp, value) = map(separated_pair(separated_pair(
         parse_tag, 
         multispace0,
-        parse_dictionary
-    ), multispace0, tag("BDC")), |value| ContentToken::BeginMarkedContentWithProperties)(start_inp)?;
+        property_list
+    ), multispace0, tag("BDC")), |value| ContentToken::BeginMarkedContentWithProperties(
+        (String::from_utf8_lossy(value.0.0).to_string(), value.0.1)
+    ))(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_stroke_path(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, char('S'), multispace1), |value| ContentToken::StrokePath)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, char('S'), multispace1), |_| ContentToken::StrokePath)(start_inp)?;
 
     Ok((inp, value))
 }
@@ -105,6 +295,25 @@ fn parse_line(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     Ok((inp, value))
 }
 
+fn parse_curve_to(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(count(terminated(double, multispace1), 6), operator("c")), ContentToken::CurveTo),
+        map(terminated(count(terminated(double, multispace1), 4), operator("v")), ContentToken::CurveToV),
+        map(terminated(count(terminated(double, multispace1), 4), operator("y")), ContentToken::CurveToY)
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_rectangle(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(
+        terminated(count(terminated(double, multispace1), 4), operator("re")),
+        |value| ContentToken::Rectangle([value[0], value[1], value[2], value[3]])
+    )(start_inp)?;
+
+    Ok((inp, value))
+}
+
 fn parse_move(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     let (inp, value) = map(
         separated_pair(
@@ -126,14 +335,84 @@ fn parse_line_width(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     Ok((inp, value))
 }
 
+fn parse_line_style(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(i64, pair(multispace1, operator("J"))), ContentToken::SetLineCap),
+        map(terminated(i64, pair(multispace1, operator("j"))), ContentToken::SetLineJoin),
+        map(single_operand("M"), ContentToken::SetMiterLimit)
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
 fn parse_color_space_grey(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(separated_pair(double, multispace1, char('g')), |value| ContentToken::ColorSpaceGrey(value.0))(start_inp)?;
+    let (inp, value) = map(pair(parse_color_components(1), operator("g")), |value| ContentToken::ColorSpaceGrey(value.0[0]))(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_g(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(separated_pair(double, multispace1, char('G')), |value| ContentToken::StrokingColorSpaceGrey(value.0))(start_inp)?;
+    let (inp, value) = map(pair(parse_color_components(1), operator("G")), |value| ContentToken::StrokingColorSpaceGrey(value.0[0]))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_rgb(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(parse_color_components(3), operator("rg")), |value| ContentToken::FillColorRGB((value[0], value[1], value[2]))),
+        map(terminated(parse_color_components(3), operator("RG")), |value| ContentToken::StrokeColorRGB((value[0], value[1], value[2])))
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_cmyk(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(parse_color_components(4), operator("k")), |value| ContentToken::FillColorCMYK([value[0], value[1], value[2], value[3]])),
+        map(terminated(parse_color_components(4), operator("K")), |value| ContentToken::StrokeColorCMYK([value[0], value[1], value[2], value[3]]))
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_color_space(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(parse_tag, operator("cs")), |name| ContentToken::SetFillColorSpace(String::from_utf8_lossy(name).to_string())),
+        map(terminated(parse_tag, operator("CS")), |name| ContentToken::SetStrokeColorSpace(String::from_utf8_lossy(name).to_string()))
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+/// Operands of scn/SCN: any number of colour components, then a pattern name when
+/// the colour space is /Pattern (e.g. `/P0 scn` or `0.2 0.4 1 /P1 scn`)
+fn parse_color_n_operands(start_inp: &[u8]) -> IResult<&[u8], (Vec<f64>, Option<String>)> {
+    let (inp, value) = pair(
+        many0(terminated(double, multispace1)),
+        opt(map(parse_tag, |name| String::from_utf8_lossy(name).to_string()))
+    )(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_fill_color_n(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(pair(parse_color_n_operands, operator("scn")), |value| ContentToken::SetFillColorN(value.0.0, value.0.1))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_stroke_color_n(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(pair(parse_color_n_operands, operator("SCN")), |value| ContentToken::SetStrokeColorN(value.0.0, value.0.1))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+/// sc/SC are scn/SCN without the pattern form, so only take numbers
+fn parse_set_color(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(terminated(many0(terminated(double, multispace1)), operator("sc")), ContentToken::SetFillColor),
+        map(terminated(many0(terminated(double, multispace1)), operator("SC")), ContentToken::SetStrokeColor)
+    ))(start_inp)?;
 
     Ok((inp, value))
 }
@@ -159,7 +438,7 @@ fn parse_bmc(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
 fn parse_begin_text_object(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     let (inp, value) = map(
         tag("BT"),
-        |value| ContentToken::BeginTextObject)(start_inp)?;
+        |_| ContentToken::BeginTextObject)(start_inp)?;
 
     Ok((inp, value))
 }
@@ -167,19 +446,35 @@ fn parse_begin_text_object(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
 fn parse_end_text_object(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     let (inp, value) = map(
         tag("ET"),
-        |value| ContentToken::EndTextObject)(start_inp)?;
+        |_| ContentToken::EndTextObject)(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_end_marked_content(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, tag("EMC"), multispace1), |value| ContentToken::EndMarkedContent)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, tag("EMC"), multispace1), |_| ContentToken::EndMarkedContent)(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_end_path(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, char('n'), multispace1), |value| ContentToken::EndPath)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, char('n'), multispace1), |_| ContentToken::EndPath)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_close_path(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(operator("h"), |_| ContentToken::ClosePath)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+/// W and W* only mark the path as a clip; the painting operator (usually n) follows
+fn parse_clip(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(operator("W*"), |_| ContentToken::ClipEvenOdd),
+        map(operator("W"), |_| ContentToken::Clip)
+    ))(start_inp)?;
 
     Ok((inp, value))
 }
@@ -199,8 +494,6 @@ fn parse_cm(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
              |value| ContentToken::Cm(value.0)
         )(start_inp)?;
 
-    // dbg!(&value);
-
     Ok((inp, value))
 }
 
@@ -222,6 +515,63 @@ fn parse_set_text_matrix(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     Ok((inp, value))
 }
 
+fn parse_move_text_position(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(
+            terminated(separated_pair(double, multispace1, double), pair(multispace1, operator("Td"))),
+            ContentToken::MoveTextPosition
+        ),
+        map(
+            terminated(separated_pair(double, multispace1, double), pair(multispace1, operator("TD"))),
+            ContentToken::MoveTextPositionSetLeading
+        )
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_next_line(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(operator("T*"), |_| ContentToken::NextLine)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_char_spacing(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(single_operand("Tc"), ContentToken::SetCharSpacing)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_word_spacing(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(single_operand("Tw"), ContentToken::SetWordSpacing)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_horizontal_scaling(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(single_operand("Tz"), ContentToken::SetHorizontalScaling)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_text_leading(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(single_operand("TL"), ContentToken::SetTextLeading)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_text_rise(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(single_operand("Ts"), ContentToken::SetTextRise)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_text_render_mode(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(terminated(i64, pair(multispace1, operator("Tr"))), ContentToken::SetTextRenderMode)(start_inp)?;
+
+    Ok((inp, value))
+}
+
 fn parse_set_text_font(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     let (inp, value) = map(
         tuple((
@@ -247,32 +597,90 @@ fn parse_set_text_font(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
 fn parse_show_text_string(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     let (inp, value) = map(
         separated_pair(
-            parse_string,
+            parse_text_string,
             multispace0,
             tag("Tj"),
         ),
-             |value| ContentToken::ShowTextString(String::from_utf8_lossy(value.0).to_string())
+             |value| ContentToken::ShowTextString(value.0)
         )(start_inp)?;
-    
-    // dbg!(&value);
+
+    Ok((inp, value))
+}
+
+fn parse_next_line_show_text(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(
+            terminated(parse_text_string, pair(multispace0, operator("'"))),
+            ContentToken::NextLineShowText
+        ),
+        map(
+            terminated(
+                tuple((terminated(double, multispace1), terminated(double, multispace1), parse_text_string)),
+                pair(multispace0, operator("\""))
+            ),
+            |(aw, ac, text)| ContentToken::NextLineShowTextSpacing { aw, ac, text }
+        )
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_text_array_element(start_inp: &[u8]) -> IResult<&[u8], TextArrayElement> {
+    let (inp, value) = alt((
+        map(parse_text_string, TextArrayElement::Text),
+        map(double, TextArrayElement::Adjustment)
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_show_text_array(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(
+        terminated(
+            delimited(
+                char('['),
+                many0(delimited(multispace0, parse_text_array_element, multispace0)),
+                char(']')
+            ),
+            pair(multispace0, operator("TJ"))
+        ),
+        ContentToken::ShowTextArray
+    )(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_fill_path_even_odd(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, tag("f*"), multispace1), |value| ContentToken::FillPathEvenOdd)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, tag("f*"), multispace1), |_| ContentToken::FillPathEvenOdd)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_fill_path(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(alt((operator("f"), operator("F"))), |_| ContentToken::FillPath)(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_fill_stroke_path(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = alt((
+        map(operator("B*"), |_| ContentToken::FillStrokePathEvenOdd),
+        map(operator("B"), |_| ContentToken::FillStrokePath),
+        map(operator("b*"), |_| ContentToken::CloseFillStrokePathEvenOdd),
+        map(operator("b"), |_| ContentToken::CloseFillStrokePath)
+    ))(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_save_graphics_state(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, char('q'), multispace1), |value| ContentToken::SaveGraphicsState)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, char('q'), multispace1), |_| ContentToken::SaveGraphicsState)(start_inp)?;
 
     Ok((inp, value))
 }
 
 fn parse_restore_graphics_state(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
-    let (inp, value) = map(delimited(multispace0, char('Q'), multispace1), |value| ContentToken::RestoreGraphicsState)(start_inp)?;
+    let (inp, value) = map(delimited(multispace0, char('Q'), multispace1), |_| ContentToken::RestoreGraphicsState)(start_inp)?;
 
     Ok((inp, value))
 }
@@ -288,102 +696,373 @@ fn parse_paint_x_object(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
     Ok((inp, value))
 }
 
-pub fn parse(source: &[u8]) -> Vec<ContentToken> {
-    // let result = many0(
-    //     alt((
-    //         parse_cm,
-    //         parse_bmc
-    //     )))(source);
-
-    // let result: IResult<&[u8], Vec<ContentToken>> = many0(
-    //     delimited(alt((multispace0, newline)), alt((
-    //         parse_cm,
-    //         parse_bmc
-    //     )), alt((multispace0, newline)))
-    // )(source);
-
-    // let a= parse_string("(Account)Tj".as_bytes());
-    // dbg!(a);
-    // panic!();
-
-    // let result: IResult<&[u8], Vec<ContentToken>> = many0(
-    //     delimited(
-    //         multispace0,
-    //         alt((
-    //             parse_cm,
-    //             parse_bmc,
-    //             parse_end_marked_content,
-    //             parse_g,
-    //             parse_line_width,
-    //             parse_move,
-    //             parse_line,
-    //             parse_stroke_path,
-    //             parse_bdc,
-    //             parse_color_space_grey,
-    //             parse_begin_text_object,
-    //             parse_end_text_object,
-    //             parse_set_text_matrix,
-    //             parse_set_text_font,
-    //             parse_show_text_string,
-    //             parse_flatness_tolerance,
-    //             parse_end_path,
-    //             parse_fill_path_even_odd,
-    //             parse_save_graphics_state,
-    //             parse_restore_graphics_state,
-    //             parse_paint_x_object
-    //         )), 
-    //         multispace0)
-    // )(source);
-
-    // dbg!(String::from_utf8_lossy(source));
-    // panic!();
-
-    let result: IResult<&[u8], Vec<ContentToken>> = many0(
-        delimited(
+fn parse_set_dash_pattern(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(
+        terminated(
+            separated_pair(
+                delimited(char('['), many0(delimited(multispace0, double, multispace0)), char(']')),
+                multispace0,
+                double
+            ),
+            pair(multispace1, operator("d"))
+        ),
+        |(array, phase)| ContentToken::SetDashPattern { array, phase }
+    )(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_set_ext_g_state(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(
+        terminated(parse_tag, operator("gs")),
+        |name| ContentToken::SetExtGState(String::from_utf8_lossy(name).to_string())
+    )(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_operand(start_inp: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (inp, value) = alt((
+        recognize(double),
+        recognize(preceded(char('/'), take_while1(is_regular_char))),
+        recognize(parse_string),
+        recognize(delimited(tag("<<"), many0(delimited(multispace0, parse_operand, multispace0)), tag(">>"))),
+        recognize(delimited(char('<'), is_not(">"), char('>'))),
+        recognize(delimited(char('['), many0(delimited(multispace0, parse_operand, multispace0)), char(']')))
+    ))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+/// The image data after ID runs to an EI with whitespace on both sides, unless the
+/// dictionary gives its length (/L or /Length, PDF 2.0)
+fn parse_inline_image(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, params) = delimited(
+        pair(operator("BI"), multispace0),
+        many0(separated_pair(
+            preceded(char('/'), take_while1(is_regular_char)),
             multispace0,
-            alt((
-                parse_cm,
-                parse_bmc,
-                parse_end_marked_content,
-                parse_g,
-                parse_line_width,
-                parse_move,
-                parse_line,
-                parse_stroke_path,
-                parse_bdc,
-                parse_color_space_grey,
-                parse_begin_text_object,
-                parse_end_text_object,
-                parse_set_text_matrix,
-                parse_set_text_font,
-                parse_show_text_string,
-                parse_flatness_tolerance,
-                parse_end_path,
-                parse_fill_path_even_odd,
-                parse_save_graphics_state,
-                parse_restore_graphics_state,
-                parse_paint_x_object
-            )), 
-            multispace0)
-    )(source);
-    
-    let result = result.unwrap();
-    // dbg!(result.unwrap().1);
+            terminated(alt((parse_operand, take_while1(is_regular_char))), multispace0)
+        )),
+        operator("ID")
+    )(start_inp)?;
+
+    let params: Vec<(String, String)> = params
+        .into_iter()
+        .map(|(key, value)| (String::from_utf8_lossy(key).to_string(), String::from_utf8_lossy(value).to_string()))
+        .collect();
+
+    // A single whitespace byte separates ID from the data
+    let data_start = match inp.first() {
+        Some(byte) if byte.is_ascii_whitespace() => &inp[1..],
+        _ => inp
+    };
+
+    let length = params
+        .iter()
+        .find(|(key, _)| key == "L" || key == "Length")
+        .and_then(|(_, length)| length.parse::<usize>().ok())
+        .filter(|length| *length <= data_start.len());
+
+    let data_end = match length {
+        Some(length) => Some(length),
+        None => (0..data_start.len().saturating_sub(1)).find(|position| {
+            &data_start[*position..*position + 2] == b"EI"
+                && (*position == 0 || data_start[*position - 1].is_ascii_whitespace())
+                && data_start.get(position + 2).is_none_or(|byte| byte.is_ascii_whitespace())
+        })
+    };
+
+    let data_end = match data_end {
+        Some(data_end) => data_end,
+        None => return Err(nom::Err::Error(nom::error::Error::new(start_inp, nom::error::ErrorKind::Tag)))
+    };
+
+    let (inp, _) = preceded(multispace0, operator("EI"))(&data_start[data_end..])?;
+
+    // Without a length, the whitespace before EI is a delimiter rather than data
+    let mut data = &data_start[..data_end];
+    if length.is_none() {
+        if let Some(last) = data.last() {
+            if last.is_ascii_whitespace() {
+                data = &data[..data.len() - 1];
+            }
+        }
+    }
+
+    Ok((inp, ContentToken::InlineImage { params, data: data.to_vec() }))
+}
+
+/// Fallback for operators the lexer does not understand (or that have the wrong operands).
+/// The operands are skipped so the rest of the content stream can still be read.
+fn parse_unknown(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    let (inp, value) = map(
+        preceded(
+            many0(terminated(parse_operand, multispace0)),
+            take_while1(is_regular_char)
+        ),
+        |value| ContentToken::Unknown(String::from_utf8_lossy(value).to_string()))(start_inp)?;
+
+    Ok((inp, value))
+}
+
+fn parse_token(start_inp: &[u8]) -> IResult<&[u8], ContentToken> {
+    // Grouped by kind (graphics state, paths, marked content and images, text, colour),
+    // as alt takes at most 21 parsers
+    alt((
+        alt((
+            parse_cm,
+            parse_save_graphics_state,
+            parse_restore_graphics_state,
+            parse_line_width,
+            parse_line_style,
+            parse_flatness_tolerance,
+            parse_set_ext_g_state,
+            parse_set_dash_pattern
+        )),
+        alt((
+            parse_move,
+            parse_line,
+            parse_curve_to,
+            parse_rectangle,
+            parse_close_path,
+            parse_clip,
+            parse_stroke_path,
+            parse_end_path,
+            // f* before f, so the even-odd fill is never read as a plain fill
+            parse_fill_path_even_odd,
+            parse_fill_path,
+            parse_fill_stroke_path
+        )),
+        alt((
+            parse_bmc,
+            parse_bdc,
+            parse_end_marked_content,
+            parse_paint_x_object,
+            parse_inline_image
+        )),
+        alt((
+            parse_begin_text_object,
+            parse_end_text_object,
+            parse_set_text_matrix,
+            parse_move_text_position,
+            parse_next_line,
+            parse_set_char_spacing,
+            parse_set_word_spacing,
+            parse_set_horizontal_scaling,
+            parse_set_text_leading,
+            parse_set_text_rise,
+            parse_set_text_render_mode,
+            parse_set_text_font,
+            parse_show_text_string,
+            parse_show_text_array,
+            parse_next_line_show_text
+        )),
+        alt((
+            parse_g,
+            parse_color_space_grey,
+            parse_rgb,
+            parse_cmyk,
+            parse_color_space,
+            parse_set_fill_color_n,
+            parse_set_stroke_color_n,
+            parse_set_color
+        )),
+        parse_unknown
+    ))(start_inp)
+}
+
+/// Lexes a content stream one token at a time, handing each to `callback` as soon as it is
+/// read so the whole token list never has to be held in memory
+pub fn parse_with<F: FnMut(ContentToken)>(source: &[u8], mut callback: F) -> Result<(), String> {
+    let mut remaining = source;
+
+    loop {
+        let (after_whitespace, _) = multispace0::<&[u8], nom::error::Error<&[u8]>>(remaining)
+            .map_err(|err| format!("Could not lex content stream: {err:?}"))?;
+        remaining = after_whitespace;
+        if remaining.is_empty() {
+            return Ok(());
+        }
+
+        match parse_token(remaining) {
+            Ok((after_token, token)) if after_token.len() < remaining.len() => {
+                callback(token);
+                remaining = after_token;
+            },
+            _ => {
+                let offset = source.len() - remaining.len();
+                let context = String::from_utf8_lossy(&remaining[..remaining.len().min(20)]);
+                return Err(format!("Could not lex content stream at byte {offset}: '{context}'"));
+            }
+        }
+    }
+}
+
+/// Lexes a whole content stream, failing rather than dropping whatever follows the
+/// first byte no parser recognizes
+pub fn parse(source: &[u8]) -> Result<Vec<ContentToken>, String> {
+    let mut tokens: Vec<ContentToken> = vec![];
+    parse_with(source, |token| tokens.push(token))?;
+
+    Ok(tokens)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ContentToken::*;
+
+    #[test]
+    fn every_operator_lexes_to_its_token() {
+        let source = b"q 1 0 0 1 10 20 cm 2 w 1 J 0 j 4 M 0.5 i /GS0 gs [3 1] 0 d
+10 10 m 20 20 l 1 2 3 4 5 6 c 1 2 3 4 v 1 2 3 4 y 0 0 5 5 re h W W* n S f f* F B B* b b* Q
+/Span BMC /P <</MCID 3>> BDC /Artifact /Props BDC EMC /Im1 Do
+BI /W 1 /H 1 /BPC 8 /CS /G ID \xff EI
+BT 1 0 0 1 72 700 Tm 0 -14 Td 0 -14 TD T* 1 Tc 2 Tw 90 Tz 12 TL 3 Ts 0 Tr /F1 12 Tf
+(a) Tj [(A) -120 (B)] TJ (x) ' 1 2 (y) \" ET
+0.5 g 0.5 G 1 0 0 rg 0 1 0 RG 0 0 0 1 k 0 0 0 1 K /DeviceRGB cs /DeviceRGB CS
+0.1 0.2 0.3 sc 0.3 SC 0.1 /P1 scn 0.2 SCN sh";
+
+        assert_eq!(parse(source).unwrap(), vec![
+            SaveGraphicsState,
+            Cm(vec![1.0, 0.0, 0.0, 1.0, 10.0, 20.0]),
+            LineWidth(2.0),
+            SetLineCap(1),
+            SetLineJoin(0),
+            SetMiterLimit(4.0),
+            SetFlatnessTolerance(0.5),
+            SetExtGState("GS0".to_string()),
+            SetDashPattern { array: vec![3.0, 1.0], phase: 0.0 },
+            Move((10.0, 10.0)),
+            Line((20.0, 20.0)),
+            CurveTo(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+            CurveToV(vec![1.0, 2.0, 3.0, 4.0]),
+            CurveToY(vec![1.0, 2.0, 3.0, 4.0]),
+            Rectangle([0.0, 0.0, 5.0, 5.0]),
+            ClosePath,
+            Clip,
+            ClipEvenOdd,
+            EndPath,
+            StrokePath,
+            FillPath,
+            FillPathEvenOdd,
+            FillPath,
+            FillStrokePath,
+            FillStrokePathEvenOdd,
+            CloseFillStrokePath,
+            CloseFillStrokePathEvenOdd,
+            RestoreGraphicsState,
+            BeginMarkedContent("Span".to_string()),
+            BeginMarkedContentWithProperties(("P".to_string(), PropertyList::Inline(vec![("MCID".to_string(), 3)]))),
+            BeginMarkedContentWithProperties(("Artifact".to_string(), PropertyList::Named("Props".to_string()))),
+            EndMarkedContent,
+            PaintXObject("Im1".to_string()),
+            InlineImage {
+                params: vec![
+                    ("W".to_string(), "1".to_string()),
+                    ("H".to_string(), "1".to_string()),
+                    ("BPC".to_string(), "8".to_string()),
+                    ("CS".to_string(), "/G".to_string())
+                ],
+                data: vec![0xFF]
+            },
+            BeginTextObject,
+            SetTextMatrix(vec![1.0, 0.0, 0.0, 1.0, 72.0, 700.0]),
+            MoveTextPosition((0.0, -14.0)),
+            MoveTextPositionSetLeading((0.0, -14.0)),
+            NextLine,
+            SetCharSpacing(1.0),
+            SetWordSpacing(2.0),
+            SetHorizontalScaling(90.0),
+            SetTextLeading(12.0),
+            SetTextRise(3.0),
+            SetTextRenderMode(0),
+            TextFont(("F1".to_string(), 12.0)),
+            ShowTextString("a".to_string()),
+            ShowTextArray(vec![
+                TextArrayElement::Text("A".to_string()),
+                TextArrayElement::Adjustment(-120.0),
+                TextArrayElement::Text("B".to_string())
+            ]),
+            NextLineShowText("x".to_string()),
+            NextLineShowTextSpacing { aw: 1.0, ac: 2.0, text: "y".to_string() },
+            EndTextObject,
+            ColorSpaceGrey(0.5),
+            StrokingColorSpaceGrey(0.5),
+            FillColorRGB((1.0, 0.0, 0.0)),
+            StrokeColorRGB((0.0, 1.0, 0.0)),
+            FillColorCMYK([0.0, 0.0, 0.0, 1.0]),
+            StrokeColorCMYK([0.0, 0.0, 0.0, 1.0]),
+            SetFillColorSpace("DeviceRGB".to_string()),
+            SetStrokeColorSpace("DeviceRGB".to_string()),
+            SetFillColor(vec![0.1, 0.2, 0.3]),
+            SetStrokeColor(vec![0.3]),
+            SetFillColorN(vec![0.1], Some("P1".to_string())),
+            SetStrokeColorN(vec![0.2], None),
+            Unknown("sh".to_string())
+        ]);
+    }
+
+    #[test]
+    fn colour_operators_with_missing_operands_default_to_zero() {
+        assert_eq!(parse(b"1 0 rg 0.5 K BT (Hi) Tj ET").unwrap(), vec![
+            FillColorRGB((1.0, 0.0, 0.0)),
+            StrokeColorCMYK([0.5, 0.0, 0.0, 0.0]),
+            BeginTextObject,
+            ShowTextString("Hi".to_string()),
+            EndTextObject
+        ]);
+    }
 
-    result.1
+    #[test]
+    fn scn_takes_an_optional_pattern_name() {
+        assert_eq!(parse(b"/Pattern cs /P0 scn 0.2 0.4 1 /P1 SCN 0.2 0.4 1 scn").unwrap(), vec![
+            SetFillColorSpace("Pattern".to_string()),
+            SetFillColorN(vec![], Some("P0".to_string())),
+            SetStrokeColorN(vec![0.2, 0.4, 1.0], Some("P1".to_string())),
+            SetFillColorN(vec![0.2, 0.4, 1.0], None)
+        ]);
+    }
 
-    // let result = many0(alt(
-    //     parse_cm
-    // ))(source)?;
-    // let (source2, items) = many0(alt((
-    //     Value::parse_bytes,
-    //     Value::parse_integer,
-    //     Value::parse_list,
-    //     Value::parse_dict,
-    // )))(source)?;
-    // dbg!(result);
+    #[test]
+    fn curve_operators_take_their_own_operand_counts() {
+        assert_eq!(parse(b"1 2 3 4 5 6 c 1 2 3 4 v 1 2 3 4 y").unwrap(), vec![
+            CurveTo(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+            CurveToV(vec![1.0, 2.0, 3.0, 4.0]),
+            CurveToY(vec![1.0, 2.0, 3.0, 4.0])
+        ]);
+        // Two points are not enough for c
+        assert!(!parse(b"1 2 3 4 c").unwrap().iter().any(|token| matches!(token, CurveTo(_))));
+    }
 
-    // let _ = eof(source2)?;
+    #[test]
+    fn literal_strings_keep_balanced_parentheses() {
+        assert_eq!(parse(b"(a (b) c) Tj").unwrap(), vec![ShowTextString("a (b) c".to_string())]);
+        assert_eq!(parse(b"(a \\( b) Tj").unwrap(), vec![ShowTextString("a ( b".to_string())]);
+        assert_eq!(
+            parse(b"[(f(o)o) 5] TJ").unwrap(),
+            vec![ShowTextArray(vec![TextArrayElement::Text("f(o)o".to_string()), TextArrayElement::Adjustment(5.0)])]
+        );
+        assert!(parse(b"(a (b) Tj").is_err());
+    }
 
-    // Ok(items)
+    #[test]
+    fn show_text_operators_accept_hex_strings() {
+        assert_eq!(parse(b"<48656C6C6F> Tj").unwrap(), vec![ShowTextString("Hello".to_string())]);
+        assert_eq!(
+            parse(b"[<48>-20<65 6>] TJ").unwrap(),
+            vec![ShowTextArray(vec![
+                TextArrayElement::Text("H".to_string()),
+                TextArrayElement::Adjustment(-20.0),
+                TextArrayElement::Text("e`".to_string())
+            ])]
+        );
+        assert_eq!(parse(b"<4869> '").unwrap(), vec![NextLineShowText("Hi".to_string())]);
+        assert_eq!(
+            parse(b"1 2 <4869> \"").unwrap(),
+            vec![NextLineShowTextSpacing { aw: 1.0, ac: 2.0, text: "Hi".to_string() }]
+        );
+    }
 }
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::error::PdfError;
+use crate::page::PDFPage;
+use crate::pdf::{PAGE_SEPARATOR, PDF};
+use crate::reader::Reader;
+use crate::text::TextExtractionOptions;
+use crate::tokenizer::Tokenizer;
+
+/// A parsed document and its page tree
+pub struct Document {
+    pdf: PDF
+}
+
+/// One page of a `Document`
+#[derive(Clone, Copy)]
+pub struct Page<'a> {
+    pdf: &'a PDF,
+    page: &'a PDFPage
+}
+
+impl Document {
+    /// Reads and parses the whole file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Document, PdfError> {
+        let bytes = fs::read(path)?;
+        Document::from_bytes(bytes)
+    }
+
+    /// Parses a document already held in memory, such as one received over the network
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Document, PdfError> {
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(bytes)));
+        reader.read()?;
+
+        Ok(Document { pdf: reader.into_pdf() })
+    }
+
+    /// The underlying object model, for anything the document API does not cover
+    pub fn pdf(&self) -> &PDF {
+        &self.pdf
+    }
+
+    /// The pages in document order
+    pub fn pages(&self) -> impl Iterator<Item = Page<'_>> {
+        self.pdf.pages.iter().map(|page| Page { pdf: &self.pdf, page })
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pdf.pages.len()
+    }
+
+    /// The text of every page, with a form feed between consecutive pages
+    pub fn extract_text(&self) -> Result<String, PdfError> {
+        let page_texts = self.pages().map(|page| page.text()).collect::<Result<Vec<String>, PdfError>>()?;
+        Ok(page_texts.join(&PAGE_SEPARATOR.to_string()))
+    }
+
+    /// A pretty-printed JSON dump of the parsed objects, trailer, xref and pages, for
+    /// debugging and for comparing against other tools
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        crate::json::to_json(&self.pdf)
+    }
+}
+
+impl<'a> Page<'a> {
+    /// The page's text with the default extraction options
+    pub fn text(&self) -> Result<String, PdfError> {
+        self.page.extract_text(self.pdf)
+    }
+
+    pub fn text_with(&self, options: &TextExtractionOptions) -> Result<String, PdfError> {
+        self.page.text(self.pdf, options)
+    }
+
+    pub fn pdf_page(&self) -> &'a PDFPage {
+        self.page
+    }
+}
@@ -0,0 +1,488 @@
+use aes::cipher::block_padding::{NoPadding, Pkcs7};
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use log::warn;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::pdf::{PDFDictionary, PDFValue};
+use crate::strings::string_bytes;
+use crate::tokenizer::PDFObjectHeader;
+
+/// Pads or replaces a password to 32 bytes when deriving keys (7.6.4.3, Algorithm 2)
+const PASSWORD_PADDING: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A
+];
+
+/// RC4 keystream applied to `data`; encrypting and decrypting are the same operation
+pub fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j: u8 = 0;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// The bytes of a string value, whether it was written in literal or hex form
+fn string_value_bytes(value: &PDFValue) -> Option<Vec<u8>> {
+    match value {
+        PDFValue::String(string) => Some(string_bytes(string)),
+        PDFValue::Bytes(bytes) => Some(bytes.clone()),
+        _ => None
+    }
+}
+
+/// How a crypt filter encrypts strings or streams (7.6.5)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CryptMethod {
+    Identity,
+    Rc4,
+    /// AES-128 in CBC mode with a per-object key
+    AesV2,
+    /// AES-256 in CBC mode with the file key itself
+    AesV3
+}
+
+/// AES-CBC with the 16-byte initialization vector in front of the data and PKCS#5 padding
+fn aes_cbc_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 16 {
+        return Err("AES data is shorter than its initialization vector".to_string());
+    }
+    let (iv, data) = data.split_at(16);
+
+    let decrypted = match key.len() {
+        16 => cbc::Decryptor::<aes::Aes128>::new_from_slices(key, iv)
+            .map_err(|err| err.to_string())?
+            .decrypt_padded_vec_mut::<Pkcs7>(data),
+        32 => cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv)
+            .map_err(|err| err.to_string())?
+            .decrypt_padded_vec_mut::<Pkcs7>(data),
+        length => return Err(format!("No AES variant takes a {length}-byte key"))
+    };
+    decrypted.map_err(|_| "AES data has invalid padding".to_string())
+}
+
+/// The hash that revision 6 runs a password through (Algorithm 2.B); revision 5 uses
+/// plain SHA-256
+fn revision_6_hash(password: &[u8], salt: &[u8], user_key: &[u8]) -> Vec<u8> {
+    let mut hash: Vec<u8> = Sha256::new().chain_update(password).chain_update(salt).chain_update(user_key).finalize().to_vec();
+
+    let mut round = 0;
+    loop {
+        let block: Vec<u8> = [password, &hash, user_key].concat().repeat(64);
+        let encrypted = cbc::Encryptor::<aes::Aes128>::new_from_slices(&hash[..16], &hash[16..32])
+            .expect("AES-128 takes a 16-byte key and IV")
+            .encrypt_padded_vec_mut::<NoPadding>(&block);
+
+        let selector: u32 = encrypted[..16].iter().map(|byte| *byte as u32).sum::<u32>() % 3;
+        hash = match selector {
+            0 => Sha256::digest(&encrypted).to_vec(),
+            1 => Sha384::digest(&encrypted).to_vec(),
+            _ => Sha512::digest(&encrypted).to_vec()
+        };
+
+        round += 1;
+        if round >= 64 && *encrypted.last().unwrap_or(&0) as u32 <= round - 32 {
+            break;
+        }
+    }
+
+    hash.truncate(32);
+    hash
+}
+
+/// The standard security handler (7.6.4) opened with the empty user password, which is
+/// how most documents that can be viewed without a prompt are encrypted
+#[derive(Debug, Clone)]
+pub struct SecurityHandler {
+    key: Vec<u8>,
+    stream_method: CryptMethod,
+    string_method: CryptMethod,
+    /// When false, `/Type /Metadata` streams are stored in the clear
+    encrypt_metadata: bool
+}
+
+impl SecurityHandler {
+    /// Derives the file key from the `/Encrypt` dictionary and the first element of the
+    /// trailer's `/ID`, failing when the document needs a password or uses a scheme
+    /// that is not supported
+    pub fn new(encrypt: &PDFDictionary, id: Option<&PDFValue>) -> Result<Self, String> {
+        let number = |key: &str| match encrypt.get(key) {
+            Some(PDFValue::Number(number)) => Some(*number as i64),
+            _ => None
+        };
+
+        match encrypt.get("Filter").and_then(|filter| filter.name().ok()) {
+            Some(filter) if filter == "Standard" => {},
+            Some(filter) => return Err(format!("encrypted PDF not supported: security handler /{filter}")),
+            None => return Err("encrypted PDF not supported: no security handler /Filter".to_string())
+        }
+
+        let version = number("V").unwrap_or(0);
+        let revision = number("R").ok_or("Encrypt dictionary has no /R")?;
+        let encrypt_metadata = !matches!(encrypt.get("EncryptMetadata"), Some(PDFValue::Boolean(false)));
+
+        let (stream_method, string_method, key_length) = match version {
+            1 | 2 => (CryptMethod::Rc4, CryptMethod::Rc4, if revision == 2 { 5 } else { number("Length").unwrap_or(40) as usize / 8 }),
+            4 | 5 => {
+                let (stream_method, stream_key_length) = crypt_filter(encrypt, "StmF")?;
+                let (string_method, string_key_length) = crypt_filter(encrypt, "StrF")?;
+                let key_length = stream_key_length.or(string_key_length).unwrap_or(if version == 5 { 32 } else { 16 });
+                (stream_method, string_method, key_length)
+            },
+            _ => return Err(format!("encrypted PDF not supported: /V {version}"))
+        };
+
+        let owner = encrypt.get("O").and_then(string_value_bytes).ok_or("Encrypt dictionary has no /O")?;
+        let user = encrypt.get("U").and_then(string_value_bytes).ok_or("Encrypt dictionary has no /U")?;
+
+        let key = match revision {
+            2..=4 => {
+                let permissions = number("P").ok_or("Encrypt dictionary has no /P")? as u32;
+                let first_id = match id {
+                    Some(PDFValue::Array(id)) => id.first().and_then(string_value_bytes).unwrap_or_default(),
+                    _ => vec![]
+                };
+                md5_file_key(revision, key_length, &owner, &user, permissions, &first_id, encrypt_metadata)?
+            },
+            5 | 6 => {
+                let user_encryption_key = encrypt.get("UE").and_then(string_value_bytes).ok_or("Encrypt dictionary has no /UE")?;
+                sha256_file_key(revision, &user, &user_encryption_key)?
+            },
+            _ => return Err(format!("encrypted PDF not supported: /R {revision}"))
+        };
+
+        Ok(SecurityHandler { key, stream_method, string_method, encrypt_metadata })
+    }
+
+    /// The key for one object's strings and streams (Algorithm 1). AESV3 uses the file
+    /// key for every object.
+    fn object_key(&self, header: &PDFObjectHeader, method: CryptMethod) -> Vec<u8> {
+        if method == CryptMethod::AesV3 {
+            return self.key.clone();
+        }
+
+        let mut context = md5::Context::new();
+        context.consume(&self.key);
+        context.consume(&header.object_number.to_le_bytes()[..3]);
+        context.consume(&header.generation_number.to_le_bytes()[..2]);
+        if method == CryptMethod::AesV2 {
+            context.consume(b"sAlT");
+        }
+        context.compute().0[..(self.key.len() + 5).min(16)].to_vec()
+    }
+
+    /// Decrypts every string and stream in an object's value in place
+    pub fn decrypt_object(&self, header: &PDFObjectHeader, value: &mut PDFValue) {
+        self.decrypt_value(header, value);
+    }
+
+    fn decrypt_bytes(&self, header: &PDFObjectHeader, method: CryptMethod, data: &[u8]) -> Vec<u8> {
+        let key = self.object_key(header, method);
+        match method {
+            CryptMethod::Identity => data.to_vec(),
+            CryptMethod::Rc4 => rc4(&key, data),
+            CryptMethod::AesV2 | CryptMethod::AesV3 => aes_cbc_decrypt(&key, data).unwrap_or_else(|err| {
+                warn!("Leaving object {} {} encrypted: {err}", header.object_number, header.generation_number);
+                data.to_vec()
+            })
+        }
+    }
+
+    fn decrypt_value(&self, header: &PDFObjectHeader, value: &mut PDFValue) {
+        match value {
+            PDFValue::String(string) => {
+                *string = self.decrypt_bytes(header, self.string_method, &string_bytes(string)).into_iter().map(char::from).collect();
+            },
+            PDFValue::Bytes(bytes) => *bytes = self.decrypt_bytes(header, self.string_method, bytes),
+            PDFValue::Array(values) => {
+                for value in values {
+                    self.decrypt_value(header, value);
+                }
+            },
+            PDFValue::Dictionary(dictionary) => {
+                for value in dictionary.values_mut() {
+                    self.decrypt_value(header, value);
+                }
+            },
+            PDFValue::Stream(stream) => {
+                for value in stream.dictionary.values_mut() {
+                    self.decrypt_value(header, value);
+                }
+                let is_metadata = stream.dictionary.get("Type").and_then(|stream_type| stream_type.name().ok()).is_some_and(|stream_type| stream_type == "Metadata");
+                if self.encrypt_metadata || !is_metadata {
+                    stream.bytes = self.decrypt_bytes(header, self.stream_method, &stream.bytes);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// The method and key length in bytes of the crypt filter named by `/StmF` or `/StrF`,
+/// looked up in `/CF`
+fn crypt_filter(encrypt: &PDFDictionary, key: &str) -> Result<(CryptMethod, Option<usize>), String> {
+    let name = match encrypt.get(key).and_then(|name| name.name().ok()) {
+        Some(name) if name != "Identity" => name,
+        _ => return Ok((CryptMethod::Identity, None))
+    };
+
+    let filter = encrypt
+        .get("CF")
+        .and_then(|filters| filters.dictionary().ok())
+        .and_then(|filters| filters.get(name))
+        .and_then(|filter| filter.dictionary().ok())
+        .ok_or(format!("Crypt filter /{name} is not in /CF"))?;
+    let method = match filter.get("CFM").and_then(|method| method.name().ok()).map(String::as_str) {
+        Some("V2") => CryptMethod::Rc4,
+        Some("AESV2") => CryptMethod::AesV2,
+        Some("AESV3") => CryptMethod::AesV3,
+        None | Some("None") => CryptMethod::Identity,
+        Some(other) => return Err(format!("encrypted PDF not supported: crypt filter method /{other}"))
+    };
+    // Usually in bytes, though some writers give bits as for the dictionary's own /Length
+    let length = match filter.get("Length") {
+        Some(PDFValue::Number(length)) if *length > 32.0 => Some(*length as usize / 8),
+        Some(PDFValue::Number(length)) => Some(*length as usize),
+        _ => None
+    };
+
+    Ok((method, length))
+}
+
+/// The file key for revisions 2 to 4 (Algorithm 2), which only counts if it reproduces
+/// `/U` (Algorithms 4 and 5)
+fn md5_file_key(revision: i64, key_length: usize, owner: &[u8], user: &[u8], permissions: u32, first_id: &[u8], encrypt_metadata: bool) -> Result<Vec<u8>, String> {
+    let key_length = key_length.clamp(5, 16);
+
+    // The empty password is all padding
+    let mut context = md5::Context::new();
+    context.consume(PASSWORD_PADDING);
+    context.consume(owner);
+    context.consume(permissions.to_le_bytes());
+    context.consume(first_id);
+    if revision >= 4 && !encrypt_metadata {
+        context.consume([0xFF; 4]);
+    }
+    let mut hash = context.compute().0;
+    if revision >= 3 {
+        for _ in 0..50 {
+            hash = md5::compute(&hash[..key_length]).0;
+        }
+    }
+    let key = hash[..key_length].to_vec();
+
+    let expected_user = if revision == 2 {
+        rc4(&key, &PASSWORD_PADDING)
+    } else {
+        let mut context = md5::Context::new();
+        context.consume(PASSWORD_PADDING);
+        context.consume(first_id);
+        let mut user_hash = rc4(&key, &context.compute().0);
+        for round in 1..=19u8 {
+            let round_key: Vec<u8> = key.iter().map(|byte| byte ^ round).collect();
+            user_hash = rc4(&round_key, &user_hash);
+        }
+        user_hash
+    };
+    let compared_length = if revision == 2 { 32 } else { 16 };
+    if user.get(..compared_length) != expected_user.get(..compared_length) {
+        return Err("encrypted PDF not supported: a user password is required".to_string());
+    }
+
+    Ok(key)
+}
+
+/// The file key for revisions 5 and 6 (Algorithm 2.A): `/U` holds a hash and two salts,
+/// and `/UE` the file key encrypted with a key hashed from the password
+fn sha256_file_key(revision: i64, user: &[u8], user_encryption_key: &[u8]) -> Result<Vec<u8>, String> {
+    if user.len() < 48 || user_encryption_key.len() < 32 {
+        return Err("Encrypt dictionary /U or /UE is too short".to_string());
+    }
+    let (user_hash, validation_salt, key_salt) = (&user[..32], &user[32..40], &user[40..48]);
+
+    let hash = |salt: &[u8]| match revision {
+        5 => Sha256::new().chain_update(salt).finalize().to_vec(),
+        _ => revision_6_hash(b"", salt, b"")
+    };
+
+    if hash(validation_salt) != user_hash {
+        return Err("encrypted PDF not supported: a user password is required".to_string());
+    }
+
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(&hash(key_salt), &[0; 16])
+        .map_err(|err| err.to_string())?
+        .decrypt_padded_vec_mut::<NoPadding>(&user_encryption_key[..32])
+        .map_err(|_| "Could not decrypt /UE".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::error::PdfError;
+    use crate::test_pdf::TestPdf;
+
+    /// The first `/ID` element every fixture's keys were derived with
+    const FIRST_ID: &str = "0123456789ABCDEF0123456789ABCDEF";
+
+    /// The fixtures below were encrypted outside this crate, with the owner password
+    /// "owner", `/P -44` and the user password empty unless noted. Object 4 is the
+    /// content stream `BT (Secret) Tj ET` and object 5 holds `/Title (Hidden title)`.
+    fn encrypted_pdf(encrypt: &str, content: &str, title: &str, extra: impl FnOnce(&mut TestPdf)) -> Vec<u8> {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Resources << >> /Contents 4 0 R >>");
+        pdf.stream(4, "", &hex(content));
+        pdf.object(5, &format!("<< /Title <{title}> >>"));
+        pdf.object(6, encrypt);
+        extra(&mut pdf);
+        pdf.finish_section(&[0], &format!("<< /Size 8 /Root 1 0 R /Info 5 0 R /Encrypt 6 0 R /ID [<{FIRST_ID}> <{FIRST_ID}>] >>"));
+        pdf.bytes
+    }
+
+    fn hex(digits: &str) -> Vec<u8> {
+        (0..digits.len()).step_by(2).map(|start| u8::from_str_radix(&digits[start..start + 2], 16).unwrap()).collect()
+    }
+
+    fn value(document: &Document, object_number: u64) -> &PDFValue {
+        &document.pdf().get_object(&PDFObjectHeader { object_number, generation_number: 0 }).unwrap().value
+    }
+
+    fn title(document: &Document) -> Vec<u8> {
+        string_value_bytes(value(document, 5).dictionary().unwrap().get("Title").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn revision_2_rc4_opens_with_the_empty_password() {
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 1 /R 2 /P -44 \
+             /O <C92422687FACEE686E373F10B5C7D04738053152F7E2EE30E11C69EC442576AB> \
+             /U <970A09B725B6BFE3A63743309E5BCFCEDFD799A6FA1D90CFCB3CF1EFC790D4C8> >>",
+            "8774870F38C55AF85CC20D78DD07083EF0",
+            "929A3894BA98E2D25EC16C30",
+            |_| {}
+        );
+
+        let document = Document::from_bytes(bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Secret\n");
+        assert_eq!(title(&document), b"Hidden title");
+    }
+
+    #[test]
+    fn revision_3_rc4_opens_with_the_empty_password() {
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 2 /R 3 /Length 128 /P -44 \
+             /O <566FA873EE33C797CD3B904FDADF814AFA34DF9A38F6ED41B984E2C6DA2AA6F5> \
+             /U <A4E8C7246E9CDA22B7FDAB9B75004E8700000000000000000000000000000000> >>",
+            "5D972BFF656839D0FD3F5CD0669FF1E9B7",
+            "1AF1D2EFD79BC5D3BFC57CE6",
+            |_| {}
+        );
+
+        let document = Document::from_bytes(bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Secret\n");
+        assert_eq!(title(&document), b"Hidden title");
+    }
+
+    #[test]
+    fn metadata_stays_plain_when_encrypt_metadata_is_false() {
+        let metadata = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>";
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 4 /R 4 /P -44 /EncryptMetadata false \
+             /CF << /StdCF << /CFM /V2 /Length 16 >> >> /StmF /StdCF /StrF /StdCF \
+             /O <566FA873EE33C797CD3B904FDADF814AFA34DF9A38F6ED41B984E2C6DA2AA6F5> \
+             /U <0FC3C651976A83EF667266DC46F1C44D00000000000000000000000000000000> >>",
+            "71CEF659081D435DACAE25F3DC80BB9F04",
+            "AC55DE5C6209B4F26F4E6A0A",
+            |pdf| pdf.stream(7, "/Type /Metadata /Subtype /XML", metadata)
+        );
+
+        let document = Document::from_bytes(bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Secret\n");
+        assert_eq!(title(&document), b"Hidden title");
+        assert!(matches!(value(&document, 7), PDFValue::Stream(stream) if stream.bytes == metadata));
+    }
+
+    #[test]
+    fn a_user_password_is_unsupported() {
+        // Encrypted with the user password "user", so the empty password does not match /U
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 2 /R 3 /Length 128 /P -44 \
+             /O <0BA3835F88F90388E74E54584125CE142BE0DE24C6B0D37746E075B891756671> \
+             /U <5B947164064E83DAF1379E5BE31D5C3900000000000000000000000000000000> >>",
+            "2C58FE2160513D77BC21B984E3B64E9FBE",
+            "236C15ED8323A12B8B23EBD1",
+            |_| {}
+        );
+
+        assert!(matches!(Document::from_bytes(bytes), Err(PdfError::Unsupported(message)) if message.contains("user password")));
+    }
+
+    #[test]
+    fn revision_4_aesv2_opens_with_the_empty_password() {
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 4 /R 4 /P -44 \
+             /CF << /StdCF << /CFM /AESV2 /Length 16 >> >> /StmF /StdCF /StrF /StdCF \
+             /O <566FA873EE33C797CD3B904FDADF814AFA34DF9A38F6ED41B984E2C6DA2AA6F5> \
+             /U <A4E8C7246E9CDA22B7FDAB9B75004E8700000000000000000000000000000000> >>",
+            "000102030405060708090A0B0C0D0E0F90819BEFD45A0CB60B07D63F53C42E389D7AA7D7687AD70D792E5F99FB71233A",
+            "101112131415161718191A1B1C1D1E1FD1BDB6B76E4100C51B7EF0D796DFDD06",
+            |_| {}
+        );
+
+        let document = Document::from_bytes(bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Secret\n");
+        assert_eq!(title(&document), b"Hidden title");
+    }
+
+    #[test]
+    fn revision_6_aesv3_opens_with_the_empty_password() {
+        // The file key is the bytes 100 to 131
+        let bytes = encrypted_pdf(
+            "<< /Filter /Standard /V 5 /R 6 /P -44 \
+             /CF << /StdCF << /CFM /AESV3 /Length 32 >> >> /StmF /StdCF /StrF /StdCF \
+             /O <CA49C15C6BAA07F7836B52430BE3A216A3DD3974681ED4A759171D7641B415656F7673616C746F766F6B73616C746F6B> \
+             /U <9E964457D81872826F0250CB6152ACEDEF420DD3293C2F23AF3C0ACE1E0863347673616C747673616B73616C746B7361> \
+             /OE <29AE66ECAFDDA01D9182244F1851DDBCA5FE49E9D751E26B930EF4E37CF0DF8F> \
+             /UE <CFBF6762B41F397ABC5B759275CF685B07A2A3B6129E4B0E6E1B19B227A7EC5B> \
+             /Perms <342A7998A92F3C14777A2D9BC2BED093> >>",
+            "202122232425262728292A2B2C2D2E2FD68790AADA62DB263016F63B000E74130A62A067C624415120585416DD9C6DFB",
+            "303132333435363738393A3B3C3D3E3F2918F519D449302E6B373267A271ADB1",
+            |_| {}
+        );
+
+        let document = Document::from_bytes(bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Secret\n");
+        assert_eq!(title(&document), b"Hidden title");
+    }
+
+    #[test]
+    fn aes_data_with_bad_padding_is_left_as_it_is() {
+        let handler = SecurityHandler {
+            key: vec![7; 16],
+            stream_method: CryptMethod::AesV2,
+            string_method: CryptMethod::AesV2,
+            encrypt_metadata: true
+        };
+        let header = PDFObjectHeader { object_number: 4, generation_number: 0 };
+        // An IV and one block that were never encrypted with this key
+        let data: Vec<u8> = (0..32).collect();
+
+        assert_eq!(aes_cbc_decrypt(&handler.object_key(&header, CryptMethod::AesV2), &data), Err("AES data has invalid padding".to_string()));
+        let mut value = PDFValue::Bytes(data.clone());
+        handler.decrypt_object(&header, &mut value);
+        assert!(matches!(value, PDFValue::Bytes(bytes) if bytes == data));
+    }
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a document, or part of one, could not be read
+#[derive(Debug)]
+pub enum PdfError {
+    /// The file could not be read at all
+    Io(io::Error),
+    /// The tokenizer could not turn the input into tokens
+    Tokenizer(String),
+    /// A token that cannot appear where it was found
+    UnexpectedToken { expected: &'static str, found: String },
+    /// A required dictionary entry is absent
+    MissingKey { dictionary: &'static str, key: &'static str },
+    /// The document uses something this crate does not implement, such as a
+    /// password-protected security handler
+    Unsupported(String),
+    /// A content stream the lexer could not tokenize
+    ContentStream(String),
+    /// The structure is broken in a way the other variants do not describe
+    Malformed(String)
+}
+
+impl fmt::Display for PdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfError::Io(err) => write!(f, "{err}"),
+            PdfError::Tokenizer(message) => write!(f, "{message}"),
+            PdfError::UnexpectedToken { expected, found } => write!(f, "Expected {expected}, found {found}"),
+            PdfError::MissingKey { dictionary, key } => write!(f, "{dictionary} dictionary has no /{key}"),
+            PdfError::Unsupported(message) => write!(f, "{message}"),
+            PdfError::ContentStream(message) => write!(f, "Could not lex content stream: {message}"),
+            PdfError::Malformed(message) => write!(f, "{message}")
+        }
+    }
+}
+
+impl Error for PdfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PdfError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for PdfError {
+    fn from(err: io::Error) -> Self {
+        PdfError::Io(err)
+    }
+}
+
+/// The tokenizer reports its errors as plain strings
+impl From<String> for PdfError {
+    fn from(message: String) -> Self {
+        PdfError::Tokenizer(message)
+    }
+}
+
+impl<I: fmt::Debug> From<nom::Err<nom::error::Error<I>>> for PdfError {
+    fn from(err: nom::Err<nom::error::Error<I>>) -> Self {
+        PdfError::ContentStream(format!("{err:?}"))
+    }
+}
@@ -0,0 +1,484 @@
+use flate2::Decompress;
+
+use crate::pdf::{PDFDictionary, PDFValue};
+
+/// Inflates zlib data, growing the output as needed. A stream cut short before its
+/// checksum still yields what was decoded, as viewers accept those too.
+pub fn inflate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decompress = Decompress::new(true);
+    let mut decompressed_bytes: Vec<u8> = Vec::with_capacity(bytes.len() * 3);
+
+    loop {
+        let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+        let status = decompress.decompress_vec(
+            &bytes[total_in as usize..],
+            &mut decompressed_bytes,
+            flate2::FlushDecompress::Sync).map_err(|err| format!("Corrupt Flate data: {err}"))?;
+
+        if status == flate2::Status::StreamEnd {
+            break;
+        }
+        if decompressed_bytes.len() == decompressed_bytes.capacity() {
+            decompressed_bytes.reserve(decompressed_bytes.capacity().max(1024));
+            continue;
+        }
+        let no_progress = decompress.total_in() == total_in && decompress.total_out() == total_out;
+        if no_progress || decompress.total_in() as usize == bytes.len() {
+            break;
+        }
+    }
+
+    Ok(decompressed_bytes)
+}
+
+/// Decodes hex digit pairs up to the `>` end-of-data marker, ignoring white-space.
+/// An odd final digit is taken to be followed by 0, as for hexadecimal strings.
+pub fn ascii_hex_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = bytes
+        .iter()
+        .copied()
+        .take_while(|&digit| digit != b'>')
+        .filter(|digit| !digit.is_ascii_whitespace())
+        .map(|digit| match (digit as char).to_digit(16) {
+            Some(value) => Ok(value as u8),
+            None => Err(format!("Invalid ASCIIHexDecode digit '{}'", digit as char))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+        .collect())
+}
+
+fn ascii85_group(digits: &[u32]) -> Result<u32, String> {
+    digits
+        .iter()
+        .try_fold(0u32, |value, digit| value.checked_mul(85)?.checked_add(*digit))
+        .ok_or("ASCII85Decode group is out of range".to_string())
+}
+
+/// Decodes base-85 data (7.4.3) up to the `~>` end-of-data marker, ignoring white-space.
+/// `z` stands for four zero bytes, and a final partial group of n digits gives n-1 bytes.
+pub fn ascii85_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded: Vec<u8> = vec![];
+    let mut group: Vec<u32> = Vec::with_capacity(5);
+
+    for &byte in bytes {
+        match byte {
+            b'~' => break,
+            b'z' if group.is_empty() => decoded.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group.push((byte - b'!') as u32);
+                if group.len() == 5 {
+                    let value = ascii85_group(&group)?;
+                    decoded.extend_from_slice(&value.to_be_bytes());
+                    group.clear();
+                }
+            },
+            _ if byte.is_ascii_whitespace() || byte == 0 => {},
+            _ => return Err(format!("Invalid ASCII85Decode character '{}'", byte as char))
+        }
+    }
+
+    if group.len() == 1 {
+        return Err("ASCII85Decode data ends with a single digit".to_string());
+    }
+    if !group.is_empty() {
+        // Pad the partial group with the highest digit, then keep only the bytes it encodes
+        let length = group.len() - 1;
+        group.resize(5, 84);
+        let value = ascii85_group(&group)?;
+        decoded.extend_from_slice(&value.to_be_bytes()[..length]);
+    }
+
+    Ok(decoded)
+}
+
+const LZW_CLEAR_TABLE: usize = 256;
+const LZW_END_OF_DATA: usize = 257;
+const LZW_MAX_TABLE_SIZE: usize = 4096;
+
+/// Reads codes of a changing bit width, most significant bit first
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, width: usize) -> Option<usize> {
+        if self.position + width > self.bytes.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0;
+        for _ in 0..width {
+            let bit = self.bytes[self.position / 8] >> (7 - self.position % 8) & 1;
+            value = value << 1 | bit as usize;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+/// Decodes LZW data with 9 to 12 bit codes (7.4.4). With `early_change` the code width
+/// grows one code early, which is the default for PDF. Data that ends without an EOD
+/// code still yields what was decoded.
+pub fn lzw_decode(bytes: &[u8], early_change: bool) -> Result<Vec<u8>, String> {
+    let initial_table = || -> Vec<Vec<u8>> {
+        // The clear-table and EOD codes take up the two slots after the single bytes
+        (0..=255u8).map(|byte| vec![byte]).chain([vec![], vec![]]).collect()
+    };
+
+    let mut table = initial_table();
+    let mut reader = BitReader { bytes, position: 0 };
+    let mut code_width = 9;
+    let mut previous: Option<Vec<u8>> = None;
+    let mut decoded: Vec<u8> = vec![];
+
+    while let Some(code) = reader.read(code_width) {
+        match code {
+            LZW_CLEAR_TABLE => {
+                table = initial_table();
+                code_width = 9;
+                previous = None;
+                continue;
+            },
+            LZW_END_OF_DATA => break,
+            _ => {}
+        }
+
+        let entry = match (table.get(code), &previous) {
+            (Some(entry), _) => entry.clone(),
+            // A code for the entry about to be added repeats the previous entry plus its first byte
+            (None, Some(previous)) if code == table.len() => {
+                let mut entry = previous.clone();
+                entry.push(previous[0]);
+                entry
+            },
+            _ => return Err(format!("Invalid LZW code {code}"))
+        };
+        decoded.extend_from_slice(&entry);
+
+        if let Some(mut new_entry) = previous.take() {
+            if table.len() < LZW_MAX_TABLE_SIZE {
+                new_entry.push(entry[0]);
+                table.push(new_entry);
+            }
+        }
+        previous = Some(entry);
+
+        code_width = match table.len() + early_change as usize {
+            ..=511 => 9,
+            512..=1023 => 10,
+            1024..=2047 => 11,
+            _ => 12
+        };
+    }
+
+    Ok(decoded)
+}
+
+/// Decodes run-length data (7.4.5): a length byte below 128 copies that many plus one
+/// bytes literally, one above 128 repeats the next byte 257 minus the length times, and
+/// 128 ends the data
+pub fn run_length_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded: Vec<u8> = vec![];
+    let mut index = 0;
+
+    while let Some(&length) = bytes.get(index) {
+        index += 1;
+        match length {
+            128 => break,
+            0..=127 => {
+                let literal = bytes
+                    .get(index..index + length as usize + 1)
+                    .ok_or("RunLengthDecode literal run is cut short")?;
+                decoded.extend_from_slice(literal);
+                index += literal.len();
+            },
+            _ => {
+                let byte = *bytes.get(index).ok_or("RunLengthDecode repeat run is cut short")?;
+                decoded.extend(std::iter::repeat_n(byte, 257 - length as usize));
+                index += 1;
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn parameter(params: Option<&PDFDictionary>, key: &str) -> Option<f64> {
+    match params?.get(key)? {
+        PDFValue::Number(number) => Some(*number),
+        _ => None
+    }
+}
+
+/// Bytes per predictor row of `/Columns` samples. The parameters are untrusted, so a row
+/// longer than the data it is applied to is rejected before any buffer is sized from it.
+fn predictor_row_length(predictor: &str, colors: usize, bits_per_component: usize, columns: usize, data_length: usize) -> Result<usize, String> {
+    let row_length = colors
+        .checked_mul(bits_per_component)
+        .and_then(|bits| bits.checked_mul(columns))
+        .map(|bits| bits.div_ceil(8))
+        .ok_or(format!("{predictor} predictor row length overflows"))?;
+
+    if row_length == 0 {
+        return Err(format!("{predictor} predictor rows are empty"));
+    }
+    if row_length > data_length {
+        return Err(format!("{predictor} predictor row of {row_length} bytes is longer than the {data_length} bytes of data"));
+    }
+    Ok(row_length)
+}
+
+/// Reverses the PNG row filters (7.4.4.4) selected by a `/Predictor` of 10 or more. Each
+/// row of `/Columns` samples is preceded by a byte naming the filter used for that row.
+pub fn png_unpredict(params: Option<&PDFDictionary>, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let colors = parameter(params, "Colors").unwrap_or(1.0) as usize;
+    let bits_per_component = parameter(params, "BitsPerComponent").unwrap_or(8.0) as usize;
+    let columns = parameter(params, "Columns").unwrap_or(1.0) as usize;
+
+    let row_length = predictor_row_length("PNG", colors, bits_per_component, columns, bytes.len())?;
+    // Sub, Average and Paeth compare against the byte for the same component one pixel back
+    let bytes_per_pixel = (colors * bits_per_component).div_ceil(8).max(1);
+
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut previous_row: Vec<u8> = vec![0; row_length];
+
+    for encoded_row in bytes.chunks(row_length + 1) {
+        let (&filter_type, encoded_row) = encoded_row.split_first().ok_or("Empty PNG predictor row")?;
+        let mut row: Vec<u8> = encoded_row.to_vec();
+
+        for index in 0..row.len() {
+            let left = if index >= bytes_per_pixel { row[index - bytes_per_pixel] } else { 0 };
+            let up = previous_row[index];
+            let up_left = if index >= bytes_per_pixel { previous_row[index - bytes_per_pixel] } else { 0 };
+
+            let prediction = match filter_type {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                other => return Err(format!("Unknown PNG predictor row filter {other}"))
+            };
+            row[index] = row[index].wrapping_add(prediction);
+        }
+
+        decoded.extend_from_slice(&row);
+        previous_row[..row.len()].copy_from_slice(&row);
+    }
+
+    Ok(decoded)
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (left_distance, up_distance, up_left_distance) = (
+        (estimate - left as i16).abs(),
+        (estimate - up as i16).abs(),
+        (estimate - up_left as i16).abs()
+    );
+
+    if left_distance <= up_distance && left_distance <= up_left_distance {
+        left
+    } else if up_distance <= up_left_distance {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Reverses TIFF Predictor 2 (horizontal differencing): each sample in a row was stored as
+/// its difference from the same colour component of the pixel before it
+pub fn tiff_unpredict(params: Option<&PDFDictionary>, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let colors = parameter(params, "Colors").unwrap_or(1.0) as usize;
+    let bits_per_component = parameter(params, "BitsPerComponent").unwrap_or(8.0) as usize;
+    let columns = parameter(params, "Columns").unwrap_or(1.0) as usize;
+
+    if ![1, 2, 4, 8, 16].contains(&bits_per_component) {
+        return Err(format!("Unsupported BitsPerComponent {bits_per_component} for TIFF predictor"));
+    }
+    let row_length = predictor_row_length("TIFF", colors, bits_per_component, columns, bytes.len())?;
+
+    let mask = (1u32 << bits_per_component) - 1;
+    let mut decoded = bytes.to_vec();
+
+    for row in decoded.chunks_mut(row_length) {
+        let samples = (row.len() * 8 / bits_per_component).min(colors * columns);
+        for index in colors..samples {
+            let sample = read_sample(row, index, bits_per_component) + read_sample(row, index - colors, bits_per_component);
+            write_sample(row, index, bits_per_component, sample & mask);
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Reads the `index`th sample of `width` bits, packed most significant bit first
+fn read_sample(row: &[u8], index: usize, width: usize) -> u32 {
+    match width {
+        16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]) as u32,
+        _ => {
+            let bit = index * width;
+            (row[bit / 8] as u32 >> (8 - width - bit % 8)) & ((1 << width) - 1)
+        }
+    }
+}
+
+fn write_sample(row: &mut [u8], index: usize, width: usize, sample: u32) {
+    match width {
+        16 => row[index * 2..index * 2 + 2].copy_from_slice(&(sample as u16).to_be_bytes()),
+        _ => {
+            let bit = index * width;
+            let shift = 8 - width - bit % 8;
+            let mask = (((1u32 << width) - 1) << shift) as u8;
+            row[bit / 8] = row[bit / 8] & !mask | ((sample << shift) as u8 & mask);
+        }
+    }
+}
+
+/// Undoes the `/Predictor` that Flate and LZW data may have been encoded with
+fn unpredict(params: Option<&PDFDictionary>, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    match parameter(params, "Predictor").unwrap_or(1.0) as i64 {
+        2 => tiff_unpredict(params, &bytes),
+        predictor if predictor >= 10 => png_unpredict(params, &bytes),
+        _ => Ok(bytes)
+    }
+}
+
+/// Whether `apply_filter` can undo `filter`, by its full or abbreviated name
+pub fn is_supported_filter(filter: &str) -> bool {
+    matches!(filter, "FlateDecode" | "Fl" | "ASCIIHexDecode" | "AHx" | "ASCII85Decode" | "A85" | "LZWDecode" | "LZW" | "RunLengthDecode" | "RL")
+}
+
+/// Undoes a single stream filter, given its entry from `/DecodeParms`
+pub fn apply_filter(filter: &str, params: Option<&PDFDictionary>, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    match filter {
+        "FlateDecode" | "Fl" => unpredict(params, inflate(bytes)?),
+        "ASCIIHexDecode" | "AHx" => ascii_hex_decode(bytes),
+        "ASCII85Decode" | "A85" => ascii85_decode(bytes),
+        "LZWDecode" | "LZW" => unpredict(params, lzw_decode(bytes, parameter(params, "EarlyChange").unwrap_or(1.0) != 0.0)?),
+        "RunLengthDecode" | "RL" => run_length_decode(bytes),
+        other => Err(format!("Unsupported stream filter /{other}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(entries: &[(&str, f64)]) -> PDFDictionary {
+        entries.iter().map(|(key, value)| (key.to_string(), PDFValue::Number(*value))).collect()
+    }
+
+    #[test]
+    fn ascii_hex_ignores_whitespace_and_pads_an_odd_digit() {
+        assert_eq!(ascii_hex_decode(b"48 65\n6c 6C 6f>ignored"), Ok(b"Hello".to_vec()));
+        assert_eq!(ascii_hex_decode(b"414>"), Ok(vec![0x41, 0x40]));
+        assert_eq!(ascii_hex_decode(b">"), Ok(vec![]));
+        assert!(ascii_hex_decode(b"4G>").is_err());
+    }
+
+    /// Packs LZW codes, each `(code, width)`, most significant bit first
+    fn pack_codes(codes: impl IntoIterator<Item = (usize, usize)>) -> Vec<u8> {
+        let bits: Vec<bool> = codes
+            .into_iter()
+            .flat_map(|(code, width)| (0..width).rev().map(move |bit| code >> bit & 1 == 1))
+            .collect();
+        bits.chunks(8)
+            .map(|byte| byte.iter().enumerate().fold(0, |value, (index, &bit)| value | (bit as u8) << (7 - index)))
+            .collect()
+    }
+
+    #[test]
+    fn lzw_decodes_the_example_from_the_spec() {
+        // 7.4.4.2: 256 45 258 258 65 259 66 257
+        let encoded = [0x80, 0x0B, 0x60, 0x50, 0x22, 0x0C, 0x0C, 0x85, 0x01];
+        assert_eq!(lzw_decode(&encoded, true), Ok(b"-----A---B".to_vec()));
+    }
+
+    #[test]
+    fn lzw_clear_table_forgets_earlier_entries() {
+        // Before the clear 258 would be "AB"; after it, it is the previous entry plus its first byte
+        let encoded = pack_codes([65, 66, LZW_CLEAR_TABLE, 67, 258, LZW_END_OF_DATA].map(|code| (code, 9)));
+        assert_eq!(lzw_decode(&encoded, true), Ok(b"ABCCC".to_vec()));
+    }
+
+    #[test]
+    fn lzw_stops_at_end_of_data() {
+        let encoded = pack_codes([65, LZW_END_OF_DATA, 66, 67].map(|code| (code, 9)));
+        assert_eq!(lzw_decode(&encoded, true), Ok(b"A".to_vec()));
+    }
+
+    #[test]
+    fn lzw_early_change_decides_when_codes_widen() {
+        // Each code after the first adds an entry, so the table reaches 511 entries after
+        // 254 codes. The default early change widens the 255th code, EarlyChange 0 the 256th.
+        let codes = |nine_bit_codes: usize| pack_codes(
+            (0..300).map(|index| (65, if index < nine_bit_codes { 9 } else { 10 })).chain([(LZW_END_OF_DATA, 10)])
+        );
+        assert_eq!(lzw_decode(&codes(254), true), Ok(vec![b'A'; 300]));
+        assert_eq!(lzw_decode(&codes(255), false), Ok(vec![b'A'; 300]));
+        assert_ne!(lzw_decode(&codes(255), true), Ok(vec![b'A'; 300]));
+
+        let params = params(&[("EarlyChange", 0.0)]);
+        assert_eq!(apply_filter("LZWDecode", Some(&params), &codes(255)), Ok(vec![b'A'; 300]));
+    }
+
+    #[test]
+    fn run_length_copies_literals_and_repeats_runs() {
+        assert_eq!(run_length_decode(&[2, b'a', b'b', b'c', 254, b'x', 128, 0, b'z']), Ok(b"abcxxx".to_vec()));
+        assert_eq!(run_length_decode(&[129, b'-']), Ok(vec![b'-'; 128]));
+        assert!(run_length_decode(&[3, b'a']).is_err());
+        assert!(run_length_decode(&[255]).is_err());
+    }
+
+    #[test]
+    fn png_rows_undo_each_filter_type() {
+        let params = params(&[("Predictor", 12.0), ("Columns", 3.0)]);
+        let encoded = [
+            0, 7, 8, 9,
+            1, 1, 1, 1,
+            2, 1, 1, 1,
+            3, 1, 3, 3,
+            4, 4, 1, 0
+        ];
+        assert_eq!(png_unpredict(Some(&params), &encoded), Ok(vec![
+            7, 8, 9,
+            1, 2, 3,
+            2, 3, 4,
+            2, 5, 7,
+            6, 7, 7
+        ]));
+    }
+
+    #[test]
+    fn tiff_predictor_adds_each_sample_to_the_one_before() {
+        let bytes = params(&[("Predictor", 2.0), ("Columns", 3.0)]);
+        assert_eq!(tiff_unpredict(Some(&bytes), &[1, 1, 1, 5, 255, 2]), Ok(vec![1, 2, 3, 5, 4, 6]));
+
+        let pairs = params(&[("Colors", 2.0), ("Columns", 2.0)]);
+        assert_eq!(tiff_unpredict(Some(&pairs), &[10, 20, 1, 2]), Ok(vec![10, 20, 11, 22]));
+
+        let wide = params(&[("BitsPerComponent", 16.0), ("Columns", 2.0)]);
+        assert_eq!(tiff_unpredict(Some(&wide), &[0x01, 0x00, 0x00, 0x01]), Ok(vec![0x01, 0x00, 0x01, 0x01]));
+
+        // Four bit samples 1 1 1 15 become 1 2 3 2, wrapping at 16
+        let nibbles = params(&[("BitsPerComponent", 4.0), ("Columns", 4.0)]);
+        assert_eq!(tiff_unpredict(Some(&nibbles), &[0x11, 0x1F]), Ok(vec![0x12, 0x32]));
+    }
+
+    #[test]
+    fn predictor_rows_longer_than_the_data_are_rejected() {
+        let huge = params(&[("Columns", 1e12)]);
+        assert!(png_unpredict(Some(&huge), &[0, 1, 2]).is_err());
+        assert!(tiff_unpredict(Some(&huge), &[1, 2, 3]).is_err());
+
+        let overflowing = params(&[("Colors", 1e19), ("Columns", 1e19)]);
+        assert!(png_unpredict(Some(&overflowing), &[0, 1, 2]).is_err());
+        assert!(tiff_unpredict(Some(&overflowing), &[1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::cmap::{parse_to_unicode, ToUnicodeCMap};
+use crate::pdf::{PDF, PDFDictionary, PDFStream, PDFValue};
+
+/// The parts of a font dictionary the text layer needs to place glyphs
+#[derive(Debug, Default)]
+pub struct Font {
+    pub subtype: Option<String>,
+    pub base_font: Option<String>,
+    pub first_char: u32,
+    /// Glyph widths in thousandths of a text space unit, starting at `first_char`
+    pub widths: Vec<f64>,
+    /// Width of any code not covered by `widths`
+    pub missing_width: f64,
+    /// Type3 fonts give widths in glyph space, which this maps to text space
+    pub font_matrix: Option<Vec<f64>>,
+    /// Glyph names from the `/Encoding` `/Differences` array, by code
+    pub differences: HashMap<u32, String>,
+    pub to_unicode: Option<ToUnicodeCMap>,
+    /// For CID-keyed TrueType fonts, the descendant's `/CIDToGIDMap`
+    pub cid_to_gid: Option<CidToGidMap>
+}
+
+/// Maps CIDs to glyph indices of an embedded TrueType font (9.7.4.2)
+#[derive(Debug, Clone, PartialEq)]
+pub enum CidToGidMap {
+    Identity,
+    /// Glyph index by CID, read from the stream form's 2-byte big-endian entries
+    Table(Vec<u16>)
+}
+
+impl CidToGidMap {
+    fn from_value(pdf: &PDF, value: &PDFValue) -> Option<Self> {
+        if let Ok(name) = value.as_name(pdf) {
+            return (name == "Identity").then_some(CidToGidMap::Identity);
+        }
+
+        let bytes = match value.as_stream(pdf).and_then(PDFStream::decompress) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                warn!("Ignoring unusable CIDToGIDMap: {error}");
+                return None;
+            }
+        };
+        Some(CidToGidMap::Table(
+            bytes.chunks_exact(2).map(|gid| u16::from_be_bytes([gid[0], gid[1]])).collect()
+        ))
+    }
+
+    pub fn gid(&self, cid: u32) -> Option<u16> {
+        match self {
+            CidToGidMap::Identity => u16::try_from(cid).ok(),
+            CidToGidMap::Table(gids) => gids.get(cid as usize).copied()
+        }
+    }
+}
+
+/// Unicode for the glyph names that commonly show up in `/Differences` arrays
+/// (a subset of the Adobe Glyph List, plus the `uniXXXX` and `uXXXX` forms)
+fn glyph_name_to_unicode(name: &str) -> Option<String> {
+    let mut characters = name.chars();
+    if let (Some(character), None) = (characters.next(), characters.next()) {
+        if character.is_ascii_alphabetic() {
+            return Some(character.to_string());
+        }
+    }
+
+    if let Some(hex) = name.strip_prefix("uni").or_else(|| name.strip_prefix('u')) {
+        if (4..=6).contains(&hex.len()) {
+            if let Some(character) = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                return Some(character.to_string());
+            }
+        }
+    }
+
+    let character = match name {
+        "space" => ' ', "exclam" => '!', "quotedbl" => '"', "numbersign" => '#',
+        "dollar" => '$', "percent" => '%', "ampersand" => '&', "quotesingle" => '\'',
+        "parenleft" => '(', "parenright" => ')', "asterisk" => '*', "plus" => '+',
+        "comma" => ',', "hyphen" => '-', "period" => '.', "slash" => '/',
+        "zero" => '0', "one" => '1', "two" => '2', "three" => '3', "four" => '4',
+        "five" => '5', "six" => '6', "seven" => '7', "eight" => '8', "nine" => '9',
+        "colon" => ':', "semicolon" => ';', "less" => '<', "equal" => '=',
+        "greater" => '>', "question" => '?', "at" => '@', "bracketleft" => '[',
+        "backslash" => '\\', "bracketright" => ']', "asciicircum" => '^',
+        "underscore" => '_', "grave" => '`', "braceleft" => '{', "bar" => '|',
+        "braceright" => '}', "asciitilde" => '~', "quoteleft" => '\u{2018}',
+        "quoteright" => '\u{2019}', "quotedblleft" => '\u{201C}', "quotedblright" => '\u{201D}',
+        "endash" => '\u{2013}', "emdash" => '\u{2014}', "bullet" => '\u{2022}',
+        "ellipsis" => '\u{2026}', "copyright" => '\u{A9}', "registered" => '\u{AE}',
+        "trademark" => '\u{2122}', "degree" => '\u{B0}',
+        "fi" => return Some("fi".to_string()),
+        "fl" => return Some("fl".to_string()),
+        "ff" => return Some("ff".to_string()),
+        _ => return None
+    };
+
+    Some(character.to_string())
+}
+
+fn parse_differences(pdf: &PDF, encoding: &PDFDictionary) -> HashMap<u32, String> {
+    let mut differences: HashMap<u32, String> = HashMap::new();
+    let entries = match encoding.get("Differences").map(|differences| differences.as_array(pdf)) {
+        Some(Ok(entries)) => entries,
+        _ => return differences
+    };
+
+    // A number sets the code for the glyph names that follow it
+    let mut code: u32 = 0;
+    for entry in entries {
+        if let Ok(name) = entry.as_name(pdf) {
+            differences.insert(code, name.clone());
+            code += 1;
+        } else if let Ok(number) = entry.as_number(pdf) {
+            code = number as u32;
+        }
+    }
+
+    differences
+}
+
+fn get_name(pdf: &PDF, dictionary: &PDFDictionary, key: &str) -> Option<String> {
+    dictionary.get(key).and_then(|value| value.as_name(pdf).ok()).cloned()
+}
+
+fn get_number(pdf: &PDF, dictionary: &PDFDictionary, key: &str) -> Option<f64> {
+    dictionary.get(key).and_then(|value| value.as_number(pdf).ok())
+}
+
+impl Font {
+    pub fn from_dictionary(pdf: &PDF, dictionary: &PDFDictionary) -> Self {
+        let subtype = get_name(pdf, dictionary, "Subtype");
+
+        let widths: Vec<f64> = match dictionary.get("Widths").map(|widths| widths.as_array(pdf)) {
+            Some(Ok(widths)) => widths
+                .iter()
+                .map(|width| width.as_number(pdf).unwrap_or(0.0))
+                .collect(),
+            _ => vec![]
+        };
+
+        let descendant = dictionary
+            .get("DescendantFonts")
+            .and_then(|descendants| descendants.as_array(pdf).ok())
+            .and_then(|descendants| descendants.first())
+            .and_then(|descendant| descendant.as_dictionary(pdf).ok());
+
+        let missing_width = if subtype.as_deref() == Some("Type0") {
+            // Composite fonts keep their default width on the descendant CIDFont
+            descendant
+                .and_then(|descendant| get_number(pdf, descendant, "DW"))
+                .unwrap_or(1000.0)
+        } else {
+            dictionary
+                .get("FontDescriptor")
+                .and_then(|descriptor| descriptor.as_dictionary(pdf).ok())
+                .and_then(|descriptor| get_number(pdf, descriptor, "MissingWidth"))
+                .unwrap_or(0.0)
+        };
+
+        let font_matrix = if subtype.as_deref() == Some("Type3") {
+            dictionary.get("FontMatrix").and_then(|matrix| matrix.as_f64_array()).filter(|matrix| matrix.len() == 6)
+        } else {
+            None
+        };
+
+        let differences = dictionary
+            .get("Encoding")
+            .and_then(|encoding| encoding.as_dictionary(pdf).ok())
+            .map(|encoding| parse_differences(pdf, encoding))
+            .unwrap_or_default();
+
+        let to_unicode = match dictionary.get("ToUnicode").map(|to_unicode| to_unicode.as_stream(pdf)) {
+            Some(Ok(stream)) => match stream.decompress() {
+                Ok(bytes) => Some(parse_to_unicode(&bytes)),
+                Err(error) => {
+                    warn!("Ignoring undecodable ToUnicode stream: {error}");
+                    None
+                }
+            },
+            Some(Err(error)) => {
+                warn!("Ignoring unusable ToUnicode entry: {error}");
+                None
+            },
+            None => None
+        };
+
+        Font {
+            base_font: get_name(pdf, dictionary, "BaseFont"),
+            first_char: get_number(pdf, dictionary, "FirstChar").unwrap_or(0.0) as u32,
+            subtype,
+            widths,
+            missing_width,
+            font_matrix,
+            differences,
+            to_unicode,
+            cid_to_gid: descendant
+                .and_then(|descendant| descendant.get("CIDToGIDMap"))
+                .and_then(|map| CidToGidMap::from_value(pdf, map))
+        }
+    }
+
+    pub fn from_value(pdf: &PDF, value: &PDFValue) -> Option<Self> {
+        value.as_dictionary(pdf).ok().map(|dictionary| Font::from_dictionary(pdf, dictionary))
+    }
+
+    /// Width of the glyph for `code` in thousandths of a text space unit
+    pub fn glyph_width(&self, code: u32) -> f64 {
+        let width = code.checked_sub(self.first_char)
+            .and_then(|index| self.widths.get(index as usize))
+            .copied()
+            .unwrap_or(self.missing_width);
+
+        match &self.font_matrix {
+            Some(matrix) => width * matrix[0] * 1000.0,
+            None => width
+        }
+    }
+
+    /// Bytes per character code in shown strings
+    pub fn code_length(&self) -> usize {
+        match (&self.to_unicode, self.subtype.as_deref()) {
+            (Some(to_unicode), _) => to_unicode.code_length,
+            (None, Some("Type0")) => 2,
+            _ => 1
+        }
+    }
+
+    /// Splits a shown string (one char per byte, as the lexer produces it) into character codes
+    pub fn codes(&self, text: &str) -> Vec<u32> {
+        let bytes: Vec<u32> = text.chars().map(|byte| byte as u32).collect();
+        bytes
+            .chunks(self.code_length())
+            .map(|code| code.iter().fold(0, |code, byte| code << 8 | byte))
+            .collect()
+    }
+
+    /// Unicode for a character code: the ToUnicode map wins, then the `/Differences`
+    /// glyph name, and otherwise the code itself
+    pub fn code_to_unicode(&self, code: u32) -> String {
+        if let Some(text) = self.to_unicode.as_ref().and_then(|to_unicode| to_unicode.mappings.get(&code)) {
+            return text.clone();
+        }
+
+        if let Some(text) = self.differences.get(&code).and_then(|name| glyph_name_to_unicode(name)) {
+            return text;
+        }
+
+        char::from_u32(code).map(String::from).unwrap_or_default()
+    }
+
+    pub fn decode(&self, text: &str) -> String {
+        self.codes(text).into_iter().map(|code| self.code_to_unicode(code)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::test_pdf::TestPdf;
+    use crate::tokenizer::PDFObjectHeader;
+
+    #[test]
+    fn cid_to_gid_map_from_a_stream_and_identity() {
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.object(5, "<< /Type /Font /Subtype /Type0 /BaseFont /Embedded /Encoding /Identity-H /DescendantFonts [6 0 R] >>");
+        pdf.object(6, "<< /Type /Font /Subtype /CIDFontType2 /BaseFont /Embedded /CIDToGIDMap 7 0 R >>");
+        pdf.stream(7, "", &[0x00, 0x00, 0x00, 0x05, 0x01, 0x02]);
+        pdf.object(8, "<< /Type /Font /Subtype /Type0 /BaseFont /Embedded /Encoding /Identity-H /DescendantFonts [<< /Subtype /CIDFontType2 /CIDToGIDMap /Identity >>] >>");
+        pdf.finish_section(&[0], "<< /Size 9 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let font = |object_number| Font::from_value(document.pdf(), &PDFValue::ObjectReference(PDFObjectHeader { object_number, generation_number: 0 })).unwrap();
+
+        let table = font(5).cid_to_gid.unwrap();
+        assert_eq!(table, CidToGidMap::Table(vec![0, 5, 258]));
+        assert_eq!((table.gid(1), table.gid(2), table.gid(3)), (Some(5), Some(258), None));
+        assert_eq!(font(8).cid_to_gid.unwrap().gid(300), Some(300));
+    }
+
+    #[test]
+    fn type3_text_maps_through_to_unicode_and_differences() {
+        let mut pdf = TestPdf::page("/Font << /T3 5 0 R >>", b"BT /T3 12 Tf <010203> Tj ET");
+        pdf.object(5, "<< /Type /Font /Subtype /Type3 /FontBBox [0 0 1000 1000] /FontMatrix [0.001 0 0 0.001 0 0] \
+            /CharProcs << /g1 7 0 R /g2 7 0 R /a 7 0 R >> /Encoding << /Type /Encoding /Differences [1 /g1 /g2 /a] >> \
+            /FirstChar 1 /LastChar 3 /Widths [500 500 500] /ToUnicode 6 0 R >>");
+        pdf.stream(6, "", b"/CIDInit /ProcSet findresource begin\n1 begincodespacerange <01> <03> endcodespacerange\n\
+            2 beginbfchar <01> <0048> <02> <0069> endbfchar\nendcmap");
+        pdf.stream(7, "", b"500 0 0 0 500 500 d1 0 0 500 500 re f");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        // Codes 1 and 2 come from the ToUnicode map, code 3 from its /Differences glyph name
+        assert_eq!(document.extract_text().unwrap(), "Hia\n");
+    }
+}
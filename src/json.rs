@@ -0,0 +1,88 @@
+use serde_json::{json, Map, Value};
+
+use crate::pdf::{PDF, PDFDictionary, PDFValue};
+use crate::strings::{decode_pdf_string, string_bytes};
+use crate::tokenizer::PDFObjectHeader;
+
+fn reference_json(reference: &PDFObjectHeader) -> Value {
+    json!({ "ref": [reference.object_number, reference.generation_number] })
+}
+
+/// Text strings as `u:` and their text, anything binary as `b:` and hex, as qpdf does
+fn string_json(bytes: &[u8]) -> Value {
+    let text = decode_pdf_string(bytes);
+    if text.chars().any(|character| character.is_control() && !character.is_whitespace()) {
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        Value::String(format!("b:{hex}"))
+    } else {
+        Value::String(format!("u:{text}"))
+    }
+}
+
+fn dictionary_json(dictionary: &PDFDictionary) -> Value {
+    let mut keys: Vec<&String> = dictionary.keys().collect();
+    keys.sort();
+
+    let mut object = Map::new();
+    for key in keys {
+        object.insert(format!("/{key}"), value_json(&dictionary[key]));
+    }
+    Value::Object(object)
+}
+
+/// Names keep their leading slash so they can be told apart from strings. Streams give
+/// their dictionary and decoded length instead of their data.
+pub fn value_json(value: &PDFValue) -> Value {
+    match value {
+        PDFValue::Dictionary(dictionary) => dictionary_json(dictionary),
+        PDFValue::Boolean(boolean) => Value::Bool(*boolean),
+        PDFValue::Array(values) => Value::Array(values.iter().map(value_json).collect()),
+        PDFValue::String(string) => string_json(&string_bytes(string)),
+        PDFValue::ObjectReference(reference) => reference_json(reference),
+        // PDF has separate integer and real objects, though both parse to f64 here
+        PDFValue::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => json!(*number as i64),
+        PDFValue::Number(number) => json!(number),
+        PDFValue::Name(name) => Value::String(format!("/{name}")),
+        PDFValue::Stream(stream) => {
+            let decoded = match stream.decompress() {
+                Ok(bytes) => json!({ "decoded_length": bytes.len() }),
+                Err(err) => json!({ "decode_error": err })
+            };
+            let mut object = Map::new();
+            object.insert("dict".to_string(), dictionary_json(&stream.dictionary));
+            if let Value::Object(decoded) = decoded {
+                object.extend(decoded);
+            }
+            Value::Object(object)
+        },
+        PDFValue::Bytes(bytes) => string_json(bytes),
+        PDFValue::Null => Value::Null
+    }
+}
+
+/// The whole parsed document: version, trailer, xref, every object keyed by
+/// `"<number> <generation>"`, and the page objects in order. Indirect references are
+/// written as `{ "ref": [number, generation] }`.
+pub fn to_json(pdf: &PDF) -> String {
+    let mut objects = Map::new();
+    for object in pdf.sorted_objects() {
+        objects.insert(
+            format!("{} {}", object.header.object_number, object.header.generation_number),
+            value_json(&object.value)
+        );
+    }
+
+    let document = json!({
+        "version": pdf.version,
+        "trailer": pdf.trailer_dictionary().map(dictionary_json),
+        "xref": pdf.xref_table.as_ref().and_then(|xref_table| serde_json::to_value(xref_table).ok()),
+        "objects": objects,
+        "pages": pdf.pages.iter().map(|page| reference_json(&page.object.header)).collect::<Vec<Value>>(),
+        "parse_errors": pdf.parse_errors
+            .iter()
+            .map(|(header, err)| json!({ "object": reference_json(header), "error": err }))
+            .collect::<Vec<Value>>()
+    });
+
+    serde_json::to_string_pretty(&document).unwrap_or_default()
+}
@@ -0,0 +1,21 @@
+pub mod tokenizer;
+pub mod reader;
+pub mod pdf;
+pub mod page;
+pub mod content_stream_lexer;
+pub mod text;
+pub mod font;
+pub mod cmap;
+pub mod strings;
+pub mod filters;
+pub mod structure;
+pub mod encryption;
+pub mod error;
+pub mod document;
+#[cfg(feature = "serde")]
+pub mod json;
+#[cfg(test)]
+mod test_pdf;
+
+pub use document::{Document, Page};
+pub use error::PdfError;
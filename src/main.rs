@@ -1,41 +1,131 @@
-// #![feature(collections)]
-
-use std::io::{prelude::*, Cursor};
-use std::fs::File;
-use env_logger::{Builder, Target};
-
-pub mod tokenizer;
-pub mod reader;
-pub mod pdf;
-pub mod page;
-pub mod content_stream_lexer;
-pub mod text;
-
-fn main() {
-    Builder::new()
-        .target(Target::Stdout)
-        .filter_level(log::LevelFilter::Debug)
+use std::env;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use env_logger::{Builder, Env, Target};
+
+use larry_pdf::Document;
+
+const USAGE: &str = "Usage: larry-pdf <file.pdf> [--page N] [--format text|json]";
+
+#[derive(PartialEq)]
+enum Format {
+    Text,
+    Json
+}
+
+struct Arguments {
+    path: String,
+    /// One-based page number, or every page when absent
+    page: Option<usize>,
+    format: Format
+}
+
+fn parse_arguments(mut arguments: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let mut path: Option<String> = None;
+    let mut page: Option<usize> = None;
+    let mut format = Format::Text;
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--page" => {
+                let number = arguments.next().ok_or("--page needs a page number")?;
+                page = match number.parse::<usize>() {
+                    Ok(number) if number > 0 => Some(number),
+                    _ => return Err(format!("Invalid page number '{number}'"))
+                };
+            },
+            "--format" => {
+                format = match arguments.next().as_deref() {
+                    Some("text") => Format::Text,
+                    Some("json") => Format::Json,
+                    other => return Err(format!("Unknown format {:?}, expected text or json", other.unwrap_or("")))
+                };
+            },
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if argument.starts_with("--") => return Err(format!("Unknown option {argument}")),
+            _ if path.is_none() => path = Some(argument),
+            _ => return Err(format!("Unexpected argument {argument}"))
+        }
+    }
+
+    Ok(Arguments {
+        path: path.ok_or("No input file given")?,
+        page,
+        format
+    })
+}
+
+#[cfg(feature = "serde")]
+fn json(document: &Document, page: Option<usize>) -> Result<String, String> {
+    match page {
+        Some(number) => {
+            let page = document.pages().nth(number - 1).ok_or(format!("The document has no page {number}"))?;
+            serde_json::to_string_pretty(&larry_pdf::json::value_json(&page.pdf_page().object.value)).map_err(|err| err.to_string())
+        },
+        None => Ok(document.to_json())
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn json(_document: &Document, _page: Option<usize>) -> Result<String, String> {
+    Err("JSON output needs the serde feature".to_string())
+}
+
+fn text(document: &Document, page: Option<usize>) -> Result<String, String> {
+    match page {
+        Some(number) => document
+            .pages()
+            .nth(number - 1)
+            .ok_or(format!("The document has no page {number}"))?
+            .text()
+            .map_err(|err| err.to_string()),
+        None => document.extract_text().map_err(|err| err.to_string())
+    }
+}
+
+fn main() -> ExitCode {
+    // Logs go to stderr so they never mix with the extracted text; RUST_LOG raises the level
+    Builder::from_env(Env::default().default_filter_or("warn"))
+        .target(Target::Stderr)
         .init();
 
-    let mut f = File::open("/Users/andrew/Downloads/63dcb628-666e-457e-a989-3e9ca38f6b78.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/Borrower 210001967312 - 1098-E Tax Form (1).pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/779503749_2022-05-11.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/bill-8743148.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/centurylink.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/documents.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/Loan 360001863193 - 10_28_2010 Line Of Credit Statement.pdf").unwrap();
-    // let mut f = File::open("/Users/andrew/Downloads/ug527-brd4188b-user-guide.pdf").unwrap();
-    let mut bytes: Vec<u8> = Vec::new();
-    f.read_to_end(&mut bytes);
-    let mut cursor = Cursor::new(bytes);
-    let file_size = f.stream_position().unwrap();
-    println!("file size: {}", file_size);
-    println!("cursor pos: {}", cursor.stream_position().unwrap());
-
-    let mut tokenizer: tokenizer::Tokenizer<Cursor<Vec<u8>>> = tokenizer::Tokenizer::new(cursor);
-    let mut pdf_reader = reader::Reader::new(tokenizer);
-
-    pdf_reader.read();
-
-    println!("DONE");
+    let arguments = match parse_arguments(env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            eprintln!("{err}");
+            if err != USAGE {
+                eprintln!("{USAGE}");
+            }
+            return ExitCode::from(2);
+        }
+    };
+
+    let document = match Document::open(&arguments.path) {
+        Ok(document) => document,
+        Err(err) => {
+            eprintln!("Could not read {}: {err}", arguments.path);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let output = match arguments.format {
+        Format::Text => text(&document, arguments.page),
+        Format::Json => json(&document, arguments.page)
+    };
+
+    match output {
+        Ok(mut output) => {
+            if arguments.format == Format::Json {
+                output.push('\n');
+            }
+            // A closed pipe, as with `| head`, is not worth a panic
+            let _ = io::stdout().lock().write_all(output.as_bytes());
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
 }
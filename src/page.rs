@@ -1,9 +1,8 @@
-// use core::slice::SlicePattern;
-use std::{io::{Cursor, Write}, borrow::Borrow, fs::File};
+use std::collections::{HashMap, HashSet};
 
-use crate::{pdf::{PDFObject, PDFValue}, tokenizer::{Tokenizer, self, PDFTokenize}, content_stream_lexer::{parse, ContentToken}, text::{get_text_objects, compile_grouped_text}};
+use log::warn;
 
-use log::debug;
+use crate::{error::PdfError, font::Font, pdf::{PDF, PDFDictionary, PDFObject, PDFStream, PDFValue}, tokenizer::PDFObjectHeader, content_stream_lexer::{parse, parse_with, ContentToken}, structure::{reading_order, StructureItem}, text::{get_text_objects, get_text_objects_with_ctm, rotation_matrix, compile_grouped_text, multiply_matrices, FormXObject, TextResources, TextExtractionOptions, IDENTITY_MATRIX}};
 
 
 #[derive(Debug, Clone)]
@@ -14,39 +13,425 @@ pub struct PDFPage {
 
 
 impl PDFPage {
-    pub fn get_text(&self, temp: i32) {
-        // println!("{:?}", self);
-        let stream_bytes = self.contents.value.stream().unwrap().decompress();
-
-        // let filename = format!("page_{}.bin",temp);
-        // let mut file = File::create(filename).unwrap();
-        // file.write_all(&stream_bytes);
-        
-        // println!("{}\n\n", String::from_utf8_lossy(&stream_bytes));
-        let tokens = parse(stream_bytes.as_slice());
-        let positioned_text = get_text_objects(&tokens);
-        let grouped_text = compile_grouped_text(positioned_text.as_slice());
-        // println!("==============\nThe Tokens\n==============\n");
-        // for token in tokens {
-        //     match token {
-        //         ContentToken::ShowTextString(text) => {
-        //             println!("TEXT: {}", text);
-        //         },
-        //         t => println!("{:?}", t)
-        //     }
-        // }
-
-        // Get any text contained in the pages X-Objects
-        // println!("Object: {:?}",self.object.value);
-        // match &self.object.value {
-        //     PDFValue::Dictionary(dict) => {
-        //         println!("Found dict: {:?}", dict);
-        //         let contents = dict.get("Contents").unwrap();
-
-        //     },
-        //     _ => {}
-        // }
-
-        panic!();
+    pub fn resources<'a>(&'a self, pdf: &'a PDF) -> Option<&'a PDFDictionary> {
+        self.object
+            .value
+            .as_dictionary(pdf)
+            .ok()?
+            .get("Resources")?
+            .as_dictionary(pdf)
+            .ok()
+    }
+
+    /// The page's `/MediaBox` (inherited when read through the page tree), or US Letter
+    /// when it has none
+    pub fn media_box(&self, pdf: &PDF) -> Vec<f64> {
+        self.object
+            .value
+            .as_dictionary(pdf)
+            .ok()
+            .and_then(|dictionary| dictionary.get("MediaBox"))
+            .and_then(|media_box| media_box.as_array(pdf).ok())
+            .map(|media_box| media_box.iter().filter_map(|side| side.as_number(pdf).ok()).collect::<Vec<f64>>())
+            .filter(|media_box| media_box.len() == 4)
+            .unwrap_or_else(|| vec![0.0, 0.0, 612.0, 792.0])
+    }
+
+    /// Clockwise rotation in degrees when the page is displayed: 0, 90, 180 or 270
+    pub fn rotation(&self, pdf: &PDF) -> i64 {
+        let rotate = self.object
+            .value
+            .as_dictionary(pdf)
+            .ok()
+            .and_then(|dictionary| dictionary.get("Rotate"))
+            .and_then(|rotate| rotate.as_number(pdf).ok())
+            .unwrap_or(0.0) as i64;
+
+        // Only multiples of 90 are allowed; round anything else to the nearest one
+        ((rotate as f64 / 90.0).round() as i64 * 90).rem_euclid(360)
+    }
+
+    /// The matrix text positions start from: the identity, or the page's rotation when
+    /// `options.apply_rotation` is set
+    fn initial_matrix(&self, pdf: &PDF, options: &TextExtractionOptions) -> Vec<f64> {
+        if options.apply_rotation {
+            rotation_matrix(self.rotation(pdf), &self.media_box(pdf))
+        } else {
+            IDENTITY_MATRIX.to_vec()
+        }
+    }
+
+    /// Names in the page's `/Properties` resources whose optional content is hidden by default
+    pub fn hidden_properties(&self, pdf: &PDF) -> HashSet<String> {
+        match self.resources(pdf) {
+            Some(resources) => collect_hidden_properties(pdf, resources, &pdf.hidden_optional_content()),
+            None => HashSet::new()
+        }
+    }
+
+    /// Resolves everything the text layer needs from the page resources, including the
+    /// content of any form XObjects the page paints
+    pub fn text_resources(&self, pdf: &PDF) -> TextResources {
+        collect_text_resources(pdf, self.resources(pdf), &pdf.hidden_optional_content(), &mut vec![])
+    }
+
+    /// The page's text, one line per text object
+    pub fn text(&self, pdf: &PDF, options: &TextExtractionOptions) -> Result<String, PdfError> {
+        let stream_bytes = self.contents.value.stream().and_then(PDFStream::decompress).map_err(PdfError::Malformed)?;
+        let tokens = parse(stream_bytes.as_slice()).map_err(PdfError::ContentStream)?;
+        let text_objects = get_text_objects_with_ctm(&tokens, &self.text_resources(pdf), options, &self.initial_matrix(pdf, options));
+        Ok(compile_grouped_text(text_objects.as_slice()))
+    }
+
+    /// The page's text in the logical order of the structure tree, with a blank line after
+    /// each paragraph or heading. Untagged pages fall back to `text`.
+    pub fn extract_text_structured(&self, pdf: &PDF) -> Result<String, PdfError> {
+        let page_items: Vec<StructureItem> = reading_order(pdf)
+            .into_iter()
+            .filter(|item| match item {
+                StructureItem::Content { page, .. } => *page == self.object.header,
+                StructureItem::BlockEnd => true
+            })
+            .collect();
+
+        if !page_items.iter().any(|item| matches!(item, StructureItem::Content { .. })) {
+            return self.text(pdf, &TextExtractionOptions::default());
+        }
+
+        let stream_bytes = self.contents.value.stream().and_then(PDFStream::decompress).map_err(PdfError::Malformed)?;
+        let tokens = parse(stream_bytes.as_slice()).map_err(PdfError::ContentStream)?;
+        let text_objects = get_text_objects(&tokens, &self.text_resources(pdf), &TextExtractionOptions::default());
+
+        let mut marked_text: HashMap<u64, String> = HashMap::new();
+        for positioned_text in text_objects.iter().flat_map(|text_object| &text_object.positioned_text) {
+            if let Some(mcid) = positioned_text.mcid {
+                marked_text.entry(mcid).or_default().push_str(&positioned_text.text);
+            }
+        }
+
+        let mut text = String::new();
+        for item in page_items {
+            match item {
+                StructureItem::Content { mcid, .. } => {
+                    if let Some(content) = marked_text.get(&mcid) {
+                        text.push_str(content);
+                    }
+                },
+                StructureItem::BlockEnd => {
+                    if !text.is_empty() && !text.ends_with("\n\n") {
+                        text.push_str("\n\n");
+                    }
+                }
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// Every image XObject the page paints, with the CTM in effect at its `Do`. The image
+    /// fills the unit square of that matrix, so it gives both position and size.
+    pub fn image_placements(&self, pdf: &PDF) -> Result<Vec<(PDFObjectHeader, Vec<f64>)>, String> {
+        let stream_bytes = self.contents.value.stream()?.decompress()?;
+        let mut placements = vec![];
+        collect_image_placements(pdf, &stream_bytes, self.resources(pdf), IDENTITY_MATRIX.to_vec(), &mut vec![], &mut placements)?;
+        Ok(placements)
+    }
+
+    /// The page's text with the default extraction options
+    pub fn extract_text(&self, pdf: &PDF) -> Result<String, PdfError> {
+        self.text(pdf, &TextExtractionOptions::default())
+    }
+}
+
+fn collect_hidden_properties(pdf: &PDF, resources: &PDFDictionary, hidden_groups: &HashSet<PDFObjectHeader>) -> HashSet<String> {
+    let mut hidden_properties: HashSet<String> = HashSet::new();
+    if hidden_groups.is_empty() {
+        return hidden_properties;
+    }
+
+    if let Some(Ok(properties)) = resources.get("Properties").map(|properties| properties.as_dictionary(pdf)) {
+        for (name, value) in properties.iter() {
+            if is_optional_content_hidden(pdf, value, hidden_groups) {
+                hidden_properties.insert(name.clone());
+            }
+        }
+    }
+
+    hidden_properties
+}
+
+/// `form_path` holds the forms currently being resolved so a form that paints itself
+/// (directly or through another form) is only expanded once
+fn collect_text_resources(pdf: &PDF, resources: Option<&PDFDictionary>, hidden_groups: &HashSet<PDFObjectHeader>, form_path: &mut Vec<PDFObjectHeader>) -> TextResources {
+    let mut text_resources = TextResources::default();
+    let resources = match resources {
+        Some(resources) => resources,
+        None => return text_resources
+    };
+
+    text_resources.hidden_properties = collect_hidden_properties(pdf, resources, hidden_groups);
+
+    if let Some(Ok(fonts)) = resources.get("Font").map(|fonts| fonts.as_dictionary(pdf)) {
+        for (name, font) in fonts.iter() {
+            if let Some(font) = Font::from_value(pdf, font) {
+                text_resources.fonts.insert(name.clone(), font);
+            }
+        }
+    }
+
+    let x_objects = match resources.get("XObject").map(|x_objects| x_objects.as_dictionary(pdf)) {
+        Some(Ok(x_objects)) => x_objects,
+        _ => return text_resources
+    };
+
+    for (name, value) in x_objects.iter() {
+        let reference = match value {
+            PDFValue::ObjectReference(reference) => *reference,
+            _ => continue
+        };
+        let stream = match value.as_stream(pdf) {
+            Ok(stream) => stream,
+            Err(_) => continue
+        };
+        if stream.dictionary.get("Subtype").and_then(|subtype| subtype.as_name(pdf).ok()).map(String::as_str) != Some("Form") {
+            continue;
+        }
+        if form_path.contains(&reference) {
+            warn!("Form XObject {:?} paints itself, not following it again", reference);
+            continue;
+        }
+
+        let matrix = stream.dictionary
+            .get("Matrix")
+            .and_then(|matrix| matrix.as_f64_array())
+            .filter(|matrix| matrix.len() == 6)
+            .unwrap_or_else(|| IDENTITY_MATRIX.to_vec());
+
+        // Forms without their own resources use the ones of whatever paints them
+        let form_resources = match stream.dictionary.get("Resources").map(|form_resources| form_resources.as_dictionary(pdf)) {
+            Some(Ok(form_resources)) => form_resources,
+            _ => resources
+        };
+
+        form_path.push(reference);
+        let nested_resources = collect_text_resources(pdf, Some(form_resources), hidden_groups, form_path);
+        form_path.pop();
+
+        let tokens = match stream.decompress().and_then(|bytes| parse(&bytes)) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                warn!("Skipping form XObject {name}: {err}");
+                continue;
+            }
+        };
+
+        text_resources.forms.insert(name.clone(), FormXObject {
+            tokens,
+            matrix,
+            resources: nested_resources
+        });
+    }
+
+    text_resources
+}
+
+/// Visibility of an optional content group, or of a membership dictionary (`/Type /OCMD`)
+/// evaluated with its `/P` visibility policy
+fn is_optional_content_hidden(pdf: &PDF, value: &PDFValue, hidden_groups: &HashSet<PDFObjectHeader>) -> bool {
+    if let PDFValue::ObjectReference(reference) = value {
+        if hidden_groups.contains(reference) {
+            return true;
+        }
+    }
+
+    let dictionary = match value.as_dictionary(pdf) {
+        Ok(dictionary) => dictionary,
+        Err(_) => return false
+    };
+    if dictionary.get("Type").and_then(|value| value.as_name(pdf).ok()).map(String::as_str) != Some("OCMD") {
+        return false;
+    }
+
+    let groups_hidden: Vec<bool> = match dictionary.get("OCGs") {
+        Some(PDFValue::ObjectReference(reference)) => vec![hidden_groups.contains(reference)],
+        Some(PDFValue::Array(groups)) => groups
+            .iter()
+            .map(|group| matches!(group, PDFValue::ObjectReference(reference) if hidden_groups.contains(reference)))
+            .collect(),
+        _ => return false
+    };
+    if groups_hidden.is_empty() {
+        return false;
+    }
+
+    let policy = dictionary.get("P").and_then(|value| value.as_name(pdf).ok()).map(String::as_str);
+    let visible = match policy {
+        Some("AllOn") => groups_hidden.iter().all(|hidden| !hidden),
+        Some("AnyOff") => groups_hidden.iter().any(|hidden| *hidden),
+        Some("AllOff") => groups_hidden.iter().all(|hidden| *hidden),
+        _ => groups_hidden.iter().any(|hidden| !hidden)
+    };
+
+    !visible
+}
+
+fn collect_image_placements(pdf: &PDF, content: &[u8], resources: Option<&PDFDictionary>, ctm: Vec<f64>, form_path: &mut Vec<PDFObjectHeader>, placements: &mut Vec<(PDFObjectHeader, Vec<f64>)>) -> Result<(), String> {
+    let x_objects = resources
+        .and_then(|resources| resources.get("XObject"))
+        .and_then(|x_objects| x_objects.as_dictionary(pdf).ok());
+
+    let mut ctm = ctm;
+    let mut ctm_stack: Vec<Vec<f64>> = vec![];
+
+    parse_with(content, |token| match token {
+        ContentToken::Cm(matrix) => {
+            ctm = multiply_matrices(&matrix, &ctm);
+        },
+        ContentToken::SaveGraphicsState => {
+            ctm_stack.push(ctm.clone());
+        },
+        ContentToken::RestoreGraphicsState => {
+            if let Some(saved_ctm) = ctm_stack.pop() {
+                ctm = saved_ctm;
+            }
+        },
+        ContentToken::PaintXObject(name) => {
+            let (reference, stream) = match x_objects.and_then(|x_objects| x_objects.get(&name)) {
+                Some(value @ PDFValue::ObjectReference(reference)) => match value.as_stream(pdf) {
+                    Ok(stream) => (*reference, stream),
+                    Err(_) => return
+                },
+                _ => return
+            };
+
+            match stream.dictionary.get("Subtype").and_then(|subtype| subtype.as_name(pdf).ok()).map(String::as_str) {
+                Some("Image") => placements.push((reference, ctm.clone())),
+                Some("Form") if !form_path.contains(&reference) => {
+                    let matrix = stream.dictionary
+                        .get("Matrix")
+                        .and_then(|matrix| matrix.as_f64_array())
+                        .filter(|matrix| matrix.len() == 6)
+                        .unwrap_or_else(|| IDENTITY_MATRIX.to_vec());
+                    let form_resources = match stream.dictionary.get("Resources").map(|form_resources| form_resources.as_dictionary(pdf)) {
+                        Some(Ok(form_resources)) => Some(form_resources),
+                        _ => resources
+                    };
+
+                    form_path.push(reference);
+                    let placed = stream.decompress().and_then(|bytes| {
+                        collect_image_placements(pdf, &bytes, form_resources, multiply_matrices(&matrix, &ctm), form_path, placements)
+                    });
+                    if let Err(err) = placed {
+                        warn!("Skipping images in form XObject {name}: {err}");
+                    }
+                    form_path.pop();
+                },
+                _ => {}
+            }
+        },
+        _ => {}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::test_pdf::TestPdf;
+
+    #[test]
+    fn glyphs_advance_by_their_widths() {
+        let mut pdf = TestPdf::page("/Font << /F1 5 0 R >>", b"BT /F1 10 Tf 1 0 0 1 100 50 Tm (ABC) Tj ET");
+        pdf.object(5, "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /FirstChar 65 /LastChar 67 /Widths [500 600 700] >>");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let page = document.pages().next().unwrap().pdf_page();
+        let tokens = parse(&page.contents.value.stream().unwrap().decompress().unwrap()).unwrap();
+        let options = TextExtractionOptions { glyphs: true, ..Default::default() };
+        let text_objects = get_text_objects(&tokens, &page.text_resources(document.pdf()), &options);
+
+        let glyphs: Vec<(String, f64, f64)> = text_objects[0].positioned_text[0].glyphs
+            .iter()
+            .map(|glyph| (glyph.unicode.clone(), glyph.x, glyph.advance))
+            .collect();
+        assert_eq!(glyphs, vec![
+            ("A".to_string(), 100.0, 5.0),
+            ("B".to_string(), 105.0, 6.0),
+            ("C".to_string(), 111.0, 7.0)
+        ]);
+    }
+
+    #[test]
+    fn consecutive_show_operators_continue_where_the_last_ended() {
+        let mut pdf = TestPdf::page("/Font << /F1 5 0 R >>", b"BT /F1 10 Tf 1 0 0 1 100 50 Tm (AB) Tj (C) Tj 2 Tc 50 Tz (A) Tj (B) Tj ET");
+        pdf.object(5, "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /FirstChar 65 /LastChar 67 /Widths [500 600 700] >>");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let page = document.pages().next().unwrap().pdf_page();
+        let tokens = parse(&page.contents.value.stream().unwrap().decompress().unwrap()).unwrap();
+        let options = TextExtractionOptions { glyphs: true, ..Default::default() };
+        let text_objects = get_text_objects(&tokens, &page.text_resources(document.pdf()), &options);
+
+        let runs: Vec<(&str, f64, Vec<f64>)> = text_objects[0].positioned_text
+            .iter()
+            .map(|text| (text.text.as_str(), text.x, text.glyphs.iter().map(|glyph| glyph.x).collect()))
+            .collect();
+        // "A" with Tc 2 at 50% scaling advances (5 + 2) / 2
+        assert_eq!(runs, vec![
+            ("AB", 100.0, vec![100.0, 105.0]),
+            ("C", 111.0, vec![111.0]),
+            ("A", 118.0, vec![118.0]),
+            ("B", 121.5, vec![121.5])
+        ]);
+    }
+
+    #[test]
+    fn text_inside_a_form_xobject_is_extracted_once() {
+        let mut pdf = TestPdf::page("/XObject << /Fm0 5 0 R >>", b"q 1 0 0 1 100 200 cm /Fm0 Do Q");
+        // The form paints itself, which must not recurse forever
+        pdf.stream(5, "/Type /XObject /Subtype /Form /Matrix [1 0 0 1 10 10] /Resources << /XObject << /Fm0 5 0 R >> >>", b"BT (Inside) Tj ET /Fm0 Do");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let page = document.pages().next().unwrap().pdf_page();
+        assert_eq!(page.extract_text(document.pdf()).unwrap(), "Inside\n");
+
+        let tokens = parse(&page.contents.value.stream().unwrap().decompress().unwrap()).unwrap();
+        let text_objects = get_text_objects(&tokens, &page.text_resources(document.pdf()), &TextExtractionOptions::default());
+        let inside = &text_objects[0].positioned_text[0];
+        assert_eq!((inside.x, inside.y), (110.0, 210.0));
+    }
+
+    #[test]
+    fn text_in_a_hidden_layer_is_dropped_by_default() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R /OCProperties << /OCGs [5 0 R] /D << /OFF [5 0 R] >> >> >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Resources << /Properties << /MC0 5 0 R >> >> /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Shown) Tj ET /OC /MC0 BDC BT (Hidden) Tj ET EMC");
+        pdf.object(5, "<< /Type /OCG /Name (Layer) >>");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let page = document.pages().next().unwrap();
+        // The hidden text object still ends a line, so only its text is missing
+        assert_eq!(page.text().unwrap().trim_end(), "Shown");
+        let options = TextExtractionOptions { include_hidden_layers: true, ..Default::default() };
+        assert_eq!(page.text_with(&options).unwrap(), "Shown\nHidden\n");
+    }
+
+    #[test]
+    fn undecodable_content_is_an_error_rather_than_empty_text() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "/Filter /FlateDecode", b"BT (Not zlib) Tj ET");
+        pdf.finish_section(&[0], "<< /Size 5 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert!(matches!(document.pages().next().unwrap().text(), Err(PdfError::Malformed(_))));
     }
 }
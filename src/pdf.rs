@@ -1,33 +1,86 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek};
+use std::ops::{Bound, RangeBounds};
 use std::option::Option;
 
-use flate2::Decompress;
+use log::warn;
 
-use crate::tokenizer::{PDFObjectHeader, XRefSection};
+use crate::error::PdfError;
+use crate::filters::{apply_filter, is_supported_filter};
+use crate::reader::Reader;
+use crate::tokenizer::{PDFObjectHeader, Tokenizer, XRefSection};
 use crate::page::{PDFPage};
+use crate::strings::{decode_pdf_string, string_bytes};
+use crate::text::TextExtractionOptions;
+
+/// Placed between the text of consecutive pages
+pub const PAGE_SEPARATOR: char = '\u{000C}';
 
 pub type PDFDictionary = HashMap<String, PDFValue>;
 
+/// Serializes raw bytes as a base64 string, which keeps stream data and hex strings
+/// compact and readable in JSON
+#[cfg(feature = "serde")]
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PDFStream {
     pub dictionary: PDFDictionary,
+    #[cfg_attr(feature = "serde", serde(with = "base64_bytes"))]
     pub bytes: Vec<u8>
 }
 
 impl PDFStream {
-    pub fn decompress(&self) -> Vec<u8> {
-        let mut decompress = Decompress::new(true);
-        let mut decompressed_bytes: Vec<u8> = Vec::with_capacity(self.bytes.len() * 3);
-        decompress.decompress_vec(
-            &self.bytes,
-            &mut decompressed_bytes,
-            flate2::FlushDecompress::Sync).unwrap();
-        decompressed_bytes
+    /// The stream's data with its filters undone: the whole `/Filter` chain is applied in
+    /// order, each stage with its `/DecodeParms` entry. Corrupt data or an unsupported
+    /// filter is an error.
+    pub fn decompress(&self) -> Result<Vec<u8>, String> {
+        let params = self.decode_parms();
+        let mut bytes = self.bytes.clone();
+        for (index, filter) in self.filters().iter().enumerate() {
+            bytes = apply_filter(filter, params.get(index).copied().flatten(), &bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn filters(&self) -> Vec<String> {
+        match self.dictionary.get("Filter") {
+            Some(PDFValue::Array(filters)) => filters
+                .iter()
+                .filter_map(|filter| filter.name().ok().cloned())
+                .collect(),
+            Some(filter) => filter.name().into_iter().cloned().collect(),
+            None => vec![]
+        }
+    }
+
+    /// The `/DecodeParms` dictionary for each filter, lined up with `filters()` by index
+    pub fn decode_parms(&self) -> Vec<Option<&PDFDictionary>> {
+        match self.dictionary.get("DecodeParms") {
+            Some(PDFValue::Array(params)) => params.iter().map(|params| params.dictionary().ok()).collect(),
+            Some(params) => vec![params.dictionary().ok()],
+            None => vec![]
+        }
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PDFValue {
     Dictionary(PDFDictionary),
     Boolean(bool),
@@ -37,16 +90,31 @@ pub enum PDFValue {
     Number(f64),
     Name(String),
     Stream(PDFStream),
-    Bytes(Vec<u8>),
+    Bytes(#[cfg_attr(feature = "serde", serde(with = "base64_bytes"))] Vec<u8>),
     Null
 }
 
 impl PDFValue {
-    pub fn object_reference(&self) -> &PDFObjectHeader {
-        if let PDFValue::ObjectReference(object_reference) = self {
-            object_reference
-        } else {
-            panic!("Value is not ObjectReference")
+    /// The kind of object, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PDFValue::Dictionary(_) => "Dictionary",
+            PDFValue::Boolean(_) => "Boolean",
+            PDFValue::Array(_) => "Array",
+            PDFValue::String(_) => "String",
+            PDFValue::ObjectReference(_) => "ObjectReference",
+            PDFValue::Number(_) => "Number",
+            PDFValue::Name(_) => "Name",
+            PDFValue::Stream(_) => "Stream",
+            PDFValue::Bytes(_) => "Bytes",
+            PDFValue::Null => "Null"
+        }
+    }
+
+    pub fn object_reference(&self) -> Result<&PDFObjectHeader, String> {
+        match self {
+            PDFValue::ObjectReference(object_reference) => Ok(object_reference),
+            _ => Err("Value is not ObjectReference".to_string())
         }
     }
 
@@ -64,16 +132,140 @@ impl PDFValue {
         }
     }
 
-    pub fn array(&self) -> &Vec<PDFValue> {
-        if let PDFValue::Array(array) = self {
-            array
-        } else {
-            panic!("Value is not Array")
+    pub fn name(&self) -> Result<&String, String> {
+        match self {
+            PDFValue::Name(name) | PDFValue::String(name) => Ok(name),
+            _ => Err("Value is not Name".to_string())
+        }
+    }
+
+    pub fn array(&self) -> Result<&Vec<PDFValue>, String> {
+        match self {
+            PDFValue::Array(array) => Ok(array),
+            _ => Err("Value is not Array".to_string())
+        }
+    }
+
+    /// The numbers of an array such as `/Matrix` or `/BBox`, if every element is a number
+    pub fn as_f64_array(&self) -> Option<Vec<f64>> {
+        match self {
+            PDFValue::Array(array) => array
+                .iter()
+                .map(|value| match value {
+                    PDFValue::Number(number) => Some(*number),
+                    _ => None
+                })
+                .collect(),
+            _ => None
+        }
+    }
+
+    /// Resolves to a dictionary; a stream counts as its own dictionary, the way viewers treat it
+    pub fn as_dictionary<'a>(&'a self, pdf: &'a PDF) -> Result<&'a PDFDictionary, String> {
+        match pdf.resolve(self)? {
+            PDFValue::Stream(stream) => Ok(&stream.dictionary),
+            value => value.dictionary()
+        }
+    }
+
+    pub fn as_array<'a>(&'a self, pdf: &'a PDF) -> Result<&'a Vec<PDFValue>, String> {
+        match pdf.resolve(self)? {
+            PDFValue::Array(array) => Ok(array),
+            _ => Err("Value is not Array".to_string())
+        }
+    }
+
+    pub fn as_stream<'a>(&'a self, pdf: &'a PDF) -> Result<&'a PDFStream, String> {
+        pdf.resolve(self)?.stream()
+    }
+
+    pub fn as_name<'a>(&'a self, pdf: &'a PDF) -> Result<&'a String, String> {
+        pdf.resolve(self)?.name()
+    }
+
+    /// A user-facing text string such as a title or file name, decoded from either
+    /// literal or hex form
+    pub fn as_text(&self, pdf: &PDF) -> Result<String, String> {
+        match pdf.resolve(self)? {
+            PDFValue::String(string) => Ok(decode_pdf_string(&string_bytes(string))),
+            PDFValue::Bytes(bytes) => Ok(decode_pdf_string(bytes)),
+            _ => Err("Value is not a text string".to_string())
+        }
+    }
+
+    pub fn as_number(&self, pdf: &PDF) -> Result<f64, String> {
+        match pdf.resolve(self)? {
+            PDFValue::Number(number) => Ok(*number),
+            _ => Err("Value is not Number".to_string())
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SizeDiscrepancy {
+    /// An object numbered at or past the declared `/Size`
+    ObjectBeyondSize(PDFObjectHeader),
+    /// Fewer distinct object numbers under `/Size` were parsed, counting the free object 0,
+    /// than it accounts for, so objects were lost
+    MissingObjects { declared: u64, parsed: u64 }
+}
+
+/// A structural problem found by `PDF::validate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    /// A required trailer entry (`Root` or `Size`) is absent
+    MissingTrailerEntry(&'static str),
+    /// `/Root` does not resolve to a dictionary
+    MissingCatalog,
+    CatalogNotTypeCatalog,
+    CatalogMissingPages,
+    /// A page with no `/MediaBox` of its own or on any ancestor
+    PageMissingMediaBox(Option<PDFObjectHeader>),
+    PageMissingContents(Option<PDFObjectHeader>),
+    /// `from` holds a reference to an object that was not found in the file
+    UnresolvedReference { from: PDFObjectHeader, to: PDFObjectHeader }
+}
+
+/// An entry of the document outline (bookmarks, 12.3.3)
 #[derive(Debug, Clone)]
+pub struct OutlineItem {
+    pub title: String,
+    /// Whether the item is shown expanded, from the sign of its `/Count`
+    pub open: bool,
+    /// The magnitude of `/Count`: how many descendants are visible when the item is open
+    pub descendant_count: i64,
+    pub children: Vec<OutlineItem>
+}
+
+/// An image XObject painted on a page
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageOccurrence {
+    pub image: PDFObjectHeader,
+    /// Zero based index into `PDF::pages`
+    pub page: usize,
+    /// The CTM at the `Do` operator; the image occupies the unit square in this space
+    pub matrix: Vec<f64>
+}
+
+/// A structural difference found by `diff`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    ObjectAdded(PDFObjectHeader),
+    ObjectRemoved(PDFObjectHeader),
+    ObjectChanged(PDFObjectHeader),
+    PageCountChanged { before: usize, after: usize },
+    /// The decoded content stream of the page at this index differs
+    PageContentChanged(usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct EmbeddedFile {
+    pub name: String,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PDFObject {
     pub header: PDFObjectHeader,
     pub value: PDFValue,
@@ -89,4 +281,722 @@ pub struct PDF {
     pub trailer: Option<PDFDictionary>,
     pub xref_table: Option<XRefSection>,
     pub pages: Vec<PDFPage>,
+    /// Objects that could not be parsed and were left out of `objects`
+    pub parse_errors: Vec<(PDFObjectHeader, String)>,
+}
+
+impl PDF {
+    /// Opens just enough of a document to show its first page: the catalog, the page tree
+    /// path to the first page and that page's own objects. Nothing else is parsed.
+    pub fn open_first_page<R: Read + Seek>(source: R) -> Result<(PDF, PDFPage), PdfError> {
+        Reader::new(Tokenizer::new(source)).first_page()
+    }
+
+    pub fn get_object(&self, reference: &PDFObjectHeader) -> Option<&PDFObject> {
+        self.objects.get(reference)
+    }
+
+    /// Follows object references until a direct value is reached
+    pub fn resolve<'a>(&'a self, value: &'a PDFValue) -> Result<&'a PDFValue, String> {
+        let mut value = value;
+        let mut depth = 0;
+        while let PDFValue::ObjectReference(reference) = value {
+            // A reference chain longer than the object count can only be a cycle
+            if depth > self.objects.len() {
+                return Err(format!("Reference cycle while resolving {:?}", reference));
+            }
+            value = match self.get_object(reference) {
+                Some(object) => &object.value,
+                None => return Err(format!("Referenced object {:?} not found", reference))
+            };
+            depth += 1;
+        }
+        Ok(value)
+    }
+
+    /// The trailer dictionary, or for files using a cross-reference stream, the stream's
+    /// dictionary which takes the trailer's place
+    pub fn trailer_dictionary(&self) -> Option<&PDFDictionary> {
+        if let Some(trailer) = &self.trailer {
+            return Some(trailer);
+        }
+
+        let startxref = self.startxref?;
+        self.objects
+            .values()
+            .find(|object| object.offset == startxref)
+            .and_then(|object| object.value.stream().ok())
+            .map(|stream| &stream.dictionary)
+    }
+
+    /// The trailer's `/Size`: one greater than the highest object number in the file
+    pub fn declared_size(&self) -> Option<u64> {
+        self.trailer_dictionary()?
+            .get("Size")?
+            .as_number(self)
+            .ok()
+            .map(|size| size as u64)
+    }
+
+    /// Compares the declared `/Size` with the objects that were actually parsed. Only
+    /// loaded objects count, so after `open_first_page` every object that was never asked
+    /// for looks missing.
+    pub fn size_discrepancies(&self) -> Vec<SizeDiscrepancy> {
+        let declared = match self.declared_size() {
+            Some(declared) => declared,
+            None => return vec![]
+        };
+
+        let mut discrepancies: Vec<SizeDiscrepancy> = self.sorted_objects()
+            .into_iter()
+            .filter(|object| object.header.object_number >= declared)
+            .map(|object| SizeDiscrepancy::ObjectBeyondSize(object.header))
+            .collect();
+
+        // Objects past /Size are reported above and cannot make up for missing ones
+        let mut object_numbers: HashSet<u64> = self.objects
+            .keys()
+            .map(|header| header.object_number)
+            .filter(|object_number| *object_number < declared)
+            .collect();
+        object_numbers.insert(0);
+        let parsed = object_numbers.len() as u64;
+        if parsed < declared {
+            discrepancies.push(SizeDiscrepancy::MissingObjects { declared, parsed });
+        }
+
+        discrepancies
+    }
+
+    pub fn catalog(&self) -> Option<&PDFDictionary> {
+        self.root.as_ref().and_then(|root| root.value.as_dictionary(self).ok())
+    }
+
+    /// The root `/Pages` node's `/Count`, read without building the page list.
+    /// Debug builds also walk the page tree and warn when the two disagree
+    pub fn page_count_fast(&self) -> Option<u64> {
+        let pages = self.catalog()?.get("Pages")?;
+        let count = pages.as_dictionary(self).ok()?.get("Count")?.as_number(self).ok()?;
+        if count < 0.0 {
+            return None;
+        }
+        let count = count as u64;
+
+        if cfg!(debug_assertions) {
+            let walked = self.count_page_leaves(pages, &mut HashSet::new());
+            if walked != count {
+                warn!("Page tree /Count is {count} but the tree has {walked} pages");
+            }
+        }
+
+        Some(count)
+    }
+
+    fn count_page_leaves(&self, node: &PDFValue, visited: &mut HashSet<PDFObjectHeader>) -> u64 {
+        if let PDFValue::ObjectReference(reference) = node {
+            if !visited.insert(*reference) {
+                return 0;
+            }
+        }
+
+        let node = match node.as_dictionary(self) {
+            Ok(node) => node,
+            Err(_) => return 0
+        };
+
+        let is_page = node
+            .get("Type")
+            .and_then(|node_type| node_type.as_name(self).ok())
+            .is_some_and(|node_type| node_type == "Page");
+
+        match node.get("Kids").map(|kids| kids.as_array(self)) {
+            Some(Ok(kids)) if !is_page => kids
+                .iter()
+                .map(|kid| self.count_page_leaves(kid, visited))
+                .sum(),
+            _ => 1
+        }
+    }
+
+    /// Flattens a name tree (7.9.6) into its key/value pairs, in tree order
+    pub fn name_tree<'a>(&'a self, node: &'a PDFValue) -> Vec<(String, &'a PDFValue)> {
+        let mut entries = vec![];
+        let mut visited: HashSet<PDFObjectHeader> = HashSet::new();
+        self.walk_name_tree(node, &mut entries, &mut visited);
+        entries
+    }
+
+    fn walk_name_tree<'a>(&'a self, node: &'a PDFValue, entries: &mut Vec<(String, &'a PDFValue)>, visited: &mut HashSet<PDFObjectHeader>) {
+        if let PDFValue::ObjectReference(reference) = node {
+            if !visited.insert(*reference) {
+                return;
+            }
+        }
+
+        let node = match node.as_dictionary(self) {
+            Ok(node) => node,
+            Err(_) => return
+        };
+
+        if let Some(Ok(names)) = node.get("Names").map(|names| names.as_array(self)) {
+            for pair in names.chunks_exact(2) {
+                let key = match pair[0].as_text(self) {
+                    Ok(key) => key,
+                    Err(_) => continue
+                };
+                entries.push((key, &pair[1]));
+            }
+        }
+
+        if let Some(Ok(kids)) = node.get("Kids").map(|kids| kids.as_array(self)) {
+            for kid in kids {
+                self.walk_name_tree(kid, entries, visited);
+            }
+        }
+    }
+
+    /// Checks the structural requirements the rest of the crate assumes: the trailer's `/Root`
+    /// and `/Size`, the catalog's `/Type` and `/Pages`, every page's (possibly inherited)
+    /// `/MediaBox` and its `/Contents`, and that every indirect reference resolves
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+
+        let trailer = self.trailer_dictionary();
+        for key in ["Root", "Size"] {
+            if trailer.and_then(|trailer| trailer.get(key)).is_none() {
+                issues.push(ValidationIssue::MissingTrailerEntry(key));
+            }
+        }
+
+        let catalog = trailer
+            .and_then(|trailer| trailer.get("Root"))
+            .and_then(|root| root.as_dictionary(self).ok())
+            .or_else(|| self.catalog());
+        match catalog {
+            Some(catalog) => {
+                if catalog.get("Type").and_then(|catalog_type| catalog_type.as_name(self).ok()).map(String::as_str) != Some("Catalog") {
+                    issues.push(ValidationIssue::CatalogNotTypeCatalog);
+                }
+                match catalog.get("Pages") {
+                    Some(pages) => self.validate_page_tree(pages, false, &mut issues, &mut HashSet::new()),
+                    None => issues.push(ValidationIssue::CatalogMissingPages)
+                }
+            },
+            None => issues.push(ValidationIssue::MissingCatalog)
+        }
+
+        for object in self.sorted_objects() {
+            let mut references = vec![];
+            collect_references(&object.value, &mut references);
+            references.sort_by_key(|reference| (reference.object_number, reference.generation_number));
+            for reference in references {
+                if !self.objects.contains_key(&reference) {
+                    issues.push(ValidationIssue::UnresolvedReference { from: object.header, to: reference });
+                }
+            }
+        }
+
+        issues
+    }
+
+    fn validate_page_tree(&self, node: &PDFValue, inherited_media_box: bool, issues: &mut Vec<ValidationIssue>, visited: &mut HashSet<PDFObjectHeader>) {
+        let reference = match node {
+            PDFValue::ObjectReference(reference) => {
+                if !visited.insert(*reference) {
+                    return;
+                }
+                Some(*reference)
+            },
+            _ => None
+        };
+
+        let node = match node.as_dictionary(self) {
+            Ok(node) => node,
+            Err(_) => return
+        };
+        let has_media_box = inherited_media_box || node.contains_key("MediaBox");
+
+        let is_page = node.get("Type").and_then(|node_type| node_type.as_name(self).ok()).is_some_and(|node_type| node_type == "Page");
+        match node.get("Kids").map(|kids| kids.as_array(self)) {
+            Some(Ok(kids)) if !is_page => {
+                for kid in kids {
+                    self.validate_page_tree(kid, has_media_box, issues, visited);
+                }
+            },
+            _ => {
+                if !has_media_box {
+                    issues.push(ValidationIssue::PageMissingMediaBox(reference));
+                }
+                if !node.contains_key("Contents") {
+                    issues.push(ValidationIssue::PageMissingContents(reference));
+                }
+            }
+        }
+    }
+
+    /// The document outline from the catalog's `/Outlines`, as a tree of top level items
+    pub fn outlines(&self) -> Vec<OutlineItem> {
+        let first = match self.catalog()
+            .and_then(|catalog| catalog.get("Outlines"))
+            .and_then(|outlines| outlines.as_dictionary(self).ok())
+            .and_then(|outlines| outlines.get("First")) {
+            Some(first) => first,
+            None => return vec![]
+        };
+
+        self.outline_siblings(first, &mut HashSet::new())
+    }
+
+    fn outline_siblings(&self, first: &PDFValue, visited: &mut HashSet<PDFObjectHeader>) -> Vec<OutlineItem> {
+        let mut items = vec![];
+        let mut next = Some(first);
+
+        while let Some(node) = next {
+            if let PDFValue::ObjectReference(reference) = node {
+                if !visited.insert(*reference) {
+                    break;
+                }
+            }
+
+            let item = match node.as_dictionary(self) {
+                Ok(item) => item,
+                Err(_) => break
+            };
+
+            let count = item.get("Count").and_then(|count| count.as_number(self).ok()).unwrap_or(0.0) as i64;
+            let children = match item.get("First") {
+                Some(first) => self.outline_siblings(first, visited),
+                None => vec![]
+            };
+
+            items.push(OutlineItem {
+                title: item.get("Title").and_then(|title| title.as_text(self).ok()).unwrap_or_default(),
+                open: count > 0,
+                descendant_count: count.abs(),
+                children
+            });
+
+            next = item.get("Next");
+        }
+
+        items
+    }
+
+    /// Every placement of an image on a page, including images inside form XObjects.
+    /// Pages whose content stream cannot be read are skipped.
+    pub fn images(&self) -> Vec<ImageOccurrence> {
+        let mut images = vec![];
+        for (page_index, page) in self.pages.iter().enumerate() {
+            match page.image_placements(self) {
+                Ok(placements) => images.extend(placements.into_iter().map(|(image, matrix)| ImageOccurrence {
+                    image,
+                    page: page_index,
+                    matrix
+                })),
+                Err(err) => warn!("Skipping images on page {page_index}: {err}")
+            }
+        }
+        images
+    }
+
+    /// Returns the files attached through the catalog's `/Names /EmbeddedFiles` tree
+    pub fn embedded_files(&self) -> Vec<EmbeddedFile> {
+        let tree = match self.catalog()
+            .and_then(|catalog| catalog.get("Names"))
+            .and_then(|names| names.as_dictionary(self).ok())
+            .and_then(|names| names.get("EmbeddedFiles")) {
+            Some(tree) => tree,
+            None => return vec![]
+        };
+
+        let mut files = vec![];
+        for (key, file_spec) in self.name_tree(tree) {
+            let file_spec = match file_spec.as_dictionary(self) {
+                Ok(file_spec) => file_spec,
+                Err(_) => continue
+            };
+
+            let stream = match file_spec
+                .get("EF")
+                .and_then(|ef| ef.as_dictionary(self).ok())
+                .and_then(|ef| ef.get("UF").or_else(|| ef.get("F")))
+                .and_then(|file| file.as_stream(self).ok()) {
+                Some(stream) => stream,
+                None => continue
+            };
+
+            let name = match file_spec.get("UF").or_else(|| file_spec.get("F")).map(|name| name.as_text(self)) {
+                Some(Ok(name)) => name,
+                _ => key
+            };
+
+            let bytes = match stream.decompress() {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    warn!("Skipping embedded file {name}: {err}");
+                    continue;
+                }
+            };
+            files.push(EmbeddedFile { name, bytes });
+        }
+
+        files
+    }
+
+    /// Optional content groups that are off in the default viewing configuration
+    /// (`/OCProperties /D`), either listed in `/OFF` or hidden by an `/OFF` base state
+    pub fn hidden_optional_content(&self) -> HashSet<PDFObjectHeader> {
+        let mut hidden: HashSet<PDFObjectHeader> = HashSet::new();
+
+        let properties = match self.catalog()
+            .and_then(|catalog| catalog.get("OCProperties"))
+            .and_then(|properties| properties.as_dictionary(self).ok()) {
+            Some(properties) => properties,
+            None => return hidden
+        };
+        let config = match properties.get("D").and_then(|config| config.as_dictionary(self).ok()) {
+            Some(config) => config,
+            None => return hidden
+        };
+
+        let references = |value: Option<&PDFValue>| -> Vec<PDFObjectHeader> {
+            match value.map(|value| value.as_array(self)) {
+                Some(Ok(array)) => array
+                    .iter()
+                    .filter_map(|value| match value {
+                        PDFValue::ObjectReference(reference) => Some(*reference),
+                        _ => None
+                    })
+                    .collect(),
+                _ => vec![]
+            }
+        };
+
+        if config.get("BaseState").and_then(|state| state.as_name(self).ok()).map(String::as_str) == Some("OFF") {
+            hidden.extend(references(properties.get("OCGs")));
+            for visible in references(config.get("ON")) {
+                hidden.remove(&visible);
+            }
+        } else {
+            hidden.extend(references(config.get("OFF")));
+        }
+
+        hidden
+    }
+
+    /// Text of the pages whose zero-based index falls in `range`, separated by form feeds.
+    /// Bounds past the last page are clamped, so only the pages that exist are returned.
+    pub fn extract_text_range(&self, range: impl RangeBounds<usize>) -> Result<String, PdfError> {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.saturating_add(1),
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => self.pages.len()
+        };
+        let end = end.min(self.pages.len());
+        let start = start.min(end);
+
+        let options = TextExtractionOptions::default();
+        let mut page_texts: Vec<String> = Vec::with_capacity(end - start);
+        for page in &self.pages[start..end] {
+            page_texts.push(page.text(self, &options)?);
+        }
+
+        Ok(page_texts.join(&PAGE_SEPARATOR.to_string()))
+    }
+
+    /// The stream's bytes exactly as stored in the file, still encoded
+    pub fn raw_stream(&self, header: &PDFObjectHeader) -> Option<&[u8]> {
+        match &self.get_object(header)?.value {
+            PDFValue::Stream(stream) => Some(&stream.bytes),
+            _ => None
+        }
+    }
+
+    /// The stream's data with its filters undone. A filter this crate cannot decode is
+    /// `Unsupported`; a missing object, a non-stream or corrupt data is `Malformed`.
+    pub fn decoded_stream(&self, header: &PDFObjectHeader) -> Result<Vec<u8>, PdfError> {
+        let object = self.get_object(header)
+            .ok_or_else(|| PdfError::Malformed(format!("Object {} {} not found", header.object_number, header.generation_number)))?;
+        let stream = object.value.stream().map_err(PdfError::Malformed)?;
+        if let Some(filter) = stream.filters().into_iter().find(|filter| !is_supported_filter(filter)) {
+            return Err(PdfError::Unsupported(format!("Unsupported stream filter /{filter}")));
+        }
+        stream.decompress().map_err(PdfError::Malformed)
+    }
+
+    pub fn sorted_objects(&self) -> Vec<&PDFObject> {
+        let mut objects: Vec<&PDFObject> = self.objects.values().collect();
+        objects.sort_by_key(|object| (object.header.object_number, object.header.generation_number));
+        objects
+    }
+
+    /// Lists the `/Filter` names of every stream object, in object number order.
+    /// Streams stored without compression are reported with an empty filter list.
+    pub fn stream_filters(&self) -> Vec<(PDFObjectHeader, Vec<String>)> {
+        self.sorted_objects()
+            .into_iter()
+            .filter_map(|object| match &object.value {
+                PDFValue::Stream(stream) => Some((object.header, stream.filters())),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+fn collect_references(value: &PDFValue, references: &mut Vec<PDFObjectHeader>) {
+    match value {
+        PDFValue::ObjectReference(reference) => references.push(*reference),
+        PDFValue::Array(values) => {
+            for value in values {
+                collect_references(value, references);
+            }
+        },
+        PDFValue::Dictionary(dictionary) | PDFValue::Stream(PDFStream { dictionary, .. }) => {
+            for value in dictionary.values() {
+                collect_references(value, references);
+            }
+        },
+        _ => {}
+    }
+}
+
+fn page_content(page: &PDFPage) -> Option<Vec<u8>> {
+    match &page.contents.value {
+        PDFValue::Stream(stream) => stream.decompress().ok(),
+        _ => None
+    }
+}
+
+/// Compares two documents object by object, in object number order. Dictionaries compare
+/// regardless of key order; page content streams compare by their decoded bytes so that
+/// recompressing a stream does not count as a page change.
+pub fn diff(a: &PDF, b: &PDF) -> Vec<Difference> {
+    let mut differences: Vec<Difference> = vec![];
+
+    let a_objects = a.sorted_objects();
+    let b_objects = b.sorted_objects();
+    let sort_key = |object: &PDFObject| (object.header.object_number, object.header.generation_number);
+
+    let (mut a_index, mut b_index) = (0, 0);
+    while a_index < a_objects.len() || b_index < b_objects.len() {
+        match (a_objects.get(a_index), b_objects.get(b_index)) {
+            (Some(a_object), Some(b_object)) if sort_key(a_object) == sort_key(b_object) => {
+                if a_object.value != b_object.value {
+                    differences.push(Difference::ObjectChanged(a_object.header));
+                }
+                a_index += 1;
+                b_index += 1;
+            },
+            (Some(a_object), Some(b_object)) if sort_key(a_object) < sort_key(b_object) => {
+                differences.push(Difference::ObjectRemoved(a_object.header));
+                a_index += 1;
+            },
+            (Some(a_object), None) => {
+                differences.push(Difference::ObjectRemoved(a_object.header));
+                a_index += 1;
+            },
+            (_, Some(b_object)) => {
+                differences.push(Difference::ObjectAdded(b_object.header));
+                b_index += 1;
+            },
+            (None, None) => break
+        }
+    }
+
+    if a.pages.len() != b.pages.len() {
+        differences.push(Difference::PageCountChanged { before: a.pages.len(), after: b.pages.len() });
+    }
+
+    for (index, (a_page, b_page)) in a.pages.iter().zip(&b.pages).enumerate() {
+        if page_content(a_page) != page_content(b_page) {
+            differences.push(Difference::PageContentChanged(index));
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::test_pdf::TestPdf;
+
+    fn header(object_number: u64) -> PDFObjectHeader {
+        PDFObjectHeader { object_number, generation_number: 0 }
+    }
+
+    #[test]
+    fn numeric_arrays_read_as_f64() {
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.object(5, "<< /FontMatrix [0.001 0 0 0.001 0 0] /Mixed [1 /Two 3] >>");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let dictionary = document.pdf().get_object(&header(5)).unwrap().value.dictionary().unwrap();
+        assert_eq!(dictionary["FontMatrix"].as_f64_array(), Some(vec![0.001, 0.0, 0.0, 0.001, 0.0, 0.0]));
+        assert_eq!(dictionary["Mixed"].as_f64_array(), None);
+    }
+
+    #[test]
+    fn accessors_report_a_type_mismatch_instead_of_panicking() {
+        let reference = PDFValue::ObjectReference(header(3));
+        let array = PDFValue::Array(vec![PDFValue::Number(1.0)]);
+
+        assert_eq!(reference.object_reference(), Ok(&header(3)));
+        assert!(reference.array().is_err());
+        assert_eq!(array.array().map(Vec::len), Ok(1));
+        assert!(array.object_reference().is_err());
+    }
+
+    #[test]
+    fn a_referenced_stream_counts_as_a_dictionary() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        // The page tree root is (wrongly) a stream, which viewers read by its dictionary
+        pdf.stream(2, "/Type /Pages /Kids [3 0 R] /Count 1", b"");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Text) Tj ET");
+        pdf.finish_section(&[0], "<< /Size 5 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let pages = document.pdf().catalog().unwrap().get("Pages").unwrap();
+        assert_eq!(pages.as_dictionary(document.pdf()).unwrap().get("Count"), Some(&PDFValue::Number(1.0)));
+        assert_eq!(document.extract_text().unwrap(), "Text\n");
+    }
+
+    #[test]
+    fn page_count_fast_matches_the_page_tree() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R 5 0 R] /Count 3 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Page) Tj ET");
+        pdf.object(5, "<< /Type /Pages /Parent 2 0 R /Kids [6 0 R 7 0 R] /Count 2 >>");
+        pdf.object(6, "<< /Type /Page /Parent 5 0 R /Contents 4 0 R >>");
+        pdf.object(7, "<< /Type /Page /Parent 5 0 R /Contents 4 0 R >>");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let pdf = document.pdf();
+        assert_eq!(pdf.page_count_fast(), Some(3));
+        assert_eq!(pdf.count_page_leaves(pdf.catalog().unwrap().get("Pages").unwrap(), &mut HashSet::new()), 3);
+        assert_eq!(document.page_count(), 3);
+    }
+
+    #[test]
+    fn raw_and_decoded_stream_bytes() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"BT (Compressed) Tj ET").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.stream(5, "/Filter /FlateDecode", &compressed);
+        pdf.stream(6, "/Filter /JBIG2Decode", b"image");
+        pdf.stream(7, "/Filter /FlateDecode", b"not zlib");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.pdf().raw_stream(&header(5)), Some(compressed.as_slice()));
+        assert_eq!(document.pdf().decoded_stream(&header(5)).unwrap(), b"BT (Compressed) Tj ET");
+        assert!(matches!(document.pdf().decoded_stream(&header(9)), Err(PdfError::Malformed(_))));
+        assert!(matches!(document.pdf().decoded_stream(&header(6)), Err(PdfError::Unsupported(_))));
+        assert!(matches!(document.pdf().decoded_stream(&header(7)), Err(PdfError::Malformed(_))));
+    }
+
+    #[test]
+    fn outline_items_keep_their_open_state() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R /Outlines 5 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Text) Tj ET");
+        pdf.object(5, "<< /Type /Outlines /First 6 0 R /Last 7 0 R /Count 3 >>");
+        pdf.object(6, "<< /Title (Open) /Parent 5 0 R /Next 7 0 R /First 8 0 R /Last 8 0 R /Count 1 >>");
+        pdf.object(7, "<< /Title (Closed) /Parent 5 0 R /Prev 6 0 R /First 9 0 R /Last 9 0 R /Count -1 >>");
+        pdf.object(8, "<< /Title (Shown child) /Parent 6 0 R >>");
+        pdf.object(9, "<< /Title (Hidden child) /Parent 7 0 R >>");
+        pdf.finish_section(&[0], "<< /Size 10 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let summary: Vec<(String, bool, i64, Vec<String>)> = document.pdf().outlines()
+            .into_iter()
+            .map(|item| (item.title, item.open, item.descendant_count, item.children.into_iter().map(|child| child.title).collect()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("Open".to_string(), true, 1, vec!["Shown child".to_string()]),
+            ("Closed".to_string(), false, 1, vec!["Hidden child".to_string()])
+        ]);
+    }
+
+    #[test]
+    fn images_are_reported_with_their_placement() {
+        let mut pdf = TestPdf::page("/XObject << /Im0 5 0 R >>", b"q 200 0 0 100 50 60 cm /Im0 Do Q");
+        pdf.stream(5, "/Type /XObject /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceGray /BitsPerComponent 8", &[0xFF]);
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.pdf().images(), vec![ImageOccurrence {
+            image: header(5),
+            page: 0,
+            matrix: vec![200.0, 0.0, 0.0, 100.0, 50.0, 60.0]
+        }]);
+    }
+
+    #[test]
+    fn text_range_is_clamped_and_reports_unlexable_pages() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R 5 0 R] /Count 2 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (One) Tj ET");
+        pdf.object(5, "<< /Type /Page /Parent 2 0 R /Contents 6 0 R >>");
+        pdf.stream(6, "", b"BT (unterminated Tj ET");
+        pdf.finish_section(&[0], "<< /Size 7 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.pdf().extract_text_range(0..1).unwrap(), "One\n");
+        assert_eq!(document.pdf().extract_text_range(5..).unwrap(), "");
+        assert!(matches!(document.pdf().extract_text_range(..), Err(PdfError::ContentStream(_))));
+
+        let second_page = document.pages().nth(1).unwrap();
+        assert!(matches!(second_page.text_with(&TextExtractionOptions::default()), Err(PdfError::ContentStream(_))));
+    }
+
+    #[test]
+    fn stream_filters_lists_each_stream_with_its_filters() {
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.stream(5, "/Filter /FlateDecode", b"not decoded");
+        pdf.stream(6, "/Filter [/ASCIIHexDecode /FlateDecode]", b"not decoded");
+        pdf.finish_section(&[0], "<< /Size 7 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.pdf().stream_filters(), vec![
+            (header(4), vec![]),
+            (header(5), vec!["FlateDecode".to_string()]),
+            (header(6), vec!["ASCIIHexDecode".to_string(), "FlateDecode".to_string()])
+        ]);
+    }
+
+    #[test]
+    fn size_discrepancies_count_the_objects_actually_parsed() {
+        // Objects 5 and 6 are lost, though 7 makes the highest number agree with /Size
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.object(7, "<< /Last true >>");
+        pdf.object(9, "<< /Extra true >>");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.pdf().size_discrepancies(), vec![
+            SizeDiscrepancy::ObjectBeyondSize(header(9)),
+            SizeDiscrepancy::MissingObjects { declared: 8, parsed: 6 }
+        ]);
+    }
 }
@@ -1,39 +1,185 @@
-use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::io::{Cursor, Read};
-use std::thread::panicking;
 
-use log::debug;
-
-use flate2::Decompress;
+use log::{debug, warn};
 
+use crate::encryption::SecurityHandler;
+use crate::error::PdfError;
 use crate::page::PDFPage;
 use crate::pdf::{PDFDictionary, PDFStream};
-use crate::tokenizer::{PDFTokenize, PDFToken, PDFObjectHeader, XRefSection, XRefEntry, XRefStreamFreeObject, XRefStreamUncompressedObject, XRefStreamCompressedObject};
+use crate::tokenizer::{Tokenizer, PDFTokenize, PDFToken, PDFObjectHeader, XRefSection, XRefEntry, XRefStreamFreeObject, XRefStreamUncompressedObject, XRefStreamCompressedObject};
 
 use super::tokenizer::{PDFTokenPatterns};
 use super::pdf::{PDF, PDFObject, PDFValue};
 
 pub struct Reader<T: PDFTokenize> {
     pdf: PDF,
-    tokenizer: T
+    tokenizer: T,
+    security_handler: Option<SecurityHandler>,
+    /// The `/Encrypt` dictionary's object, which is stored unencrypted
+    encrypt_reference: Option<PDFObjectHeader>,
+    /// Which object starts at each byte offset, for objects not packed in an object stream
+    objects_by_offset: HashMap<u64, PDFObjectHeader>,
+    /// Where the xref puts each object number, for loading objects on demand
+    locations: HashMap<u64, ObjectLocation>,
+    /// Object numbers that an incremental update marked free after an older section had
+    /// them in use
+    deleted_objects: HashSet<u64>,
+    /// The unpacked contents of each object stream loaded on demand, by the stream's object
+    /// number, so a stream is decoded once however many of its objects are asked for
+    object_streams: HashMap<u64, Vec<PackedObject>>
 }
 
 trait ReadU64 {
-    fn read_u64(&mut self, num_bytes: u8) -> u64;
+    fn read_u64(&mut self, num_bytes: u8) -> Result<u64, String>;
 }
 
 impl ReadU64 for Cursor<Vec<u8>> {
-    fn read_u64(&mut self, num_bytes: u8) -> u64 {
-        assert!(num_bytes <= 8, "Width exceeds size of u64");
+    fn read_u64(&mut self, num_bytes: u8) -> Result<u64, String> {
+        if num_bytes > 8 {
+            return Err(format!("Field width {num_bytes} exceeds size of u64"));
+        }
         let mut buf: [u8; 8] = [0; 8];
         let mut source_bytes_buf: Vec<u8> = vec![0; num_bytes as usize];
-        self.read_exact(&mut source_bytes_buf).unwrap();
+        self.read_exact(&mut source_bytes_buf).map_err(|_| "Xref stream data ends in the middle of an entry".to_string())?;
         for i in 0..num_bytes {
             buf[7 - i as usize] = source_bytes_buf[(num_bytes - i - 1) as usize];
         }
 
-        u64::from_be_bytes(buf)
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+/// Where the xref says an object is stored
+#[derive(Debug, Clone, Copy)]
+enum ObjectLocation {
+    Offset(u64),
+    /// The `index`th object packed in object stream `stream_object_number`
+    InObjectStream { stream_object_number: u64, index: u64 }
+}
+
+/// Object number to location while walking the xref, with `None` for an entry marked
+/// free so that it hides any older entry for the same number
+type XRefLocations = HashMap<u64, Option<ObjectLocation>>;
+
+/// The locations of the objects in use, once the walk is over
+fn in_use_locations(locations: XRefLocations) -> HashMap<u64, ObjectLocation> {
+    locations
+        .into_iter()
+        .filter_map(|(object_number, location)| Some((object_number, location?)))
+        .collect()
+}
+
+/// The byte offset a trailer gives in `/Prev` or `/XRefStm`, which has to be a
+/// non-negative integer
+fn trailer_offset(trailer: &PDFDictionary, key: &str) -> Result<Option<u64>, String> {
+    match trailer.get(key) {
+        None => Ok(None),
+        Some(PDFValue::Number(offset)) if *offset >= 0.0 && offset.fract() == 0.0 => Ok(Some(*offset as u64)),
+        Some(other) => Err(format!("Trailer /{key} is not a byte offset: {:?}", other))
+    }
+}
+
+/// An object number and the value parsed for it from an object stream
+type PackedObject = (u64, Result<PDFValue, String>);
+
+/// Page attributes a `/Page` takes from its nearest `/Pages` ancestor when it does not
+/// set them itself (7.7.3.4)
+const INHERITABLE_PAGE_ATTRIBUTES: [&str; 4] = ["Resources", "MediaBox", "CropBox", "Rotate"];
+
+/// Whether a page tree kid is an intermediate `/Pages` node rather than a page
+fn is_page_tree_node(node: &PDFDictionary, pdf: &PDF) -> bool {
+    match node.get("Type").and_then(|node_type| node_type.as_name(pdf).ok()) {
+        Some(node_type) => node_type == "Pages",
+        // Some writers leave out /Type, but only intermediate nodes have kids
+        None => node.contains_key("Kids")
+    }
+}
+
+/// The inheritable attributes in effect for the kids of `node`
+fn inherited_page_attributes(node: &PDFDictionary, inherited: &PDFDictionary) -> PDFDictionary {
+    let mut attributes = inherited.clone();
+    for key in INHERITABLE_PAGE_ATTRIBUTES {
+        if let Some(value) = node.get(key) {
+            attributes.insert(key.to_string(), value.clone());
+        }
+    }
+    attributes
+}
+
+/// Fills in the attributes a page inherits, so its dictionary is the effective one
+fn inherit_page_attributes(page: &mut PDFObject, inherited: &PDFDictionary) {
+    if let PDFValue::Dictionary(dictionary) = &mut page.value {
+        for (key, value) in inherited {
+            dictionary.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// A page's content as a single stream. The parts of a `/Contents` array (7.8.2) are
+/// decoded and joined with a newline, since a part may end without whitespace; the joined
+/// stream carries the page's own header.
+fn join_content_streams(page: &PDFObject, parts: &[&PDFObject]) -> Result<PDFObject, String> {
+    if let [part] = parts {
+        return Ok((*part).clone());
+    }
+
+    let mut bytes: Vec<u8> = vec![];
+    for part in parts.iter() {
+        if !bytes.is_empty() {
+            bytes.push(b'\n');
+        }
+        bytes.extend(part.value.stream()?.decompress()?);
     }
+
+    let mut dictionary = PDFDictionary::new();
+    dictionary.insert("Length".to_string(), PDFValue::Number(bytes.len() as f64));
+
+    Ok(PDFObject {
+        header: page.header,
+        value: PDFValue::Stream(PDFStream { dictionary, bytes }),
+        offset: page.offset
+    })
+}
+
+/// The objects packed in an object stream (`/Type /ObjStm`, 7.5.7), in the order of the
+/// stream's header
+fn unpack_object_stream(stream: &PDFStream) -> Result<Vec<PackedObject>, String> {
+    let first = match stream.dictionary.get("First") {
+        Some(PDFValue::Number(first)) => *first as usize,
+        _ => return Err("Object stream has no /First".to_string())
+    };
+    let bytes = stream.decompress()?;
+    if first > bytes.len() {
+        return Err("Object stream has /First past its end".to_string());
+    }
+
+    // The header is pairs of object number and offset relative to /First
+    let numbers: Vec<usize> = String::from_utf8_lossy(&bytes[..first])
+        .split_ascii_whitespace()
+        .filter_map(|number| number.parse::<usize>().ok())
+        .collect();
+    let entries: Vec<(usize, usize)> = numbers.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect();
+
+    Ok(entries.iter().enumerate().map(|(index, (object_number, offset))| {
+        let start = (first + offset).min(bytes.len());
+        let end = entries
+            .get(index + 1)
+            .map_or(bytes.len(), |(_, next_offset)| (first + next_offset).clamp(start, bytes.len()));
+
+        // Wrap the bare value in an object header and endobj so the normal object
+        // parser can read it
+        let mut object_bytes = format!("{} 0 obj\n", object_number).into_bytes();
+        object_bytes.extend_from_slice(&bytes[start..end]);
+        object_bytes.extend_from_slice(b"\nendobj\n");
+
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(object_bytes)));
+        let value = match reader.tokenizer.next() {
+            Ok(PDFToken::ObjectHeader(parsed_header)) => reader.parse_object(0, &parsed_header).map(|object| object.value).map_err(|err| err.to_string()),
+            other => Err(format!("Unexpected token {:?}", other))
+        };
+        (*object_number as u64, value)
+    }).collect())
 }
 
 impl<T: PDFTokenize> Reader<T> {
@@ -41,18 +187,60 @@ impl<T: PDFTokenize> Reader<T> {
         Self {
             tokenizer,
             pdf: Default::default(),
+            security_handler: None,
+            encrypt_reference: None,
+            objects_by_offset: HashMap::new(),
+            locations: HashMap::new(),
+            deleted_objects: HashSet::new(),
+            object_streams: HashMap::new()
         }
     }
 
-    pub fn read(&mut self) {
-        self.parse();
-        self.build_tree();
+    /// Parses the whole document and its page tree. Fails on encrypted documents that
+    /// cannot be opened with the empty user password.
+    pub fn read(&mut self) -> Result<(), PdfError> {
+        self.parse()?;
+        self.build_tree()
     }
 
-    fn parse_xref_stream(&mut self, widths: Vec<u64>, bytes: Vec<u8>) -> Vec<XRefEntry> {
-        assert!(widths.first() == Some(&1), "First width was not zero!");
-        let second_field_width = *widths.get(1).unwrap_or_else( || panic!("Not enough values in widths array: {:?}", widths));
-        let third_field_width = *widths.get(2).unwrap_or_else( || panic!("Not enough values in widths array: {:?}", widths));
+    /// The document read so far
+    pub fn into_pdf(self) -> PDF {
+        self.pdf
+    }
+
+    /// Like `read`, but for files whose xref is beyond repair: objects are found by scanning
+    /// for their headers, and the catalog is the object with `/Type /Catalog`
+    pub fn read_with_rebuild(&mut self) -> Result<(), PdfError> {
+        self.rebuild_objects();
+        self.build_tree()
+    }
+
+    /// Fills the object map from every object header and body found in the raw bytes,
+    /// without reading any xref section or trailer. Later definitions of an object win.
+    fn rebuild_objects(&mut self) {
+        for (header, offset) in self.tokenizer.find_object_headers() {
+            let object = match self.tokenizer.seek_to(offset).and_then(|_| self.tokenizer.next()) {
+                Ok(PDFToken::ObjectHeader(parsed_header)) => self.parse_object(offset, &parsed_header).map_err(|err| err.to_string()),
+                other => Err(format!("Expected object header, found {:?}", other))
+            };
+
+            match object {
+                Ok(object) => self.insert_object(object.header, object, ObjectLocation::Offset(offset)),
+                Err(err) => {
+                    warn!("Skipping object {} {} at offset {offset}: {err}", header.object_number, header.generation_number);
+                    self.pdf.parse_errors.push((header, err));
+                }
+            }
+        }
+
+        self.load_object_streams();
+    }
+
+    fn parse_xref_stream(&self, widths: Vec<u64>, bytes: Vec<u8>) -> Result<Vec<XRefEntry>, String> {
+        let (second_field_width, third_field_width) = match widths[..] {
+            [1, second, third] => (second, third),
+            _ => return Err(format!("Unsupported xref stream field widths {:?}", widths))
+        };
 
         let mut entries: Vec<XRefEntry> = vec![];
 
@@ -63,247 +251,892 @@ impl<T: PDFTokenize> Reader<T> {
             let entry_type = next_byte[0];
             match entry_type {
                 0 => {
-                    let object_number_of_next_free_object = cursor.read_u64(second_field_width as u8);
-                    let generation_number_for_next_object_use = cursor.read_u64(third_field_width as u8);
+                    let object_number_of_next_free_object = cursor.read_u64(second_field_width as u8)?;
+                    let generation_number_for_next_object_use = cursor.read_u64(third_field_width as u8)?;
                     entries.push(XRefEntry::Free(XRefStreamFreeObject {
                         object_number_of_next_free_object,
                         generation_number_for_next_object_use
                     }));
                 },
                 1 => {
-                    let byte_offset = cursor.read_u64(second_field_width as u8);
-                    let generation_number = cursor.read_u64(third_field_width as u8);
+                    let byte_offset = cursor.read_u64(second_field_width as u8)?;
+                    let generation_number = cursor.read_u64(third_field_width as u8)?;
                     entries.push(XRefEntry::Uncompressed(XRefStreamUncompressedObject {
                         byte_offset,
                         generation_number
                     }));
                 },
                 2 => {
-                    let object_number_of_parent_stream = cursor.read_u64(second_field_width as u8);
-                    let index_in_stream = cursor.read_u64(third_field_width as u8);
+                    let object_number_of_parent_stream = cursor.read_u64(second_field_width as u8)?;
+                    let index_in_stream = cursor.read_u64(third_field_width as u8)?;
                     entries.push(XRefEntry::Compressed(XRefStreamCompressedObject {
                         object_number_of_parent_stream,
                         index_in_stream
                     }));
                 },
                 _ => {
-                    panic!("Unsupported xref entry type {entry_type}");
+                    return Err(format!("Unsupported xref entry type {entry_type}"));
                 }
             }
         }
 
-        entries
+        Ok(entries)
     }
 
-    fn get_object_at_offset(&mut self, offset: u64) -> Option<PDFObject> {
-        for object in self.pdf.objects.values() {
-            if object.offset == offset {
-                return Some(object.clone());
-            }
+    /// Adds `object` to the document under `header`, replacing any earlier object there.
+    /// Objects stored at an offset of their own are indexed by it as well.
+    fn insert_object(&mut self, header: PDFObjectHeader, object: PDFObject, location: ObjectLocation) {
+        if let ObjectLocation::Offset(offset) = location {
+            self.objects_by_offset.insert(offset, header);
         }
-        None
+        self.pdf.objects.insert(header, object);
+    }
+
+    fn get_object_at_offset(&self, offset: u64) -> Option<&PDFObject> {
+        self.objects_by_offset
+            .get(&offset)
+            .and_then(|header| self.pdf.objects.get(header))
+            // The object may have since been replaced by a newer version elsewhere
+            .filter(|object| object.offset == offset)
     }
 
-    fn get_object_by_reference(&mut self, reference: &PDFObjectHeader) -> Option<PDFObject> {
-        self.pdf.objects.get(reference).cloned()
+    /// The object already loaded for `reference`, borrowed rather than copied
+    fn get_object_ref(&self, reference: &PDFObjectHeader) -> Option<&PDFObject> {
+        self.pdf.objects.get(reference)
     }
 
+    /// A page's `/Contents` (7.8.2) as a single stream, from already loaded objects. It may
+    /// be a stream or an array of streams, directly or through a reference; a page without
+    /// one is blank and gets an empty stream.
+    fn page_contents(&self, page: &PDFObject, contents: Option<&PDFValue>) -> Result<PDFObject, String> {
+        let resolve = |reference: &PDFObjectHeader| self
+            .get_object_ref(reference)
+            .ok_or(format!("Content stream {} {} not found", reference.object_number, reference.generation_number));
+
+        let (contents, contents_object) = match contents {
+            None => return join_content_streams(page, &[]),
+            Some(PDFValue::ObjectReference(reference)) => {
+                let object = resolve(reference)?;
+                (&object.value, Some(object))
+            },
+            Some(value) => (value, None)
+        };
+
+        match (contents, contents_object) {
+            (PDFValue::Stream(_), Some(object)) => Ok(object.clone()),
+            (PDFValue::Stream(_), None) => Err("Page /Contents is a direct stream, which must be indirect".to_string()),
+            (PDFValue::Array(parts), _) => {
+                let parts = parts
+                    .iter()
+                    .map(|part| match part {
+                        PDFValue::ObjectReference(part_reference) => resolve(part_reference),
+                        other => Err(format!("Contents array entry is a {}, not an indirect reference", other.type_name()))
+                    })
+                    .collect::<Result<Vec<&PDFObject>, String>>()?;
+                join_content_streams(page, &parts)
+            },
+            (other, _) => Err(format!("Page /Contents is a {}, not a stream or an array", other.type_name()))
+        }
+    }
+
+    /// An owned copy of a loaded object, including any stream data. Prefer
+    /// `get_object_ref` unless the copy is kept.
+    fn get_object_by_reference(&self, reference: &PDFObjectHeader) -> Option<PDFObject> {
+        self.get_object_ref(reference).cloned()
+    }
+
+    /// Reads the cross-reference stream at `startxref`, if that is what is there, and
+    /// returns its dictionary
+    fn read_xref_stream(&mut self) -> Result<Option<PDFDictionary>, String> {
+        let startxref = match self.pdf.startxref {
+            Some(startxref) => startxref,
+            None => return Ok(None)
+        };
+        debug!("StartXRef: {:?}", startxref);
+
+        let stream = match self.get_object_at_offset(startxref).map(|object| &object.value) {
+            Some(PDFValue::Stream(stream)) => stream,
+            _ => return Ok(None)
+        };
+
+        let width_vector: Vec<u64> = stream.dictionary
+            .get("W")
+            .ok_or("No 'W' entry in xref stream dictionary")?
+            .as_f64_array()
+            .ok_or("XRef stream 'W' entry is not an array of numbers")?
+            .iter()
+            .map(|width| *width as u64)
+            .collect();
+
+        let entries = self.parse_xref_stream(width_vector, stream.decompress()?)?;
+        let dictionary = stream.dictionary.clone();
+
+        self.pdf.xref_table = Some(XRefSection {
+            header: None,
+            entries
+        });
+
+        Ok(Some(dictionary))
+    }
+
+    /// The catalog, from the xref stream dictionary's `/Root` or else the trailer's. It may
+    /// live in an object stream, so those are unpacked if it hasn't been seen yet.
     fn get_root_object(&mut self) -> Result<PDFObject, String> {
-        if let Some(trailer) = &self.pdf.trailer {
-            if let Some(PDFValue::Dictionary(trailer_dict)) = trailer.get("Root") {
-                debug!("Trailer: {:?}", trailer_dict);
+        let xref_stream_dictionary = self.read_xref_stream()?;
+
+        let root = xref_stream_dictionary
+            .as_ref()
+            .and_then(|dictionary| dictionary.get("Root"))
+            .or_else(|| self.pdf.trailer.as_ref().and_then(|trailer| trailer.get("Root")))
+            .or_else(|| {
+                // startxref may point to the wrong place, but any xref stream names the catalog
+                self.pdf
+                    .objects
+                    .values()
+                    .filter_map(|object| match &object.value {
+                        PDFValue::Stream(stream) if stream.dictionary.get("Type").and_then(|stream_type| stream_type.name().ok()).is_some_and(|stream_type| stream_type == "XRef") => {
+                            Some((object.offset, stream.dictionary.get("Root")?))
+                        },
+                        _ => None
+                    })
+                    .max_by_key(|(offset, _)| *offset)
+                    .map(|(_, root)| root)
+            });
+
+        let root_reference = match root {
+            Some(PDFValue::ObjectReference(root_reference)) => *root_reference,
+            Some(other) => return Err(format!("Root is not an indirect reference: {:?}", other)),
+            None => {
+                return self
+                    .find_catalog()
+                    .ok_or("Neither the xref stream nor the trailer has a Root entry, and no catalog was found".to_string());
             }
-        } else if let Some(startxref) = self.pdf.startxref {
-            debug!("StartXRef: {:?}", startxref);
+        };
+        debug!("Root: {:?}", root_reference);
 
-            if let PDFValue::Stream(stream) = self.get_object_at_offset(startxref).unwrap().value {
-                let stream_length = if let PDFValue::Number(length) = stream.dictionary.get("Length").expect("XRef stream dictionary has no Length member") {
-                    length
-                } else {
-                    panic!("XRef stream length cannot be converted from {:?}", stream.dictionary.get("Length"));
-                };
+        if !self.pdf.objects.contains_key(&root_reference) {
+            self.load_object_streams();
+        }
 
-                let width: &PDFValue = stream.dictionary.get("W").expect("No 'W' entry in xref stream dictionary");
-                let mut width_vector: Vec<u64> = vec![];
-                if let PDFValue::Array(width_array) = width {
-                    for val in width_array.iter() {
-                        if let PDFValue::Number(val) = val {
-                            width_vector.push(*val as u64);
-                        }
-                    }
-                }
-                let xref_size = if let PDFValue::Number(xref_size) = stream.dictionary.get("Size").expect("XRef stream dictionary has no Size member") {
-                    xref_size
-                } else {
-                    panic!("XRef size cannot be converted from {:?}", stream.dictionary.get("Size"));
-                };
+        self.get_object_by_reference(&root_reference)
+            .ok_or(format!("Root object {} {} not found", root_reference.object_number, root_reference.generation_number))
+    }
+
+    /// The last object in the file whose dictionary has `/Type /Catalog`
+    fn find_catalog(&self) -> Option<PDFObject> {
+        self.pdf
+            .objects
+            .values()
+            .filter(|object| {
+                object.value.dictionary().ok()
+                    .and_then(|dictionary| dictionary.get("Type"))
+                    .and_then(|object_type| object_type.name().ok())
+                    .is_some_and(|object_type| object_type == "Catalog")
+            })
+            .max_by_key(|object| (object.offset, object.header.object_number))
+            .cloned()
+    }
 
-                let mut decompressed_bytes = stream.decompress();
+    /// Unpacks the objects stored in object streams (`/Type /ObjStm`, 7.5.7). Objects
+    /// already read directly from the file are left alone.
+    fn load_object_streams(&mut self) {
+        let object_streams: Vec<(PDFObjectHeader, u64)> = self.pdf
+            .sorted_objects()
+            .into_iter()
+            .filter(|object| match &object.value {
+                PDFValue::Stream(stream) => stream.dictionary.get("Type").and_then(|stream_type| stream_type.name().ok()).is_some_and(|stream_type| stream_type == "ObjStm"),
+                _ => false
+            })
+            .map(|object| (object.header, object.offset))
+            .collect();
 
-                self.pdf.xref_table = Some(XRefSection {
-                    header: None,
-                    entries: self.parse_xref_stream(width_vector,decompressed_bytes)
-                });
+        for (stream_header, stream_offset) in object_streams {
+            let stream_object_number = stream_header.object_number;
+            let unpacked = self.pdf.objects[&stream_header].value.stream().and_then(unpack_object_stream);
+            let objects = match unpacked {
+                Ok(objects) => objects,
+                Err(err) => {
+                    warn!("Skipping object stream {stream_object_number}: {err}");
+                    continue;
+                }
+            };
 
-                let root: &PDFValue = stream.dictionary.get("Root").expect("No 'Root' entry in xref stream dictionary");
-                match root {
-                    PDFValue::ObjectReference(object_ref) => {
-                        return Ok(self.get_object_by_reference(object_ref).expect("Root object not found"));
-                    }
-                    _ => panic!("Root object was not object reference")
+            for (object_number, value) in objects {
+                let header = PDFObjectHeader { object_number, generation_number: 0 };
+                if self.pdf.objects.contains_key(&header) {
+                    continue;
+                }
+
+                match value {
+                    // Compressed objects have no file offset of their own, so they get their stream's
+                    Ok(value) => {
+                        self.pdf.objects.insert(header, PDFObject { header, value, offset: stream_offset });
+                    },
+                    Err(err) => warn!("Skipping object {object_number} in object stream {stream_object_number}: {err}")
                 }
-            } else {
-                panic!("No stream object at startxref location: {startxref}")
             }
         }
-
-        Err("".to_string())
     }
 
-    fn get_pages_dict(&mut self, root: &PDFObject) -> Result<PDFDictionary, String> {
-        let pages_obj_ref = root
+    fn get_pages_dict<'a>(&'a self, root: &'a PDFObject) -> Result<&'a PDFDictionary, String> {
+        root
             .value
-            .dictionary()
-            .unwrap()
+            .as_dictionary(&self.pdf)?
             .get("Pages")
-            .expect("Root dictionary has no pages member")
-            .object_reference();
-
-        Ok(self
-            .get_object_by_reference(pages_obj_ref)
-            .expect("Pages dictionary object not found")
-            .value
-            .dictionary()
-            .unwrap()
-            .clone()
-        )
+            .ok_or("Root dictionary has no Pages member")?
+            .as_dictionary(&self.pdf)
     }
 
-    fn read_pages(&mut self, pages_dict: &PDFDictionary) -> Result<Vec<PDFPage>, String> {
+    fn read_pages(&self, pages_dict: &PDFDictionary) -> Result<Vec<PDFPage>, String> {
         let mut pages: Vec<PDFPage> = vec![];
+        self.read_page_tree_node(pages_dict, &PDFDictionary::new(), &mut HashSet::new(), &mut pages)?;
+        Ok(pages)
+    }
 
-        let kids = pages_dict
+    /// Collects the pages under one `/Pages` node in document order, descending into
+    /// intermediate `/Pages` kids. `visited` keeps a kid that appears twice, or an
+    /// ancestor listed as a kid, from being read again.
+    fn read_page_tree_node(&self, node: &PDFDictionary, inherited: &PDFDictionary, visited: &mut HashSet<PDFObjectHeader>, pages: &mut Vec<PDFPage>) -> Result<(), String> {
+        let kids = node
             .get("Kids")
-            .expect("Pages dict has no kids entry")
-            .array();
+            .ok_or("Pages dict has no Kids entry")?
+            .as_array(&self.pdf)?;
+        let inherited = inherited_page_attributes(node, inherited);
 
         for kid in kids.iter() {
             debug!("kid: {:?}", kid);
-            let object: PDFObject = self.get_object_by_reference(kid.object_reference()).expect("Page object not found");
-            // debug!("kid object: {:?}", object);
-            let page_dict = object.value.dictionary().unwrap();
-
-            let contents_obj = match page_dict.get("Contents") {
-                Some(PDFValue::ObjectReference(object_header)) => {
-                    self.get_object_by_reference(object_header).unwrap()
-                },
-                Some(_) => {
-                    return Err("Page dict has no 'Contents' entry".to_string());
-                },
-                None => {
-                    return Err("Page dict has no 'Contents' entry".to_string());
-                },
+            let reference = match kid {
+                PDFValue::ObjectReference(reference) => *reference,
+                other => return Err(format!("Page tree kid is not an indirect reference: {:?}", other))
             };
+            if !visited.insert(reference) {
+                warn!("Page tree node {} {} is listed more than once, skipping it", reference.object_number, reference.generation_number);
+                continue;
+            }
+
+            let kid_object = self
+                .get_object_ref(&reference)
+                .ok_or(format!("Page object {} {} not found", reference.object_number, reference.generation_number))?;
+            let page_dict = kid_object.value.as_dictionary(&self.pdf)?;
+
+            if is_page_tree_node(page_dict, &self.pdf) {
+                self.read_page_tree_node(page_dict, &inherited, visited, pages)?;
+                continue;
+            }
+
+            let contents_obj = self.page_contents(kid_object, page_dict.get("Contents"))?;
 
+            let mut object = kid_object.clone();
+            inherit_page_attributes(&mut object, &inherited);
             pages.push(PDFPage { object, contents: contents_obj });
         }
 
-        Ok(pages)
+        Ok(())
     }
 
-    fn build_tree(&mut self) {
-        let root = self.get_root_object().unwrap();
+    fn build_tree(&mut self) -> Result<(), PdfError> {
+        let root = self.get_root_object().map_err(PdfError::Malformed)?;
         debug!("root object: {:?}", root);
-        let pages_dict = self.get_pages_dict(&root).unwrap();
+        let pages_dict = self.get_pages_dict(&root).map_err(PdfError::Malformed)?;
         debug!("pages_dict {:?}", pages_dict);
-        self.pdf.pages = self.read_pages(&pages_dict).unwrap();
-
-        let mut temp = 0;
-
-        for page in self.pdf.pages.iter() {
-            println!("==========================================");
-            page.get_text(temp);
-            temp += 1;
-        }
-
-        // panic!();
-        // debug!("pages: {:?}", self.pdf.pages);
+        let pages = self.read_pages(pages_dict).map_err(PdfError::Malformed)?;
+        self.pdf.root = Some(root);
+        self.pdf.pages = pages;
+        Ok(())
     }
 
-    fn parse(&mut self) {
+    fn parse(&mut self) -> Result<(), PdfError> {
+        let document_end = self.tokenizer.last_document_end();
+
         loop {
-            let current_offset = self.tokenizer.get_offset();
+            let current_offset = self.tokenizer.get_offset()?;
             let token = self.tokenizer.next();
             debug!("{:?}", token.as_ref());
 
             match token.as_ref() {
                 Ok(PDFToken::Comment(comment)) => {
                     if comment.is_version() {
+                        debug!("version: {comment}");
                         self.pdf.version = Some(comment.to_string());
-                        println!("version: {}", self.pdf.version.as_ref().unwrap().to_owned());
                     }
                 },
                 Ok(PDFToken::ObjectHeader(object_header)) => {
-                    let pdf_object = self.parse_object(current_offset, object_header).unwrap();
-                    self.pdf.objects.insert(pdf_object.header, pdf_object);
+                    match self.parse_object(current_offset, object_header) {
+                        Ok(pdf_object) => self.insert_object(pdf_object.header, pdf_object, ObjectLocation::Offset(current_offset)),
+                        Err(err) => {
+                            warn!("Skipping object {} {}: {err}", object_header.object_number, object_header.generation_number);
+                            self.pdf.parse_errors.push((*object_header, err.to_string()));
+                            if let Err(err) = self.tokenizer.skip_object(current_offset) {
+                                warn!("Could not skip past broken object: {err}");
+                                break;
+                            }
+                        }
+                    }
                 },
                 Ok(PDFToken::StartXRef(xref_offset)) => {
                     self.pdf.startxref = Some(*xref_offset);
                 },
                 Ok(PDFToken::DocumentEnd) => {
-                    break;
+                    // Incremental updates append objects, an xref and a trailer after the
+                    // first %%EOF. Keep reading until the last one and ignore anything after it.
+                    match document_end {
+                        Some(document_end) if self.tokenizer.get_offset()? < document_end => {
+                            self.tokenizer.resume();
+                        },
+                        _ => break
+                    }
                 },
                 Ok(PDFToken::XRefSectionBegin) => {
-                    self.parse_xref().unwrap();
+                    self.parse_xref()?;
                 },
                 Ok(PDFToken::TrailerBegin) => {
-                    match self.parse_value() {
-                        Ok(PDFValue::Dictionary(trailer_dictionary)) => {
+                    match self.parse_value()? {
+                        PDFValue::Dictionary(trailer_dictionary) => {
                             self.pdf.trailer = Some(trailer_dictionary);
                         },
-                        Ok(other) => {
-                            panic!("Unexpected token '{:?}' while looking for trailer dictionary", other);
-                        },
-                        Err(err) => {
-                            panic!("Trailer parse error: {err}");
+                        other => {
+                            return Err(PdfError::UnexpectedToken { expected: "trailer dictionary", found: format!("{:?}", other) });
                         }
                     }
                 },
                 Ok(something) => {
-                    panic!("Unexpected token {:?}", something);
+                    return Err(PdfError::UnexpectedToken { expected: "object, xref section or trailer", found: format!("{:?}", something) });
+                },
+                Err(err) if document_end.is_none() && !self.pdf.objects.is_empty() => {
+                    // A file cut off before its %%EOF can still be used for the objects read so far
+                    warn!("Stopping at offset {current_offset}, the file has no %%EOF: {err}");
+                    break;
                 },
                 Err(err) => {
-                    panic!("{err}");
+                    return Err(PdfError::Tokenizer(err.clone()));
                 }
             }
         }
-    }
 
-    fn parse_xref(&mut self) -> Result<XRefSection, String> {
-        let token = self.tokenizer.next();
-        debug!("{:?}", token.as_ref());
+        if let Some(trailer) = self.pdf.trailer_dictionary().cloned() {
+            self.set_up_security_handler(&trailer).map_err(PdfError::Unsupported)?;
+        }
+        if self.security_handler.is_some() {
+            let headers: Vec<PDFObjectHeader> = self.pdf.objects.keys().copied().collect();
+            for header in headers {
+                if let Some(mut object) = self.pdf.objects.remove(&header) {
+                    self.decrypt(&mut object);
+                    self.pdf.objects.insert(header, object);
+                }
+            }
+        }
 
-        let header = match token {
-            Ok(PDFToken::XRefSubSectionHeader(header)) => {
-                header
+        self.load_object_streams();
+        self.use_latest_object_versions();
+        Ok(())
+    }
+
+    /// Prepares decryption when the trailer has an `/Encrypt` entry (7.6). The dictionary
+    /// is loaded through the xref when it was not parsed yet.
+    fn set_up_security_handler(&mut self, trailer: &PDFDictionary) -> Result<(), String> {
+        let encrypt = match trailer.get("Encrypt") {
+            Some(PDFValue::ObjectReference(reference)) => {
+                self.encrypt_reference = Some(*reference);
+                match self.pdf.objects.get(reference) {
+                    Some(object) => object.value.dictionary()?.clone(),
+                    None => return Err(format!("Encrypt dictionary {} {} not found", reference.object_number, reference.generation_number))
+                }
             },
+            Some(PDFValue::Dictionary(encrypt)) => encrypt.clone(),
+            Some(other) => return Err(format!("Trailer /Encrypt is not a dictionary: {:?}", other)),
+            None => return Ok(())
+        };
+
+        self.security_handler = Some(SecurityHandler::new(&encrypt, trailer.get("ID"))?);
+        Ok(())
+    }
+
+    /// Decrypts an object read straight from the file. Xref streams and the encryption
+    /// dictionary are never encrypted, and objects unpacked from an object stream were
+    /// decrypted along with it.
+    fn decrypt(&self, object: &mut PDFObject) {
+        let handler = match &self.security_handler {
+            Some(handler) => handler,
+            None => return
+        };
+        if Some(object.header) == self.encrypt_reference {
+            return;
+        }
+        if let PDFValue::Stream(stream) = &object.value {
+            if stream.dictionary.get("Type").and_then(|stream_type| stream_type.name().ok()).is_some_and(|stream_type| stream_type == "XRef") {
+                return;
+            }
+        }
+
+        handler.decrypt_object(&object.header, &mut object.value);
+    }
+
+    /// Incrementally updated files define some objects more than once. Follows the xref
+    /// chain from `startxref` back through each `/Prev` and swaps in the version the newest
+    /// section points to wherever the linear parse kept a different one.
+    fn use_latest_object_versions(&mut self) {
+        let startxref = match self.pdf.startxref {
+            Some(startxref) => startxref,
+            None => return
+        };
+        self.locations = match self.read_xref_locations(startxref) {
+            Ok(locations) => locations,
             Err(err) => {
-                return Err(err);
+                warn!("Could not follow the xref chain, keeping objects in file order: {err}");
+                return;
+            }
+        };
+
+        let deleted_objects = self.deleted_objects.clone();
+        self.pdf.objects.retain(|header, _| {
+            let deleted = deleted_objects.contains(&header.object_number);
+            if deleted {
+                debug!("Dropping object {} {}, which a later update deleted", header.object_number, header.generation_number);
             }
-            other_token => {
-                return Err(format!("Unexpected token: {:?} while reading xref table", other_token));
+            !deleted
+        });
+
+        let mut headers_by_number: HashMap<u64, Vec<PDFObjectHeader>> = HashMap::new();
+        for header in self.pdf.objects.keys() {
+            headers_by_number.entry(header.object_number).or_default().push(*header);
+        }
+
+        let locations: Vec<(u64, ObjectLocation)> = self.locations.iter().map(|(object_number, location)| (*object_number, *location)).collect();
+        for (object_number, location) in &locations {
+            let expected_offset = match location {
+                ObjectLocation::Offset(offset) => Some(*offset),
+                ObjectLocation::InObjectStream { stream_object_number, .. } => match self.locations.get(stream_object_number) {
+                    Some(ObjectLocation::Offset(offset)) => Some(*offset),
+                    _ => None
+                }
+            };
+            let headers = headers_by_number.get(object_number).cloned().unwrap_or_default();
+            let is_latest = headers
+                .iter()
+                .any(|header| self.pdf.objects.get(header).map(|object| object.offset) == expected_offset);
+            if is_latest {
+                continue;
+            }
+
+            let reference = PDFObjectHeader { object_number: *object_number, generation_number: 0 };
+            match self.read_object_at(&reference, *location) {
+                Ok(object) if object.header.object_number != *object_number => {
+                    warn!("The xref entry for object {object_number} points at object {} instead", object.header.object_number);
+                },
+                Ok(object) => {
+                    debug!("Using the newer version of object {object_number} at offset {}", object.offset);
+                    for header in headers {
+                        self.pdf.objects.remove(&header);
+                    }
+                    self.insert_object(object.header, object, *location);
+                },
+                Err(err) => warn!("Could not read object {object_number} where the xref points: {err}")
+            }
+        }
+    }
+
+    /// Reads only the xref and trailer, and sets up decryption, instead of parsing the whole
+    /// file. Objects are then parsed the first time they are asked for, through `get_object`
+    /// or `page`, and kept for later.
+    pub fn read_xref(&mut self) -> Result<(), PdfError> {
+        self.locations = match self.tokenizer.last_startxref() {
+            Some(startxref) => {
+                self.pdf.startxref = Some(startxref);
+                match self.read_xref_locations(startxref) {
+                    Ok(locations) => locations,
+                    Err(err) => {
+                        warn!("Rebuilding the xref by scanning for objects: {err}");
+                        self.rebuild_xref_locations()
+                    }
+                }
             },
+            None => {
+                warn!("No startxref found at the end of the file, rebuilding the xref by scanning for objects");
+                self.rebuild_xref_locations()
+            }
         };
 
-        let entries: Vec<XRefEntry> = self.tokenizer.get_xref_table(header.num_entries).unwrap();
+        if let Some(trailer) = self.pdf.trailer.clone() {
+            // The encryption dictionary has to be read before the handler exists
+            if let Some(PDFValue::ObjectReference(encrypt_reference)) = trailer.get("Encrypt") {
+                self.load_object(encrypt_reference).map_err(PdfError::Malformed)?;
+            }
+            self.set_up_security_handler(&trailer).map_err(PdfError::Unsupported)?;
+        }
 
-        Ok(XRefSection {
-            header: Some(header),
-            entries
-        })
+        Ok(())
+    }
+
+    /// The object for `reference`, parsed on first use after `read_xref`
+    pub fn get_object(&mut self, reference: &PDFObjectHeader) -> Result<&PDFObject, PdfError> {
+        self.load_object(reference).map_err(PdfError::Malformed)
+    }
+
+    /// The zero-based `index`th page after `read_xref`. Only the catalog, the page tree
+    /// nodes on the way down, their kids and the objects the page itself refers to are
+    /// parsed; the page tree `/Count`s say which subtree to go into.
+    pub fn page(&mut self, index: usize) -> Result<PDFPage, PdfError> {
+        self.load_page(index).map_err(PdfError::Malformed)
+    }
+
+    /// The document read so far, including every object loaded on demand
+    pub fn pdf(&self) -> &PDF {
+        &self.pdf
     }
 
-    fn parse_array(&mut self) -> Result<PDFValue, String> {
+    fn load_page(&mut self, index: usize) -> Result<PDFPage, String> {
+        let root_reference = match self.pdf.trailer.as_ref().and_then(|trailer| trailer.get("Root")) {
+            Some(PDFValue::ObjectReference(root_reference)) => *root_reference,
+            _ => return Err("Trailer has no Root reference".to_string())
+        };
+        let root = self.load_object(&root_reference)?.clone();
+
+        let mut node_reference = match root.value.dictionary()?.get("Pages") {
+            Some(PDFValue::ObjectReference(pages_reference)) => *pages_reference,
+            _ => return Err("Root dictionary has no Pages reference".to_string())
+        };
+        self.pdf.root = Some(root);
+
+        let mut remaining = index;
+        let mut visited: HashSet<PDFObjectHeader> = HashSet::new();
+        let mut inherited = PDFDictionary::new();
+        let mut page_object = loop {
+            if !visited.insert(node_reference) {
+                return Err("Page tree has a cycle".to_string());
+            }
+
+            self.load_object(&node_reference)?;
+            let node = &self.pdf.objects[&node_reference];
+            let node_dictionary = node.value.as_dictionary(&self.pdf)?;
+            if !is_page_tree_node(node_dictionary, &self.pdf) {
+                break node.clone();
+            }
+            inherited = inherited_page_attributes(node_dictionary, &inherited);
+
+            let kids = node_dictionary
+                .get("Kids")
+                .ok_or("Page tree node has no Kids")?
+                .as_array(&self.pdf)?
+                .clone();
+            let mut next_node = None;
+            for kid in kids {
+                let kid_reference = match kid {
+                    PDFValue::ObjectReference(kid_reference) => kid_reference,
+                    other => return Err(format!("Page tree kid is not an indirect reference: {:?}", other))
+                };
+                self.load_object(&kid_reference)?;
+                let kid_dictionary = self.pdf.objects[&kid_reference].value.as_dictionary(&self.pdf)?;
+                let kid_page_count = if is_page_tree_node(kid_dictionary, &self.pdf) {
+                    kid_dictionary
+                        .get("Count")
+                        .ok_or("Page tree node has no Count")?
+                        .as_number(&self.pdf)? as usize
+                } else {
+                    1
+                };
+
+                if remaining < kid_page_count {
+                    next_node = Some(kid_reference);
+                    break;
+                }
+                remaining -= kid_page_count;
+            }
+
+            node_reference = next_node.ok_or(format!("The document has no page {}", index + 1))?;
+        };
+
+        inherit_page_attributes(&mut page_object, &inherited);
+        self.load_references(&page_object.value)?;
+
+        // load_references has loaded the content streams along with everything else
+        let contents = self.page_contents(&page_object, page_object.value.dictionary()?.get("Contents"))?;
+
+        Ok(PDFPage { object: page_object, contents })
+    }
+
+    /// Reads just the catalog, the page tree nodes down to the first page, and the objects
+    /// that page refers to, by following the xref from the end of the file instead of
+    /// parsing every object
+    pub fn first_page(mut self) -> Result<(PDF, PDFPage), PdfError> {
+        self.read_xref()?;
+        let page = self.load_page(0).map_err(PdfError::Malformed)?;
+        self.pdf.pages = vec![page.clone()];
+
+        Ok((self.pdf, page))
+    }
+
+    /// Object number to location for every in-use object, following `/Prev` into older
+    /// sections. Entries from newer sections win, including free ones, so an object deleted
+    /// by an update stays deleted. Also sets the trailer from the newest section.
+    fn read_xref_locations(&mut self, startxref: u64) -> Result<HashMap<u64, ObjectLocation>, String> {
+        let mut locations = XRefLocations::new();
+        let mut visited_sections: HashSet<u64> = HashSet::new();
+        let mut next_section = Some(startxref);
+
+        while let Some(section_offset) = next_section {
+            if !visited_sections.insert(section_offset) {
+                break;
+            }
+
+            self.tokenizer.seek_to(section_offset)?;
+            let trailer = match self.tokenizer.next()? {
+                PDFToken::XRefSectionBegin => {
+                    let mut section_locations = XRefLocations::new();
+                    for subsection in self.parse_xref().map_err(|err| err.to_string())? {
+                        let first_object_number = subsection.header.as_ref().map_or(0, |header| header.first_object_number);
+                        for (index, entry) in subsection.entries.iter().enumerate() {
+                            if let XRefEntry::Simple(entry) = entry {
+                                let location = (!entry.free).then_some(ObjectLocation::Offset(entry.byte_offset));
+                                section_locations.entry(first_object_number + index as u64).or_insert(location);
+                            }
+                        }
+                    }
+
+                    let trailer = match self.tokenizer.next()? {
+                        PDFToken::TrailerBegin => self.parse_value().map_err(|err| err.to_string())?.dictionary()?.clone(),
+                        token => return Err(format!("Expected trailer after xref table, found {:?}", token))
+                    };
+
+                    // Hybrid-reference files (7.5.8.4) list the objects that older readers
+                    // cannot find, such as compressed ones, in an xref stream named by /XRefStm.
+                    // The table marks those free, but its in-use entries take precedence.
+                    if let Some(xref_stream_offset) = trailer_offset(&trailer, "XRefStm")? {
+                        self.tokenizer.seek_to(xref_stream_offset)?;
+                        let mut stream_locations = XRefLocations::new();
+                        match self.tokenizer.next()? {
+                            PDFToken::ObjectHeader(header) => {
+                                self.read_xref_stream_locations(xref_stream_offset, &header, &mut stream_locations)?;
+                            },
+                            token => return Err(format!("Expected the XRefStm stream at offset {xref_stream_offset}, found {:?}", token))
+                        }
+                        for (object_number, location) in stream_locations {
+                            if !matches!(section_locations.get(&object_number), Some(Some(_))) {
+                                section_locations.insert(object_number, location);
+                            }
+                        }
+                    }
+
+                    self.merge_older_locations(&mut locations, section_locations);
+
+                    trailer
+                },
+                PDFToken::ObjectHeader(header) => {
+                    let mut section_locations = XRefLocations::new();
+                    let dictionary = self.read_xref_stream_locations(section_offset, &header, &mut section_locations)?;
+                    self.merge_older_locations(&mut locations, section_locations);
+                    dictionary
+                },
+                token => return Err(format!("Expected an xref section at offset {section_offset}, found {:?}", token))
+            };
+
+            next_section = trailer_offset(&trailer, "Prev")?;
+            if self.pdf.trailer.is_none() {
+                self.pdf.trailer = Some(trailer);
+            }
+        }
+
+        Ok(in_use_locations(locations))
+    }
+
+    /// Adds the entries of an older xref section to those already read from newer ones,
+    /// noting the objects a newer section deleted
+    fn merge_older_locations(&mut self, locations: &mut XRefLocations, older_locations: XRefLocations) {
+        for (object_number, location) in older_locations {
+            match locations.get(&object_number) {
+                None => {
+                    locations.insert(object_number, location);
+                },
+                Some(None) if location.is_some() => {
+                    self.deleted_objects.insert(object_number);
+                },
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Object locations found by scanning the whole file for object headers, for when the
+    /// xref cannot be used. Later definitions of an object win, and compressed objects come
+    /// from any xref streams found. The trailer is taken from the last `trailer` keyword,
+    /// or else from the last xref stream that has a `/Root`.
+    fn rebuild_xref_locations(&mut self) -> HashMap<u64, ObjectLocation> {
+        let headers = self.tokenizer.find_object_headers();
+        let mut locations: XRefLocations = headers
+            .iter()
+            .map(|(header, offset)| (header.object_number, Some(ObjectLocation::Offset(*offset))))
+            .collect();
+
+        if let Some(trailer_offset) = self.tokenizer.find_last_trailer() {
+            if let Ok(PDFToken::TrailerBegin) = self.tokenizer.seek_to(trailer_offset).and_then(|_| self.tokenizer.next()) {
+                match self.parse_value() {
+                    Ok(PDFValue::Dictionary(trailer)) => self.pdf.trailer = Some(trailer),
+                    other => warn!("Could not read the trailer at offset {trailer_offset}: {:?}", other)
+                }
+            }
+        }
+
+        // Compressed objects can only be found through the xref streams, which may also
+        // stand in for a missing trailer
+        for (header, offset) in headers.iter().rev() {
+            if !matches!(self.tokenizer.seek_to(*offset).and_then(|_| self.tokenizer.next()), Ok(PDFToken::ObjectHeader(_))) {
+                continue;
+            }
+            // Anything that is not an xref stream fails here and is passed over
+            if let Ok(dictionary) = self.read_xref_stream_locations(*offset, header, &mut locations) {
+                let has_root = self.pdf.trailer.as_ref().is_some_and(|trailer| trailer.contains_key("Root"));
+                if !has_root && dictionary.contains_key("Root") {
+                    self.pdf.trailer = Some(dictionary);
+                }
+            }
+        }
+
+        in_use_locations(locations)
+    }
+
+    /// Adds the entries of the xref stream object whose header was just read at `offset`,
+    /// keeping any location already known, and returns the stream's dictionary
+    fn read_xref_stream_locations(&mut self, offset: u64, header: &PDFObjectHeader, locations: &mut XRefLocations) -> Result<PDFDictionary, String> {
+        let object = self.parse_object(offset, header).map_err(|err| err.to_string())?;
+        let stream = object.value.stream()?;
+        let widths: Vec<u64> = stream.dictionary
+            .get("W")
+            .and_then(|widths| widths.as_f64_array())
+            .ok_or("XRef stream has no W array")?
+            .iter()
+            .map(|width| *width as u64)
+            .collect();
+        let size = match stream.dictionary.get("Size") {
+            Some(PDFValue::Number(size)) => *size,
+            _ => return Err("XRef stream has no Size".to_string())
+        };
+        let index = stream.dictionary
+            .get("Index")
+            .and_then(|index| index.as_f64_array())
+            .unwrap_or(vec![0.0, size]);
+
+        let entries = self.parse_xref_stream(widths, stream.decompress()?)?;
+        let object_numbers = index
+            .chunks_exact(2)
+            .flat_map(|range| range[0] as u64..(range[0] + range[1]) as u64);
+        for (object_number, entry) in object_numbers.zip(&entries) {
+            let location = match entry {
+                XRefEntry::Uncompressed(entry) => Some(ObjectLocation::Offset(entry.byte_offset)),
+                XRefEntry::Compressed(entry) => Some(ObjectLocation::InObjectStream {
+                    stream_object_number: entry.object_number_of_parent_stream,
+                    index: entry.index_in_stream
+                }),
+                XRefEntry::Free(_) => None,
+                XRefEntry::Simple(_) => continue
+            };
+            locations.entry(object_number).or_insert(location);
+        }
+
+        Ok(stream.dictionary.clone())
+    }
+
+    /// Reads an object from where the xref says it is, without consulting what was
+    /// already loaded
+    fn read_object_at(&mut self, reference: &PDFObjectHeader, location: ObjectLocation) -> Result<PDFObject, String> {
+        match location {
+            ObjectLocation::Offset(offset) => {
+                self.tokenizer.seek_to(offset)?;
+                let mut object = match self.tokenizer.next()? {
+                    PDFToken::ObjectHeader(header) => self.parse_object(offset, &header).map_err(|err| err.to_string())?,
+                    token => return Err(format!("Expected object header at offset {offset}, found {:?}", token))
+                };
+                self.decrypt(&mut object);
+                Ok(object)
+            },
+            ObjectLocation::InObjectStream { stream_object_number, index } => {
+                let stream_reference = PDFObjectHeader { object_number: stream_object_number, generation_number: 0 };
+                let stream_offset = self.load_object(&stream_reference)?.offset;
+                if !self.object_streams.contains_key(&stream_object_number) {
+                    let unpacked = unpack_object_stream(self.load_object(&stream_reference)?.value.stream()?)?;
+                    self.object_streams.insert(stream_object_number, unpacked);
+                }
+
+                let (object_number, value) = self.object_streams[&stream_object_number]
+                    .get(index as usize)
+                    .ok_or(format!("Object stream {stream_object_number} has no object at index {index}"))?;
+                if *object_number != reference.object_number {
+                    return Err(format!("Object stream {stream_object_number} holds object {object_number} at index {index}, not {}", reference.object_number));
+                }
+
+                Ok(PDFObject { header: *reference, value: value.clone()?, offset: stream_offset })
+            }
+        }
+    }
+
+    /// The object for `reference`, parsed from where the xref puts it unless already loaded
+    fn load_object(&mut self, reference: &PDFObjectHeader) -> Result<&PDFObject, String> {
+        if !self.pdf.objects.contains_key(reference) {
+            let location = *self.locations
+                .get(&reference.object_number)
+                .ok_or(format!("Object {} {} is not in the xref table", reference.object_number, reference.generation_number))?;
+
+            let object = self.read_object_at(reference, location)?;
+            self.insert_object(*reference, object, location);
+        }
+
+        Ok(&self.pdf.objects[reference])
+    }
+
+    /// Loads everything reachable from `value`, except back up the page tree through `/Parent`
+    fn load_references(&mut self, value: &PDFValue) -> Result<(), String> {
+        match value {
+            PDFValue::ObjectReference(reference) if !self.pdf.objects.contains_key(reference) => {
+                let value = self.load_object(reference)?.value.clone();
+                self.load_references(&value)?;
+            },
+            PDFValue::Array(values) => {
+                for value in values {
+                    self.load_references(value)?;
+                }
+            },
+            PDFValue::Dictionary(dictionary) | PDFValue::Stream(PDFStream { dictionary, .. }) => {
+                for (key, value) in dictionary {
+                    if key != "Parent" {
+                        self.load_references(value)?;
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reads the subsections of an xref table up to its trailer, one `XRefSection` each
+    fn parse_xref(&mut self) -> Result<Vec<XRefSection>, PdfError> {
+        let mut subsections: Vec<XRefSection> = vec![];
+
+        loop {
+            let token = self.tokenizer.next();
+            debug!("{:?}", token.as_ref());
+
+            let header = match token {
+                Ok(PDFToken::XRefSubSectionHeader(header)) => {
+                    header
+                },
+                Ok(PDFToken::XRefSectionEnd) => {
+                    return Ok(subsections);
+                },
+                Err(err) => {
+                    return Err(PdfError::Tokenizer(err));
+                }
+                Ok(other_token) => {
+                    return Err(PdfError::UnexpectedToken { expected: "xref subsection header", found: format!("{:?}", other_token) });
+                },
+            };
+
+            let entries: Vec<XRefEntry> = self.tokenizer.get_xref_table(header.num_entries)?;
+            subsections.push(XRefSection {
+                header: Some(header),
+                entries
+            });
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<PDFValue, PdfError> {
         let mut values: Vec<PDFValue> = vec![];
 
         loop {
@@ -312,14 +1145,14 @@ impl<T: PDFTokenize> Reader<T> {
                 Ok(PDFToken::ArrayEnd) => {
                     // Consume array end token
                     debug!("{:?}", next_token.as_ref());
-                    self.tokenizer.next().unwrap();
+                    self.tokenizer.next()?;
                     break;
                 },
                 Ok(_) => {
-                    values.push(self.parse_value().unwrap());
+                    values.push(self.parse_value()?);
                 },
                 Err(err) => {
-                    return Err(err);
+                    return Err(PdfError::Tokenizer(err));
                 }
             }
         }
@@ -327,7 +1160,7 @@ impl<T: PDFTokenize> Reader<T> {
         Ok(PDFValue::Array(values))
     }
 
-    fn parse_dictionary(&mut self) -> Result<PDFDictionary, String> {
+    fn parse_dictionary(&mut self) -> Result<PDFDictionary, PdfError> {
         let mut dictionary = PDFDictionary::new();
 
         loop {
@@ -339,15 +1172,15 @@ impl<T: PDFTokenize> Reader<T> {
                 },
                 Ok(PDFToken::Name(name)) => name,
                 Ok(token) => {
-                    return Err(format!("Got unexpected token {:?} while looking for dictionary key", token));
+                    return Err(PdfError::UnexpectedToken { expected: "dictionary key", found: format!("{:?}", token) });
                 },
                 Err(err) => {
-                    return Err(err);
+                    return Err(PdfError::Tokenizer(err));
                 }
             };
 
 
-            let value = self.parse_value().unwrap();
+            let value = self.parse_value()?;
 
             dictionary.insert(key, value);
         }
@@ -355,29 +1188,38 @@ impl<T: PDFTokenize> Reader<T> {
         Ok(dictionary)
     }
 
-    fn parse_stream(&mut self, stream_dictionary: PDFDictionary) -> Result<PDFValue, String> {
-        let length = match stream_dictionary.get("Length") {
-            Some(PDFValue::Number(number)) => number,
-            Some(_) => {
-                return Err("Stream dictionary has a Length that is not a number".to_string())
+    fn parse_stream(&mut self, stream_dictionary: PDFDictionary) -> Result<PDFValue, PdfError> {
+        // /Length may be an indirect reference to an object not read yet, and some writers
+        // get it wrong, so the position of endstream is what counts when it can be found
+        let declared_length = match stream_dictionary.get("Length") {
+            Some(PDFValue::Number(number)) => Some(*number as usize),
+            _ => None
+        };
+        let length = match (self.tokenizer.scan_stream_length(), declared_length) {
+            (Some(scanned_length), Some(declared_length)) => {
+                if scanned_length != declared_length {
+                    warn!("Stream Length is {declared_length} but endstream follows {scanned_length} bytes of data");
+                }
+                scanned_length
             },
-            None => {
-                return Err("Stream dictionary has no Length member".to_string());
+            (Some(length), None) | (None, Some(length)) => length,
+            (None, None) => {
+                return Err(PdfError::Malformed("Stream dictionary has no usable Length and no endstream".to_string()));
             }
         };
 
-        let bytes = self.tokenizer.get_stream(*length as usize);
+        let bytes = self.tokenizer.get_stream(length)?;
 
         let next_token = self.tokenizer.next();
         debug!("{:?}", next_token.as_ref());
 
         match next_token? {
             PDFToken::StreamEnd => Ok(PDFValue::Stream( PDFStream {bytes, dictionary: stream_dictionary})),
-            token => Err(format!("Unexpected token {:?} while parsing stream", token))
+            token => Err(PdfError::UnexpectedToken { expected: "endstream", found: format!("{:?}", token) })
         }
     }
 
-    fn parse_value(&mut self) -> Result<PDFValue, String> {
+    fn parse_value(&mut self) -> Result<PDFValue, PdfError> {
         let token = self.tokenizer.next();
         debug!("{:?}", token.as_ref());
         match token {
@@ -385,18 +1227,21 @@ impl<T: PDFTokenize> Reader<T> {
                 self.parse_array()
             },
             Ok(PDFToken::DictionaryStart) => {
-                let dictionary = self.parse_dictionary().unwrap();
+                let dictionary = self.parse_dictionary()?;
                 match self.tokenizer.peak_next() {
                     Ok(PDFToken::StreamBegin) => {
-                        debug!("{:?}", self.tokenizer.next());
+                        // Consume the keyword we just peeked; this must not live inside debug!,
+                        // which skips evaluating its arguments when debug logging is off
+                        let stream_begin = self.tokenizer.next();
+                        debug!("{:?}", stream_begin);
                         self.parse_stream(dictionary)
                     },
                     Ok(_) => Ok(PDFValue::Dictionary(dictionary)),
-                    Err(err) => Err(err)
+                    Err(err) => Err(PdfError::Tokenizer(err))
                 }
             },
             Ok(PDFToken::Name(name)) => {
-                Ok(PDFValue::String(name))
+                Ok(PDFValue::Name(name))
             },
             Ok(PDFToken::String(string_token)) => {
                 Ok(PDFValue::String(string_token))
@@ -417,14 +1262,14 @@ impl<T: PDFTokenize> Reader<T> {
                 Ok(PDFValue::Bytes(bytes))
             },
             Ok(token) => {
-                todo!("Could not parse {:?}", token)
+                Err(PdfError::UnexpectedToken { expected: "value", found: format!("{:?}", token) })
             },
-            Err(err) => Err(err)
+            Err(err) => Err(PdfError::Tokenizer(err))
         }
     }
 
-    fn parse_object(&mut self, offset: u64, header: &PDFObjectHeader) -> Result<PDFObject, String> {
-        let value = self.parse_value().unwrap();
+    fn parse_object(&mut self, offset: u64, header: &PDFObjectHeader) -> Result<PDFObject, PdfError> {
+        let value = self.parse_value()?;
 
         let next_token = self.tokenizer.next();
         debug!("{:?}", next_token.as_ref());
@@ -435,7 +1280,321 @@ impl<T: PDFTokenize> Reader<T> {
                 value,
                 offset
             }),
-            token => Err(format!("Unexpected token {:?} while parsing object", token))
+            token => Err(PdfError::UnexpectedToken { expected: "endobj", found: format!("{:?}", token) })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::test_pdf::TestPdf;
+
+    /// A one-page document whose update replaces the content stream and deletes object 5,
+    /// listing them in separate xref subsections
+    fn updated_pdf() -> Vec<u8> {
+        let mut pdf = TestPdf::single_page("Old");
+        pdf.object(5, "<< /Note (deleted later) >>");
+        let first_xref = pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+        pdf.stream(4, "", b"BT (New) Tj ET");
+        pdf.finish_section(&[0, 5], &format!("<< /Size 6 /Root 1 0 R /Prev {first_xref} >>"));
+        pdf.bytes
+    }
+
+    #[test]
+    fn bytes_after_the_last_eof_are_ignored() {
+        let mut pdf = TestPdf::single_page("Old");
+        let first_xref = pdf.finish_section(&[0], "<< /Size 5 /Root 1 0 R >>");
+        pdf.stream(4, "", b"BT (New) Tj ET");
+        pdf.finish_section(&[], &format!("<< /Size 5 /Root 1 0 R /Prev {first_xref} >>"));
+        pdf.raw(b"-- footer written by another tool --\x00\xff 9 0 obj");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "New\n");
+    }
+
+    #[test]
+    fn streams_and_bare_dictionaries_are_told_apart() {
+        let mut pdf = TestPdf::single_page("Text");
+        pdf.object(5, "<< /Length 3 /Note (no data) >>");
+        pdf.stream(6, "/Note (data)", b"abc");
+        pdf.finish_section(&[0], "<< /Size 7 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let value = |object_number| &document.pdf().get_object(&PDFObjectHeader { object_number, generation_number: 0 }).unwrap().value;
+        assert!(matches!(value(5), PDFValue::Dictionary(dictionary) if dictionary.contains_key("Note")));
+        assert!(matches!(value(6), PDFValue::Stream(stream) if stream.bytes == b"abc" && stream.dictionary.contains_key("Note")));
+    }
+
+    #[test]
+    fn first_page_loads_only_its_own_objects() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R /Outlines 7 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R 5 0 R] /Count 2 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (First) Tj ET");
+        pdf.object(5, "<< /Type /Page /Parent 2 0 R /Contents 6 0 R >>");
+        pdf.stream(6, "", b"BT (Second) Tj ET");
+        pdf.object(7, "<< /Type /Outlines /Count 0 >>");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let (pdf, page) = PDF::open_first_page(Cursor::new(pdf.bytes)).unwrap();
+        let mut loaded: Vec<u64> = pdf.objects.keys().map(|header| header.object_number).collect();
+        loaded.sort();
+        assert_eq!(loaded, vec![1, 2, 3, 4]);
+        assert_eq!(page.extract_text(&pdf).unwrap(), "First\n");
+    }
+
+    #[test]
+    fn first_page_reports_a_missing_root_as_malformed() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.finish_section(&[0], "<< /Size 2 >>");
+
+        assert!(matches!(PDF::open_first_page(Cursor::new(pdf.bytes)), Err(PdfError::Malformed(_))));
+    }
+
+    /// Two pages: the first has a reference to an array of content streams, the second no
+    /// `/Contents` at all
+    fn contents_forms_pdf() -> Vec<u8> {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R 7 0 R] /Count 2 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.object(4, "[5 0 R 6 0 R]");
+        pdf.stream(5, "", b"BT (Joined) Tj");
+        pdf.stream(6, "", b"ET");
+        pdf.object(7, "<< /Type /Page /Parent 2 0 R >>");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+        pdf.bytes
+    }
+
+    #[test]
+    fn contents_may_be_a_referenced_array_or_missing() {
+        let document = Document::from_bytes(contents_forms_pdf()).unwrap();
+        let text: Vec<String> = document.pages().map(|page| page.text().unwrap()).collect();
+        assert_eq!(text, vec!["Joined\n", ""]);
+
+        let (pdf, page) = PDF::open_first_page(Cursor::new(contents_forms_pdf())).unwrap();
+        assert_eq!(page.extract_text(&pdf).unwrap(), "Joined\n");
+    }
+
+    #[test]
+    fn contents_of_the_wrong_type_are_named_in_the_error() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.object(4, "<< /Not /AStream >>");
+        pdf.finish_section(&[0], "<< /Size 5 /Root 1 0 R >>");
+
+        let Err(PdfError::Malformed(message)) = PDF::open_first_page(Cursor::new(pdf.bytes)) else {
+            panic!("Contents of the wrong type should be malformed");
+        };
+        assert!(message.contains("Dictionary"), "{message}");
+    }
+
+    #[test]
+    fn stream_data_right_after_the_keyword_is_kept() {
+        let mut pdf = TestPdf::single_page("Text");
+        // No end of line after the keyword, and data that starts with a space
+        pdf.object(5, "<< /Length 4 >>\nstream \u{1}\u{2}A\nendstream");
+        pdf.finish_section(&[0], "<< /Size 6 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let stream = document.pdf().get_object(&PDFObjectHeader { object_number: 5, generation_number: 0 }).unwrap().value.stream().unwrap();
+        assert_eq!(stream.bytes, b" \x01\x02A");
+    }
+
+    /// A one-page document whose catalog is compressed into an object stream, so `/Root`
+    /// in the cross-reference stream points into it
+    fn compressed_catalog_pdf() -> Vec<u8> {
+        let mut pdf = TestPdf::new();
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Compressed root) Tj ET");
+        pdf.object_stream(5, &[(1, "<< /Type /Catalog /Pages 2 0 R >>")]);
+        pdf.finish_xref_stream(6, "/Root 1 0 R");
+        pdf.bytes
+    }
+
+    #[test]
+    fn root_inside_an_object_stream() {
+        let document = Document::from_bytes(compressed_catalog_pdf()).unwrap();
+        let catalog_type = document.pdf().catalog().and_then(|catalog| catalog.get("Type"));
+        assert_eq!(catalog_type, Some(&PDFValue::Name("Catalog".to_string())));
+        assert_eq!(document.extract_text().unwrap(), "Compressed root\n");
+
+        let (pdf, page) = PDF::open_first_page(Cursor::new(compressed_catalog_pdf())).unwrap();
+        assert_eq!(page.extract_text(&pdf).unwrap(), "Compressed root\n");
+    }
+
+    #[test]
+    fn root_is_taken_from_a_classic_trailer() {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog >>");
+        // A later catalog that /Root does not name, which a search by type would pick
+        pdf.object(2, "<< /Type /Catalog >>");
+        pdf.finish_section(&[0], "<< /Size 3 /Root 1 0 R >>");
+
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(pdf.bytes)));
+        reader.parse().unwrap();
+        assert_eq!(reader.get_root_object().unwrap().header.object_number, 1);
+    }
+
+    #[test]
+    fn a_corrupt_object_is_recorded_and_the_rest_still_load() {
+        let mut pdf = TestPdf::single_page("Still here");
+        pdf.object(5, "<< /Good true >>");
+        pdf.object(6, "<< /Broken ) >>");
+        pdf.object(7, "<< /AlsoGood true >>");
+        pdf.finish_section(&[0], "<< /Size 8 /Root 1 0 R >>");
+
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        let header = |object_number| PDFObjectHeader { object_number, generation_number: 0 };
+        assert!(document.pdf().get_object(&header(5)).is_some());
+        assert!(document.pdf().get_object(&header(6)).is_none());
+        assert!(document.pdf().get_object(&header(7)).is_some());
+        assert_eq!(
+            document.pdf().parse_errors.iter().map(|(header, _)| *header).collect::<Vec<_>>(),
+            vec![header(6)]
+        );
+        assert_eq!(document.extract_text().unwrap(), "Still here\n");
+    }
+
+    #[test]
+    fn objects_in_one_object_stream_share_its_unpacking() {
+        let mut pdf = TestPdf::new();
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>");
+        pdf.stream(4, "", b"BT (Text) Tj ET");
+        pdf.object_stream(5, &[(1, "<< /Type /Catalog /Pages 2 0 R >>"), (6, "(six)"), (7, "[7]")]);
+        pdf.finish_xref_stream(8, "/Root 1 0 R");
+
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(pdf.bytes)));
+        reader.read_xref().unwrap();
+        let value = |reader: &mut Reader<_>, object_number| reader.get_object(&PDFObjectHeader { object_number, generation_number: 0 }).unwrap().value.clone();
+        assert_eq!(value(&mut reader, 7), PDFValue::Array(vec![PDFValue::Number(7.0)]));
+        assert_eq!(value(&mut reader, 6), PDFValue::String("six".to_string()));
+        assert_eq!(reader.object_streams.keys().collect::<Vec<_>>(), vec![&5]);
+        assert_eq!(reader.object_streams[&5].len(), 3);
+    }
+
+    #[test]
+    fn reads_every_xref_subsection() {
+        let document = Document::from_bytes(updated_pdf()).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "New\n");
+    }
+
+    #[test]
+    fn free_entries_in_an_update_delete_older_objects() {
+        let deleted = PDFObjectHeader { object_number: 5, generation_number: 0 };
+
+        let document = Document::from_bytes(updated_pdf()).unwrap();
+        assert!(document.pdf().get_object(&deleted).is_none());
+
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(updated_pdf())));
+        reader.read_xref().unwrap();
+        assert!(reader.get_object(&deleted).is_err());
+        assert!(reader.get_object(&PDFObjectHeader { object_number: 4, generation_number: 0 }).is_ok());
+    }
+
+    #[test]
+    fn hybrid_files_find_compressed_objects_through_xref_stm() {
+        // The page is only in the object stream, which the table marks free and the xref
+        // stream named by /XRefStm locates
+        let mut pdf = TestPdf::new();
+        let catalog = pdf.offset();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        let pages = pdf.offset();
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        let content = pdf.offset();
+        pdf.stream(4, "", b"BT (Hybrid) Tj ET");
+        let object_stream = pdf.offset();
+        pdf.object_stream(5, &[(3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>")]);
+        let xref_stream = pdf.finish_xref_stream(6, "");
+        let table = pdf.offset();
+        pdf.raw(format!(
+            "xref\n0 6\n0000000000 65535 f \n{catalog:010} 00000 n \n{pages:010} 00000 n \n0000000000 65535 f \n\
+             {content:010} 00000 n \n{object_stream:010} 00000 n \n\
+             trailer\n<< /Size 7 /Root 1 0 R /XRefStm {xref_stream} >>\nstartxref\n{table}\n%%EOF\n"
+        ).as_bytes());
+
+        let (pdf, page) = PDF::open_first_page(Cursor::new(pdf.bytes)).unwrap();
+        assert_eq!(page.extract_text(&pdf).unwrap(), "Hybrid\n");
+    }
+
+    #[test]
+    fn trailer_offsets_must_be_non_negative_integers() {
+        for (entry, value) in [("Prev", "-5"), ("Prev", "9.5"), ("Prev", "(12)"), ("XRefStm", "-1")] {
+            let mut pdf = TestPdf::single_page("Text");
+            let startxref = pdf.finish_section(&[0], &format!("<< /Size 5 /Root 1 0 R /{entry} {value} >>"));
+
+            let mut reader = Reader::new(Tokenizer::new(Cursor::new(pdf.bytes)));
+            let Err(message) = reader.read_xref_locations(startxref) else {
+                panic!("/{entry} {value} was accepted");
+            };
+            assert!(message.contains(&format!("/{entry}")), "{message}");
+        }
+    }
+
+    /// Rewrites the `startxref` at the end of the file to point at `wrong_offset`
+    fn point_startxref_at(pdf: &mut TestPdf, startxref: u64, wrong_offset: u64) {
+        let tail = format!("startxref\n{startxref}\n%%EOF\n");
+        pdf.bytes.truncate(pdf.bytes.len() - tail.len());
+        pdf.raw(format!("startxref\n{wrong_offset}\n%%EOF\n").as_bytes());
+    }
+
+    #[test]
+    fn a_wrong_startxref_is_recovered_by_scanning_for_objects() {
+        let mut pdf = TestPdf::single_page("Old");
+        let first_xref = pdf.finish_section(&[0], "<< /Size 5 /Root 1 0 R >>");
+        pdf.stream(4, "", b"BT (New) Tj ET");
+        let startxref = pdf.finish_section(&[], &format!("<< /Size 5 /Root 1 0 R /Prev {first_xref} >>"));
+        // Lands on the header of object 1 rather than an xref section
+        point_startxref_at(&mut pdf, startxref, 9);
+
+        let (pdf, page) = PDF::open_first_page(Cursor::new(pdf.bytes)).unwrap();
+        assert_eq!(page.extract_text(&pdf).unwrap(), "New\n");
+    }
+
+    #[test]
+    fn a_wrong_startxref_still_finds_compressed_objects_and_the_root() {
+        let mut pdf = TestPdf::new();
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.stream(4, "", b"BT (Packed) Tj ET");
+        pdf.object_stream(5, &[(1, "<< /Type /Catalog /Pages 2 0 R >>"), (3, "<< /Type /Page /Parent 2 0 R /Contents 4 0 R >>")]);
+        // A later catalog that /Root does not name, which must not be taken instead
+        pdf.object(7, "<< /Type /Catalog /Pages 8 0 R >>");
+        pdf.object(8, "<< /Type /Pages /Kids [9 0 R] /Count 1 >>");
+        pdf.object(9, "<< /Type /Page /Parent 8 0 R /Contents 10 0 R >>");
+        pdf.stream(10, "", b"BT (Stray) Tj ET");
+        let startxref = pdf.finish_xref_stream(6, "/Root 1 0 R");
+        point_startxref_at(&mut pdf, startxref, 9);
+
+        let (lazy_pdf, page) = PDF::open_first_page(Cursor::new(pdf.bytes.clone())).unwrap();
+        assert_eq!(page.extract_text(&lazy_pdf).unwrap(), "Packed\n");
+        let document = Document::from_bytes(pdf.bytes).unwrap();
+        assert_eq!(document.extract_text().unwrap(), "Packed\n");
+    }
+
+    #[test]
+    fn rebuilding_ignores_the_xref_and_finds_the_catalog_by_type() {
+        // No trailer names the root, and the xref table is garbage
+        let mut pdf = TestPdf::single_page("Old");
+        pdf.object(5, "<< /Broken ) >>");
+        pdf.stream(4, "", b"BT (New) Tj ET");
+        pdf.raw(b"xref\n0 5\nnot an xref table at all\nstartxref\n12345\n%%EOF\n");
+
+        let mut reader = Reader::new(Tokenizer::new(Cursor::new(pdf.bytes)));
+        reader.read_with_rebuild().unwrap();
+        let pdf = reader.into_pdf();
+        assert_eq!(pdf.pages.len(), 1);
+        assert_eq!(pdf.pages[0].extract_text(&pdf).unwrap(), "New\n");
+        assert_eq!(
+            pdf.parse_errors.iter().map(|(header, _)| header.object_number).collect::<Vec<_>>(),
+            vec![5]
+        );
+    }
+}
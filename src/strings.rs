@@ -0,0 +1,61 @@
+/// PDFDocEncoding code points that differ from Latin-1 (Annex D.2); bytes 0x9F and
+/// 0xAD are undefined there and fall back to Latin-1 like the rest
+fn pdf_doc_encoding(byte: u8) -> char {
+    match byte {
+        0x18 => '\u{02D8}', 0x19 => '\u{02C7}', 0x1A => '\u{02C6}', 0x1B => '\u{02D9}',
+        0x1C => '\u{02DD}', 0x1D => '\u{02DB}', 0x1E => '\u{02DA}', 0x1F => '\u{02DC}',
+        0x80 => '\u{2022}', 0x81 => '\u{2020}', 0x82 => '\u{2021}', 0x83 => '\u{2026}',
+        0x84 => '\u{2014}', 0x85 => '\u{2013}', 0x86 => '\u{0192}', 0x87 => '\u{2044}',
+        0x88 => '\u{2039}', 0x89 => '\u{203A}', 0x8A => '\u{2212}', 0x8B => '\u{2030}',
+        0x8C => '\u{201E}', 0x8D => '\u{201C}', 0x8E => '\u{201D}', 0x8F => '\u{2018}',
+        0x90 => '\u{2019}', 0x91 => '\u{201A}', 0x92 => '\u{2122}', 0x93 => '\u{FB01}',
+        0x94 => '\u{FB02}', 0x95 => '\u{0141}', 0x96 => '\u{0152}', 0x97 => '\u{0160}',
+        0x98 => '\u{0178}', 0x99 => '\u{017D}', 0x9A => '\u{0131}', 0x9B => '\u{0142}',
+        0x9C => '\u{0153}', 0x9D => '\u{0161}', 0x9E => '\u{017E}', 0xA0 => '\u{20AC}',
+        other => other as char
+    }
+}
+
+/// Decodes a text string (7.9.2.2), whether it came from a literal or a hex string:
+/// UTF-16BE when it starts with a byte order mark, UTF-8 with its PDF 2.0 marker,
+/// and PDFDocEncoding otherwise
+pub fn decode_pdf_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = utf16
+            .chunks(2)
+            .map(|pair| (pair[0] as u16) << 8 | pair.get(1).copied().unwrap_or(0) as u16)
+            .collect();
+        return String::from_utf16_lossy(&units);
+    }
+
+    if let Some(utf8) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return String::from_utf8_lossy(utf8).to_string();
+    }
+
+    bytes.iter().map(|byte| pdf_doc_encoding(*byte)).collect()
+}
+
+/// The tokenizer reads strings one byte per char, so this recovers the original bytes
+pub fn string_bytes(string: &str) -> Vec<u8> {
+    string.chars().map(|character| character as u32 as u8).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf16_with_a_byte_order_mark() {
+        assert_eq!(decode_pdf_string(&[0xFE, 0xFF, 0x00, 0x48, 0x00, 0xE9, 0x20, 0xAC]), "Hé€");
+    }
+
+    #[test]
+    fn utf8_with_its_marker() {
+        assert_eq!(decode_pdf_string("\u{FEFF}Grüße".as_bytes()), "Grüße");
+    }
+
+    #[test]
+    fn pdf_doc_encoding_without_a_byte_order_mark() {
+        assert_eq!(decode_pdf_string(b"Caf\xE9 \x93 \x80 \xA0"), "Café ﬁ • €");
+    }
+}
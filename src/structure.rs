@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use crate::pdf::{PDF, PDFDictionary, PDFValue};
+use crate::tokenizer::PDFObjectHeader;
+
+/// One step of the logical reading order given by the structure tree (14.7)
+#[derive(Debug, Clone, PartialEq)]
+pub enum StructureItem {
+    /// A marked-content sequence on a page
+    Content { page: PDFObjectHeader, mcid: u64 },
+    /// The end of a block level element (a paragraph or heading)
+    BlockEnd
+}
+
+fn is_block_type(structure_type: &str) -> bool {
+    matches!(structure_type, "P" | "H" | "H1" | "H2" | "H3" | "H4" | "H5" | "H6")
+}
+
+/// Flattens the catalog's `/StructTreeRoot` into reading order. Custom structure types are
+/// mapped through the `/RoleMap` before deciding whether they end a block.
+pub fn reading_order(pdf: &PDF) -> Vec<StructureItem> {
+    let root = match pdf.catalog()
+        .and_then(|catalog| catalog.get("StructTreeRoot"))
+        .and_then(|root| root.as_dictionary(pdf).ok()) {
+        Some(root) => root,
+        None => return vec![]
+    };
+
+    let role_map = root.get("RoleMap").and_then(|role_map| role_map.as_dictionary(pdf).ok());
+
+    let mut items = vec![];
+    if let Some(kids) = root.get("K") {
+        walk(pdf, kids, None, role_map, &mut items, &mut HashSet::new());
+    }
+    items
+}
+
+fn standard_type(pdf: &PDF, structure_type: &str, role_map: Option<&PDFDictionary>) -> String {
+    let mut structure_type = structure_type.to_string();
+    // Role maps may chain, but never needs more steps than it has entries
+    for _ in 0..role_map.map_or(0, |role_map| role_map.len()) {
+        match role_map.and_then(|role_map| role_map.get(&structure_type)).and_then(|mapped| mapped.as_name(pdf).ok()) {
+            Some(mapped) if *mapped != structure_type => structure_type = mapped.clone(),
+            _ => break
+        }
+    }
+    structure_type
+}
+
+fn walk(pdf: &PDF, node: &PDFValue, page: Option<PDFObjectHeader>, role_map: Option<&PDFDictionary>, items: &mut Vec<StructureItem>, visited: &mut HashSet<PDFObjectHeader>) {
+    if let PDFValue::ObjectReference(reference) = node {
+        if !visited.insert(*reference) {
+            return;
+        }
+    }
+
+    let node = match pdf.resolve(node) {
+        Ok(node) => node,
+        Err(_) => return
+    };
+
+    match node {
+        PDFValue::Number(mcid) => {
+            if let Some(page) = page {
+                items.push(StructureItem::Content { page, mcid: *mcid as u64 });
+            }
+        },
+        PDFValue::Array(kids) => {
+            for kid in kids {
+                walk(pdf, kid, page, role_map, items, visited);
+            }
+        },
+        PDFValue::Dictionary(element) => {
+            let page = match element.get("Pg") {
+                Some(PDFValue::ObjectReference(page)) => Some(*page),
+                _ => page
+            };
+
+            let element_type = element.get("Type").and_then(|element_type| element_type.as_name(pdf).ok());
+            match element_type.map(String::as_str) {
+                Some("MCR") => {
+                    let mcid = element.get("MCID").and_then(|mcid| mcid.as_number(pdf).ok());
+                    if let (Some(page), Some(mcid)) = (page, mcid) {
+                        items.push(StructureItem::Content { page, mcid: mcid as u64 });
+                    }
+                },
+                // Object references point at annotations and XObjects, which carry no MCIDs here
+                Some("OBJR") => {},
+                _ => {
+                    if let Some(kids) = element.get("K") {
+                        walk(pdf, kids, page, role_map, items, visited);
+                    }
+
+                    let structure_type = element.get("S").and_then(|structure_type| structure_type.as_name(pdf).ok());
+                    if structure_type.is_some_and(|structure_type| is_block_type(&standard_type(pdf, structure_type, role_map))) {
+                        items.push(StructureItem::BlockEnd);
+                    }
+                }
+            }
+        },
+        _ => {}
+    }
+}
@@ -0,0 +1,126 @@
+//! Builds small documents with correct xref offsets for the tests
+
+/// A document written one piece at a time, remembering where each object starts
+pub struct TestPdf {
+    pub bytes: Vec<u8>,
+    offsets: Vec<(u64, u64)>,
+    /// Object number, with the object stream holding it and its index there
+    compressed: Vec<(u64, u64, u64)>
+}
+
+impl TestPdf {
+    pub fn new() -> Self {
+        TestPdf { bytes: b"%PDF-1.5\n".to_vec(), offsets: vec![], compressed: vec![] }
+    }
+
+    /// A catalog (1), page tree (2), page (3) and content stream (4) showing `text`
+    pub fn single_page(text: &str) -> Self {
+        TestPdf::page("", format!("BT ({text}) Tj ET").as_bytes())
+    }
+
+    /// Like `single_page`, with the page's `/Resources` entries and its whole content stream
+    pub fn page(resources: &str, content: &[u8]) -> Self {
+        let mut pdf = TestPdf::new();
+        pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+        pdf.object(2, "<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        pdf.object(3, &format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Resources << {resources} >> /Contents 4 0 R >>"));
+        pdf.stream(4, "", content);
+        pdf
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn object(&mut self, object_number: u64, body: &str) {
+        self.offsets.push((object_number, self.offset()));
+        self.raw(format!("{object_number} 0 obj\n{body}\nendobj\n").as_bytes());
+    }
+
+    /// A stream object with `data` unfiltered; `dictionary` is any entries besides /Length
+    pub fn stream(&mut self, object_number: u64, dictionary: &str, data: &[u8]) {
+        self.offsets.push((object_number, self.offset()));
+        self.raw(format!("{object_number} 0 obj\n<< /Length {} {dictionary} >>\nstream\n", data.len()).as_bytes());
+        self.raw(data);
+        self.raw(b"\nendstream\nendobj\n");
+    }
+
+    /// An object stream (`/Type /ObjStm`) holding `objects`, unfiltered
+    pub fn object_stream(&mut self, object_number: u64, objects: &[(u64, &str)]) {
+        let mut index = String::new();
+        let mut bodies = String::new();
+        for (position, (number, body)) in objects.iter().enumerate() {
+            index.push_str(&format!("{number} {} ", bodies.len()));
+            bodies.push_str(body);
+            bodies.push('\n');
+            self.compressed.push((*number, object_number, position as u64));
+        }
+        let dictionary = format!("/Type /ObjStm /N {} /First {}", objects.len(), index.len());
+        self.stream(object_number, &dictionary, format!("{index}{bodies}").as_bytes());
+    }
+
+    /// Ends the file with a cross-reference stream, numbered `object_number`, covering every
+    /// object and object stream written so far. `trailer` holds the extra dictionary entries
+    /// such as `/Root`.
+    pub fn finish_xref_stream(&mut self, object_number: u64, trailer: &str) -> u64 {
+        let xref_offset = self.offset();
+        let mut entries: Vec<(u64, [u64; 3])> = self.offsets.drain(..).map(|(number, offset)| (number, [1, offset, 0])).collect();
+        entries.extend(self.compressed.drain(..).map(|(number, stream, index)| (number, [2, stream, index])));
+        entries.push((object_number, [1, xref_offset, 0]));
+
+        let size = entries.iter().map(|(number, _)| number + 1).max().unwrap_or(1);
+        let mut rows = vec![[0, 0, 65535]; size as usize];
+        for (number, row) in entries {
+            rows[number as usize] = row;
+        }
+        let data: Vec<u8> = rows
+            .iter()
+            .flat_map(|[kind, field, generation]| {
+                let mut row = vec![*kind as u8];
+                row.extend_from_slice(&(*field as u32).to_be_bytes());
+                row.extend_from_slice(&(*generation as u16).to_be_bytes());
+                row
+            })
+            .collect();
+
+        self.stream(object_number, &format!("/Type /XRef /Size {size} /W [1 4 2] {trailer}"), &data);
+        self.offsets.clear();
+        self.raw(format!("startxref\n{xref_offset}\n%%EOF\n").as_bytes());
+        xref_offset
+    }
+
+    /// Ends a section: an xref table for the objects written since the last one, plus
+    /// `free` object numbers, split into subsections of consecutive numbers, then the
+    /// trailer and `%%EOF`. Returns the table's offset.
+    pub fn finish_section(&mut self, free: &[u64], trailer: &str) -> u64 {
+        let mut entries: Vec<(u64, Option<u64>)> = self.offsets.drain(..).map(|(number, offset)| (number, Some(offset))).collect();
+        entries.extend(free.iter().map(|number| (*number, None)));
+        entries.sort();
+
+        let xref_offset = self.offset();
+        let mut table = String::from("xref\n");
+        let mut subsections: Vec<Vec<(u64, Option<u64>)>> = vec![];
+        for entry in entries {
+            match subsections.last_mut() {
+                Some(subsection) if subsection.last().unwrap().0 + 1 == entry.0 => subsection.push(entry),
+                _ => subsections.push(vec![entry])
+            }
+        }
+        for subsection in subsections {
+            table.push_str(&format!("{} {}\n", subsection[0].0, subsection.len()));
+            for (_, offset) in subsection {
+                match offset {
+                    Some(offset) => table.push_str(&format!("{offset:010} 00000 n \n")),
+                    None => table.push_str("0000000000 65535 f \n")
+                }
+            }
+        }
+        table.push_str(&format!("trailer\n{trailer}\nstartxref\n{xref_offset}\n%%EOF\n"));
+        self.raw(table.as_bytes());
+        xref_offset
+    }
+}
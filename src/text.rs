@@ -1,12 +1,282 @@
-use std::borrow::BorrowMut;
+use std::collections::{HashMap, HashSet};
 
-use crate::{content_stream_lexer::ContentToken, text};
+use log::{debug, warn};
+
+use crate::content_stream_lexer::{ContentToken, PropertyList, TextArrayElement};
+use crate::font::Font;
 
 #[derive(Debug, Clone)]
 pub struct PositionedText {
     pub text: String,
     pub x: f64,
-    pub y: f64
+    pub y: f64,
+    pub font_size: f64,
+    /// Marked content identifier of the innermost enclosing `/MCID` sequence, which ties the
+    /// text to an element of the structure tree
+    pub mcid: Option<u64>,
+    /// Text rendering mode (9.3.6); 3 is invisible, as used for the text layer of OCR'd scans
+    pub render_mode: i64,
+    /// Only filled in when `TextExtractionOptions::glyphs` is set
+    pub glyphs: Vec<Glyph>
+}
+
+/// A single glyph of a show-text operator, positioned in user space
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glyph {
+    /// Usually one character, but a ToUnicode map may turn one code into several (ligatures)
+    pub unicode: String,
+    pub x: f64,
+    pub y: f64,
+    pub advance: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct TextState {
+    pub font: Option<String>,
+    pub font_size: f64,
+    /// Baseline to baseline distance used by T*, set by TL and TD
+    pub leading: f64,
+    /// Extra space after every glyph in unscaled text space units, set by Tc
+    pub char_spacing: f64,
+    /// Extra space after each single byte code 32, on top of `char_spacing`, set by Tw
+    pub word_spacing: f64,
+    /// Horizontal stretch as a percentage, set by Tz
+    pub horizontal_scaling: f64,
+    /// Baseline offset for superscripts and subscripts, set by Ts and cleared by BT
+    pub rise: f64,
+    /// Fill, stroke, clip or invisible, set by Tr
+    pub render_mode: i64
+}
+
+impl Default for TextState {
+    fn default() -> Self {
+        TextState {
+            font: None,
+            font_size: 0.0,
+            leading: 0.0,
+            char_spacing: 0.0,
+            word_spacing: 0.0,
+            horizontal_scaling: 100.0,
+            rise: 0.0,
+            render_mode: 0
+        }
+    }
+}
+
+impl TextState {
+    fn effective_font_size(&self, text_matrix: &[f64]) -> f64 {
+        if self.font_size != 0.0 {
+            return self.font_size;
+        }
+
+        // A Tf size of 0 is legal; the glyphs are then sized by the text matrix alone
+        (text_matrix[2] * text_matrix[2] + text_matrix[3] * text_matrix[3]).sqrt()
+    }
+
+    /// The size glyph widths are scaled by in text space; with a Tf size of 0 the text
+    /// matrix alone carries the scale
+    fn text_space_font_size(&self) -> f64 {
+        if self.font_size == 0.0 { 1.0 } else { self.font_size }
+    }
+}
+
+/// Page level information the text layer needs that is not in the content stream itself
+#[derive(Debug, Default)]
+pub struct TextResources {
+    /// `/Properties` names whose optional content (layer) is hidden by default
+    pub hidden_properties: HashSet<String>,
+    /// Form XObjects by their `/XObject` resource name
+    pub forms: HashMap<String, FormXObject>,
+    /// Fonts by their `/Font` resource name
+    pub fonts: HashMap<String, Font>
+}
+
+#[derive(Debug)]
+pub struct FormXObject {
+    pub tokens: Vec<ContentToken>,
+    pub matrix: Vec<f64>,
+    pub resources: TextResources
+}
+
+pub const IDENTITY_MATRIX: [f64; 6] = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Concatenates two transformation matrices in `[a b c d e f]` form, applying `first` then `second`
+pub fn multiply_matrices(first: &[f64], second: &[f64]) -> Vec<f64> {
+    vec![
+        first[0] * second[0] + first[1] * second[2],
+        first[0] * second[1] + first[1] * second[3],
+        first[2] * second[0] + first[3] * second[2],
+        first[2] * second[1] + first[3] * second[3],
+        first[4] * second[0] + first[5] * second[2] + second[4],
+        first[4] * second[1] + first[5] * second[3] + second[5]
+    ]
+}
+
+/// Maps default user space to the page as a viewer shows it: `rotation` degrees clockwise
+/// (a multiple of 90), with the origin at the lower left corner of the rotated `media_box`
+pub fn rotation_matrix(rotation: i64, media_box: &[f64]) -> Vec<f64> {
+    let (left, bottom, right, top) = (media_box[0].min(media_box[2]), media_box[1].min(media_box[3]), media_box[0].max(media_box[2]), media_box[1].max(media_box[3]));
+    let (width, height) = (right - left, top - bottom);
+    let to_origin = [1.0, 0.0, 0.0, 1.0, -left, -bottom];
+
+    let rotation = match rotation.rem_euclid(360) {
+        90 => [0.0, -1.0, 1.0, 0.0, 0.0, width],
+        180 => [-1.0, 0.0, 0.0, -1.0, width, height],
+        270 => [0.0, 1.0, -1.0, 0.0, height, 0.0],
+        _ => IDENTITY_MATRIX
+    };
+
+    multiply_matrices(&to_origin, &rotation)
+}
+
+fn transform_point(matrix: &[f64], x: f64, y: f64) -> (f64, f64) {
+    (
+        matrix[0] * x + matrix[2] * y + matrix[4],
+        matrix[1] * x + matrix[3] * y + matrix[5]
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextExtractionOptions {
+    /// Keep text from layers that are off in the document's default configuration
+    pub include_hidden_layers: bool,
+    /// Position every glyph individually, which needs the font's widths
+    pub glyphs: bool,
+    /// Recover text that malformed content streams show outside of `BT`/`ET`, as viewers do
+    pub lenient: bool,
+    /// Report positions on the page as displayed, turned by its `/Rotate`, rather than in
+    /// the content stream's default user space
+    pub apply_rotation: bool
+}
+
+/// Each character code of `text` with its advance along the text space x axis, before
+/// horizontal scaling: the glyph width at the font size, plus Tc, plus Tw for a single
+/// byte space (9.4.4)
+fn glyph_advances(text: &str, font: Option<&Font>, text_state: &TextState) -> Vec<(u32, f64)> {
+    let codes: Vec<u32> = match font {
+        Some(font) => font.codes(text),
+        None => text.chars().map(|code| code as u32).collect()
+    };
+
+    codes
+        .into_iter()
+        .map(|code| {
+            let width = font.map_or(0.0, |font| font.glyph_width(code));
+            let mut advance = width / 1000.0 * text_state.text_space_font_size() + text_state.char_spacing;
+            // Word spacing never applies to multi-byte codes, even when one happens to be 32
+            if code == 32 && font.map_or(1, |font| font.code_length()) == 1 {
+                advance += text_state.word_spacing;
+            }
+            (code, advance)
+        })
+        .collect()
+}
+
+/// Places each glyph by accumulating its advance along the text space x axis, then mapping
+/// the origins through the text matrix and CTM
+fn layout_glyphs(advances: &[(u32, f64)], font: Option<&Font>, text_state: &TextState, text_matrix: &[f64], ctm: &[f64]) -> Vec<Glyph> {
+    // Horizontal scaling stretches text space along x before the text matrix applies
+    let horizontal_scaling = [text_state.horizontal_scaling / 100.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+    let matrix = multiply_matrices(&multiply_matrices(&horizontal_scaling, text_matrix), ctm);
+    let scale = (matrix[0] * matrix[0] + matrix[1] * matrix[1]).sqrt();
+
+    let mut glyphs: Vec<Glyph> = vec![];
+    let mut text_x = 0.0;
+    for &(code, advance) in advances {
+        let unicode = match font {
+            Some(font) => font.code_to_unicode(code),
+            None => char::from_u32(code).map(String::from).unwrap_or_default()
+        };
+        let (x, y) = transform_point(&matrix, text_x, text_state.rise);
+        glyphs.push(Glyph { unicode, x, y, advance: advance * scale });
+        text_x += advance;
+    }
+
+    glyphs
+}
+
+/// Moves the text matrix `tx` along the text space x axis, as showing a glyph does
+fn translate_text_matrix(text_matrix: &mut Vec<f64>, tx: f64) {
+    *text_matrix = multiply_matrices(&[1.0, 0.0, 0.0, 1.0, tx, 0.0], text_matrix);
+}
+
+/// Positions and decodes the string of a show-text operator, then advances the text
+/// matrix past it so the next string starts where this one ended
+fn show_text(text: &str, text_matrix: &mut Vec<f64>, text_state: &TextState, resources: &TextResources, options: &TextExtractionOptions, ctm: &[f64], mcid: Option<u64>) -> PositionedText {
+    let (x, y) = transform_point(&multiply_matrices(text_matrix, ctm), 0.0, text_state.rise);
+
+    let font = text_state.font.as_ref().and_then(|font| resources.fonts.get(font));
+    let font_size = text_state.effective_font_size(text_matrix);
+
+    let advances = glyph_advances(text, font, text_state);
+    let glyphs = if options.glyphs {
+        layout_glyphs(&advances, font, text_state, text_matrix, ctm)
+    } else {
+        vec![]
+    };
+    let width: f64 = advances.iter().map(|(_, advance)| advance).sum();
+    translate_text_matrix(text_matrix, width * text_state.horizontal_scaling / 100.0);
+
+    let text = match font {
+        Some(font) => font.decode(text),
+        None => text.to_string()
+    };
+
+    PositionedText { text, x, y, font_size, mcid, render_mode: text_state.render_mode, glyphs }
+}
+
+/// A TJ adjustment that moves the next glyph right by more than this many thousandths of
+/// an em is taken as a word break
+const WORD_BREAK_ADJUSTMENT: f64 = 200.0;
+
+/// Shows the strings of Tj, TJ, ' or ". A TJ array's adjustments move the text matrix
+/// between its strings, and a gap wide enough to be a word break becomes a space.
+fn show_token(token: &ContentToken, text_matrix: &mut Vec<f64>, text_state: &TextState, resources: &TextResources, options: &TextExtractionOptions, ctm: &[f64], mcid: Option<u64>) -> Vec<PositionedText> {
+    let ContentToken::ShowTextArray(elements) = token else {
+        return vec![show_text(&shown_codes(token), text_matrix, text_state, resources, options, ctm, mcid)];
+    };
+
+    let mut shown: Vec<PositionedText> = vec![];
+    for element in elements {
+        match element {
+            TextArrayElement::Text(text) => shown.push(show_text(text, text_matrix, text_state, resources, options, ctm, mcid)),
+            TextArrayElement::Adjustment(adjustment) => {
+                let follows_text = shown.last().is_some_and(|text| !text.text.is_empty() && !text.text.ends_with(' '));
+                if -adjustment > WORD_BREAK_ADJUSTMENT && follows_text {
+                    let (x, y) = transform_point(&multiply_matrices(text_matrix, ctm), 0.0, text_state.rise);
+                    shown.push(PositionedText {
+                        text: " ".to_string(),
+                        x,
+                        y,
+                        font_size: text_state.effective_font_size(text_matrix),
+                        mcid,
+                        render_mode: text_state.render_mode,
+                        glyphs: vec![]
+                    });
+                }
+                let tx = -adjustment / 1000.0 * text_state.text_space_font_size() * text_state.horizontal_scaling / 100.0;
+                translate_text_matrix(text_matrix, tx);
+            }
+        }
+    }
+
+    shown
+}
+
+/// The character codes shown by Tj, TJ, ' or "; a TJ array's strings are shown back to back
+fn shown_codes(token: &ContentToken) -> String {
+    match token {
+        ContentToken::ShowTextString(text) | ContentToken::NextLineShowText(text) |
+        ContentToken::NextLineShowTextSpacing { text, .. } => text.clone(),
+        ContentToken::ShowTextArray(elements) => elements
+            .iter()
+            .filter_map(|element| match element {
+                TextArrayElement::Text(text) => Some(text.as_str()),
+                TextArrayElement::Adjustment(_) => None
+            })
+            .collect(),
+        _ => String::new()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -14,11 +284,33 @@ pub struct TextObjectContent {
     pub positioned_text: Vec<PositionedText>
 }
 
-pub fn get_text_objects(tokens:  &Vec<ContentToken>) -> Vec<TextObjectContent> {
+pub fn get_text_objects(tokens: &[ContentToken], resources: &TextResources, options: &TextExtractionOptions) -> Vec<TextObjectContent> {
+    collect_text_objects(tokens, resources, options, IDENTITY_MATRIX.to_vec())
+}
+
+/// Like `get_text_objects`, with `ctm` as the initial transformation matrix instead of the
+/// identity, for example a page's `rotation_matrix`
+pub fn get_text_objects_with_ctm(tokens: &[ContentToken], resources: &TextResources, options: &TextExtractionOptions, ctm: &[f64]) -> Vec<TextObjectContent> {
+    collect_text_objects(tokens, resources, options, ctm.to_vec())
+}
+
+fn collect_text_objects(tokens: &[ContentToken], resources: &TextResources, options: &TextExtractionOptions, ctm: Vec<f64>) -> Vec<TextObjectContent> {
     let mut token_iter = tokens.iter();
 
+    let mut ctm = ctm;
+    let mut ctm_stack: Vec<Vec<f64>> = vec![];
+
     let mut in_text_object = false;
     let mut text_matrix: Option<Vec<f64>> = None;
+    // Start of the current line, which Td moves relative to (9.4.2)
+    let mut text_line_matrix: Option<Vec<f64>> = None;
+    let mut text_state = TextState::default();
+    // Fonts already warned about, so a page full of text in one missing font logs once
+    let mut missing_fonts: HashSet<String> = HashSet::new();
+    // One entry per open marked-content sequence, true when it belongs to a hidden layer
+    let mut marked_content_hidden: Vec<bool> = vec![];
+    // Parallel to marked_content_hidden, with the sequence's MCID if it has one
+    let mut marked_content_ids: Vec<Option<u64>> = vec![];
 
     let mut text_objects: Vec<TextObjectContent> = vec![];
     let mut current_text_object = TextObjectContent {
@@ -32,73 +324,322 @@ pub fn get_text_objects(tokens:  &Vec<ContentToken>) -> Vec<TextObjectContent> {
         }
         let token = token.unwrap();
 
+        match token {
+            ContentToken::BeginMarkedContent(_) => {
+                marked_content_hidden.push(false);
+                marked_content_ids.push(None);
+                continue;
+            },
+            ContentToken::BeginMarkedContentWithProperties((tag, properties)) => {
+                let hidden = match properties {
+                    PropertyList::Named(name) => tag == "OC" && resources.hidden_properties.contains(name),
+                    PropertyList::Inline(_) => false
+                };
+                let mcid = match properties {
+                    PropertyList::Inline(entries) => entries.iter().find(|(key, _)| key == "MCID").map(|(_, mcid)| *mcid),
+                    PropertyList::Named(_) => None
+                };
+                marked_content_hidden.push(hidden);
+                marked_content_ids.push(mcid);
+                continue;
+            },
+            ContentToken::EndMarkedContent => {
+                marked_content_hidden.pop();
+                marked_content_ids.pop();
+                continue;
+            },
+            // Text state set outside BT is only used by the lenient recovery below
+            ContentToken::SetTextMatrix(matrix) => {
+                text_matrix = Some(matrix.clone());
+                text_line_matrix = Some(matrix.clone());
+                continue;
+            },
+            ContentToken::MoveTextPosition(_) | ContentToken::MoveTextPositionSetLeading(_) | ContentToken::NextLine |
+            ContentToken::NextLineShowText(_) | ContentToken::NextLineShowTextSpacing { .. } => {
+                if let ContentToken::NextLineShowTextSpacing { aw, ac, .. } = token {
+                    text_state.word_spacing = *aw;
+                    text_state.char_spacing = *ac;
+                }
+                let (tx, ty) = match token {
+                    ContentToken::MoveTextPosition(offset) => *offset,
+                    ContentToken::MoveTextPositionSetLeading((tx, ty)) => {
+                        text_state.leading = -ty;
+                        (*tx, *ty)
+                    },
+                    // T* (and the move of ' and ") is the same as 0 -leading Td
+                    _ => (0.0, -text_state.leading)
+                };
+                let line_matrix = multiply_matrices(&[1.0, 0.0, 0.0, 1.0, tx, ty], text_line_matrix.as_deref().unwrap_or(&IDENTITY_MATRIX));
+                text_matrix = Some(line_matrix.clone());
+                text_line_matrix = Some(line_matrix);
+                // ' and " go on to show their string below, like Tj
+                if !matches!(token, ContentToken::NextLineShowText(_) | ContentToken::NextLineShowTextSpacing { .. }) {
+                    continue;
+                }
+            },
+            ContentToken::TextFont((font, font_size)) => {
+                if !resources.fonts.contains_key(font) && missing_fonts.insert(font.clone()) {
+                    warn!("Font {font} not found in resources, falling back to identity encoding");
+                }
+                text_state.font = Some(font.clone());
+                text_state.font_size = *font_size;
+                continue;
+            },
+            ContentToken::SetCharSpacing(char_spacing) => {
+                text_state.char_spacing = *char_spacing;
+                continue;
+            },
+            ContentToken::SetWordSpacing(word_spacing) => {
+                text_state.word_spacing = *word_spacing;
+                continue;
+            },
+            ContentToken::SetHorizontalScaling(horizontal_scaling) => {
+                text_state.horizontal_scaling = *horizontal_scaling;
+                continue;
+            },
+            ContentToken::SetTextLeading(leading) => {
+                text_state.leading = *leading;
+                continue;
+            },
+            ContentToken::SetTextRise(rise) => {
+                text_state.rise = *rise;
+                continue;
+            },
+            ContentToken::SetTextRenderMode(render_mode) => {
+                text_state.render_mode = *render_mode;
+                continue;
+            },
+            // The graphics state is tracked the same way inside and outside a text object
+            ContentToken::Cm(matrix) => {
+                ctm = multiply_matrices(matrix, &ctm);
+                continue;
+            },
+            ContentToken::SaveGraphicsState => {
+                ctm_stack.push(ctm.clone());
+                continue;
+            },
+            ContentToken::RestoreGraphicsState => {
+                if let Some(saved_ctm) = ctm_stack.pop() {
+                    ctm = saved_ctm;
+                }
+                continue;
+            },
+            ContentToken::PaintXObject(name) => {
+                if !options.include_hidden_layers && marked_content_hidden.contains(&true) {
+                    continue;
+                }
+
+                // Forms are resolved ahead of time without cycles, so this recursion terminates
+                if let Some(form) = resources.forms.get(name) {
+                    let form_ctm = multiply_matrices(&form.matrix, &ctm);
+                    text_objects.extend(collect_text_objects(&form.tokens, &form.resources, options, form_ctm));
+                }
+                continue;
+            },
+            _ => {}
+        }
+
         if in_text_object {
             match token {
                 ContentToken::BeginTextObject => {
-                    panic!("Unhandled nested text object");
+                    // Text objects cannot nest, so a second BT ends the open one first
+                    debug!("BT inside a text object, closing the open one");
+                    text_objects.push(std::mem::replace(&mut current_text_object, TextObjectContent { positioned_text: vec![] }));
+                    text_state.rise = 0.0;
+                    text_matrix = Some(IDENTITY_MATRIX.to_vec());
+                    text_line_matrix = Some(IDENTITY_MATRIX.to_vec());
                 },
                 ContentToken::EndTextObject => {
                     in_text_object = false;
-                    //TODO: This clone is bad :(
-                    text_objects.push(current_text_object.clone());
-                },
-                ContentToken::SetTextMatrix(matrix) => {
-                    text_matrix = Some(matrix.clone());
+                    text_objects.push(std::mem::replace(&mut current_text_object, TextObjectContent { positioned_text: vec![] }));
                 },
-                ContentToken::TextFont(_) => {},
-                ContentToken::ShowTextString(text) => {
-                    let mut x: f64 = 0.0;
-                    let mut y: f64 = 0.0;
-
-                    if text_matrix.is_none() {
-                        panic!("No text matrix set");
+                ContentToken::ShowTextString(_) | ContentToken::ShowTextArray(_) |
+                ContentToken::NextLineShowText(_) | ContentToken::NextLineShowTextSpacing { .. } => {
+                    if !options.include_hidden_layers && marked_content_hidden.contains(&true) {
+                        continue;
                     }
 
-                    let matrix = text_matrix.clone().unwrap();
-                    if matrix.len() == 6 {
-                        x = matrix[4];
-                        y = matrix[5];
-                    } else {
-                        panic!("Unexpected text matrix length: {}", matrix.len());
-                    }
-
-                    current_text_object.positioned_text.push(PositionedText {
-                        text: text.clone(), x, y
-                    })
-                },
-                unhandled_token => {
-                    panic!("Unhandled token in text object {:?}", unhandled_token);
+                    // BT always sets the matrix, so this only guards against a bad Tm
+                    let mut matrix = text_matrix
+                        .clone()
+                        .filter(|matrix| matrix.len() == 6)
+                        .unwrap_or_else(|| IDENTITY_MATRIX.to_vec());
+                    let mcid = marked_content_ids.iter().rev().find_map(|mcid| *mcid);
+                    current_text_object.positioned_text.extend(
+                        show_token(token, &mut matrix, &text_state, resources, options, &ctm, mcid)
+                    );
+                    text_matrix = Some(matrix);
                 },
+                // Colour, paths, clipping, inline images and anything unknown do not affect
+                // the text
+                other => {
+                    debug!("Skipping {:?} in text object", other);
+                }
             }
         } else {
             match token {
                 ContentToken::BeginTextObject => {
                     in_text_object = true;
+                    text_state.rise = 0.0;
+                    text_matrix = Some(IDENTITY_MATRIX.to_vec());
+                    text_line_matrix = Some(IDENTITY_MATRIX.to_vec());
                     current_text_object = TextObjectContent {
                         positioned_text: vec![]
                     };
                 },
-                ContentToken::ShowTextString(text) => {
-                    println!("\n\nGOT NON OBJECT TEXT: {}\n\n", text);
+                ContentToken::EndTextObject => {
+                    debug!("Ignoring ET without a matching BT");
                 },
-                _ => {
-                    println!("{:?}", token);
-                }
+                ContentToken::ShowTextString(_) | ContentToken::ShowTextArray(_) |
+                ContentToken::NextLineShowText(_) | ContentToken::NextLineShowTextSpacing { .. } => {
+                    let text = shown_codes(token);
+                    if !options.lenient {
+                        debug!("Dropping text shown outside a text object: {text}");
+                        continue;
+                    }
+                    if !options.include_hidden_layers && marked_content_hidden.contains(&true) {
+                        continue;
+                    }
+
+                    // Viewers draw it with whatever text state was last set, so give it an
+                    // implicit text object of its own
+                    let mut matrix = text_matrix
+                        .clone()
+                        .filter(|matrix| matrix.len() == 6)
+                        .unwrap_or_else(|| IDENTITY_MATRIX.to_vec());
+                    let mcid = marked_content_ids.iter().rev().find_map(|mcid| *mcid);
+                    text_objects.push(TextObjectContent {
+                        positioned_text: show_token(token, &mut matrix, &text_state, resources, options, &ctm, mcid)
+                    });
+                    text_matrix = Some(matrix);
+                },
+                _ => {}
             }
         }
     }
 
-    // print!("{:?}", text_objects);
+    // A stream that ends inside BT still showed its text
+    if in_text_object {
+        text_objects.push(current_text_object);
+    }
 
     text_objects
 }
 
 
-pub fn compile_grouped_text(object_contents: &[TextObjectContent]) {
+pub fn compile_grouped_text(object_contents: &[TextObjectContent]) -> String {
+    let mut text = String::new();
     for content in object_contents {
-        for text in &content.positioned_text {
-            print!("{}", text.text);
+        for positioned_text in &content.positioned_text {
+            text.push_str(&positioned_text.text);
         }
-        println!();
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_stream_lexer::parse;
+
+    /// Each text object's strings, with where the first one was shown
+    fn text_objects(content: &[u8]) -> Vec<(String, f64, f64)> {
+        let tokens = parse(content).unwrap();
+        get_text_objects(&tokens, &TextResources::default(), &TextExtractionOptions::default())
+            .iter()
+            .map(|object| {
+                let text = object.positioned_text.iter().map(|text| text.text.as_str()).collect();
+                let (x, y) = object.positioned_text.first().map_or((0.0, 0.0), |text| (text.x, text.y));
+                (text, x, y)
+            })
+            .collect()
+    }
+
+    fn positioned_text(content: &[u8], resources: &TextResources, options: &TextExtractionOptions) -> Vec<PositionedText> {
+        let tokens = parse(content).unwrap();
+        get_text_objects(&tokens, resources, options)
+            .into_iter()
+            .flat_map(|object| object.positioned_text)
+            .collect()
+    }
+
+    #[test]
+    fn zero_font_size_comes_from_the_text_matrix() {
+        let text = positioned_text(b"BT /F1 0 Tf 12 0 0 12 10 20 Tm (A) Tj ET", &TextResources::default(), &TextExtractionOptions::default());
+        assert_eq!(text[0].font_size, 12.0);
+    }
+
+    #[test]
+    fn text_in_a_missing_font_keeps_its_codes() {
+        let text = positioned_text(b"BT /Missing 10 Tf (Hi) Tj ET", &TextResources::default(), &TextExtractionOptions::default());
+        assert_eq!(text[0].text, "Hi");
+        assert_eq!(text[0].font_size, 10.0);
+    }
+
+    #[test]
+    fn text_outside_bt_is_recovered_in_lenient_mode() {
+        let content = b"1 0 0 1 30 40 Tm (Loose) Tj BT (Inside) Tj ET";
+        let lenient = TextExtractionOptions { lenient: true, ..Default::default() };
+
+        let text = positioned_text(content, &TextResources::default(), &lenient);
+        assert_eq!(
+            text.iter().map(|text| (text.text.as_str(), text.x, text.y)).collect::<Vec<_>>(),
+            vec![("Loose", 30.0, 40.0), ("Inside", 0.0, 0.0)]
+        );
+
+        let strict = positioned_text(content, &TextResources::default(), &TextExtractionOptions::default());
+        assert_eq!(strict.iter().map(|text| text.text.as_str()).collect::<Vec<_>>(), vec!["Inside"]);
+    }
+
+    #[test]
+    fn tj_adjustments_move_the_text_and_wide_gaps_break_words() {
+        // No font, so only the adjustments move the text: 1000 thousandths of 10 points is 10
+        let text = positioned_text(b"BT /F1 10 Tf 1 0 0 1 100 50 Tm [(A) -1000 (B) -50 (C) 300 (D)] TJ (E) Tj ET", &TextResources::default(), &TextExtractionOptions::default());
+        assert_eq!(
+            text.iter().map(|text| (text.text.as_str(), text.x)).collect::<Vec<_>>(),
+            vec![("A", 100.0), (" ", 100.0), ("B", 110.0), ("C", 110.5), ("D", 107.5), ("E", 107.5)]
+        );
+        assert_eq!(text_objects(b"BT [(A) -1000 (B)] TJ ET")[0].0, "A B");
+    }
+
+    #[test]
+    fn stray_et_is_ignored() {
+        assert_eq!(
+            text_objects(b"ET BT (One) Tj ET ET"),
+            vec![("One".to_string(), 0.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn text_object_without_a_final_et_is_kept() {
+        assert_eq!(
+            text_objects(b"BT (One) Tj ET BT 1 0 0 1 5 5 Tm (Two) Tj"),
+            vec![("One".to_string(), 0.0, 0.0), ("Two".to_string(), 5.0, 5.0)]
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nested_bt_closes_the_open_text_object() {
+        assert_eq!(
+            text_objects(b"BT 1 0 0 1 10 20 Tm (One) Tj BT (Two) Tj ET"),
+            vec![("One".to_string(), 10.0, 20.0), ("Two".to_string(), 0.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn paths_and_clipping_inside_bt_are_skipped() {
+        assert_eq!(
+            text_objects(b"BT 0 0 m 10 10 l S 0 0 5 5 re W n /Im1 Do (Text) Tj ET"),
+            vec![("Text".to_string(), 0.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn graphics_state_inside_bt_moves_the_text() {
+        assert_eq!(
+            text_objects(b"BT 1 0 0 1 10 20 Tm q 2 0 0 2 0 0 cm (A) Tj Q ET BT 1 0 0 1 10 20 Tm (B) Tj ET"),
+            vec![("A".to_string(), 20.0, 40.0), ("B".to_string(), 10.0, 20.0)]
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use std::{io::{prelude::*, SeekFrom}, str::FromStr};
+use std::io::{prelude::*, BufReader, SeekFrom};
+use std::sync::LazyLock;
 use regex::Regex;
 use log::{debug};
 
@@ -9,37 +10,43 @@ use log::{debug};
  */
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PDFObjectHeader {
     pub object_number: u64,
     pub generation_number: u64,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefSimpleEntry {
     pub byte_offset: u64,
     pub generation_number: u64,
     pub free: bool
 }
 
- #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefStreamFreeObject {
     pub object_number_of_next_free_object: u64,
     pub generation_number_for_next_object_use: u64
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefStreamCompressedObject {
     pub object_number_of_parent_stream: u64,
     pub index_in_stream: u64
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefStreamUncompressedObject {
     pub byte_offset: u64,
     pub generation_number: u64
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum XRefEntry {
     Free(XRefStreamFreeObject),
     Uncompressed(XRefStreamUncompressedObject),
@@ -48,18 +55,20 @@ pub enum XRefEntry {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefHeader {
     pub first_object_number: u64,
     pub num_entries: u64
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct XRefSection {
     pub header: Option<XRefHeader>,
     pub entries: Vec<XRefEntry>
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum PDFToken {
     Comment(String),
     ObjectHeader(PDFObjectHeader),
@@ -98,39 +107,39 @@ pub trait PDFTokenPatterns {
     fn is_object(&self) -> bool;
 }
 
+// Compiled on first use rather than on every call, since these run for nearly every token
+static POSITIVE_INT_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d+$").unwrap());
+static OBJECT_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d+\s\d+\sobj$").unwrap());
+static INT_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^-?\d+$").unwrap());
+static FLOAT_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^-?\d+(\.\d+)?$").unwrap());
+static VERSION_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^PDF-(\d\.\d)$").unwrap());
+static NAME_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"/.+").unwrap());
+/// An `N G obj` header anywhere in raw file bytes, capturing the object and generation numbers
+static OBJECT_HEADER_BYTES_PATTERN: LazyLock<regex::bytes::Regex> = LazyLock::new(|| regex::bytes::Regex::new(r"(\d+)\s+(\d+)\s+obj\b").unwrap());
+
 impl PDFTokenPatterns for String {
     fn is_positive_int(&self) -> bool {
-        let positive_int_pattern: Regex = Regex::new(r"^\d+$").unwrap();
-        positive_int_pattern.is_match(self.as_str())
+        POSITIVE_INT_PATTERN.is_match(self.as_str())
     }
 
     fn is_object(&self) -> bool {
-        let object_pattern: Regex = Regex::new(r"^\d+\s\d+\sobj$").unwrap();
-        object_pattern.is_match(self.as_str())
+        OBJECT_PATTERN.is_match(self.as_str())
     }
 
     fn is_int(&self) -> bool {
-        Regex::new(r"^-?\d+$")
-            .unwrap()
-            .is_match(self.as_str())
+        INT_PATTERN.is_match(self.as_str())
     }
 
     fn is_float(&self) -> bool {
-        Regex::new(r"^-?\d+(\.\d+)?$")
-            .unwrap()
-            .is_match(self.as_str())
+        FLOAT_PATTERN.is_match(self.as_str())
     }
 
     fn is_version(&self) -> bool {
-        Regex::new(r"^PDF-(\d\.\d)$")
-            .unwrap()
-            .is_match(self.as_str())
+        VERSION_PATTERN.is_match(self.as_str())
     }
 
     fn is_name(&self) -> bool {
-        Regex::new(r"/.+")
-            .unwrap()
-            .is_match(self.as_str())
+        NAME_PATTERN.is_match(self.as_str())
     }
 
     fn is_comment(&self) -> bool {
@@ -160,76 +169,216 @@ pub struct Tokenizer<T: Read + Seek> {
 
 pub trait PDFTokenize {
     fn next(&mut self) -> Result<PDFToken, String>;
-    fn get_offset(&mut self) -> u64;
-    fn get_stream(&mut self, num_bytes: usize) -> Vec<u8>;
+    fn get_offset(&mut self) -> Result<u64, String>;
+    fn get_stream(&mut self, num_bytes: usize) -> Result<Vec<u8>, String>;
     fn peak_next(&mut self) -> Result<PDFToken, String>;
     fn peak_multiple(&mut self, num_tokens: u32) -> Result<Vec<PDFToken>, String>;
     fn get_xref_table(&mut self, num_entries: u64) -> Result<Vec<XRefEntry>, String>;
+    fn last_document_end(&mut self) -> Option<u64>;
+    fn resume(&mut self);
+    fn seek_to(&mut self, offset: u64) -> Result<(), String>;
+    fn last_startxref(&mut self) -> Option<u64>;
+    fn skip_object(&mut self, offset: u64) -> Result<(), String>;
+    fn scan_stream_length(&mut self) -> Option<usize>;
+    fn find_object_headers(&mut self) -> Vec<(PDFObjectHeader, u64)>;
+    fn find_last_trailer(&mut self) -> Option<u64>;
+}
+
+
+/// Parses a numeric token: an optional single sign, digits and at most one period, which
+/// may come last as in the sloppy `1.` some writers emit. Anything else, such as `1.2.3`
+/// or a doubled sign like `--5`, is rejected.
+fn parse_number(token: &str) -> Option<f64> {
+    let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+    if !digits.chars().any(|c| c.is_ascii_digit()) || !digits.chars().all(|c| c.is_ascii_digit() || c == '.') || digits.matches('.').count() > 1 {
+        return None;
+    }
+
+    let sign = if token.starts_with('-') { "-" } else { "" };
+    format!("{sign}{digits}").parse::<f64>().ok()
+}
+
+/// The characters that end a name: PDF whitespace and the delimiters (7.2.3)
+const NAME_TERMINATORS: [char; 16] = ['\0', '\t', '\n', '\x0C', '\r', ' ', '(', ')', '<', '>', '[', ']', '{', '}', '/', '%'];
+
+/// Replaces each `#` and two hex digits in a name with the byte they encode (7.3.5), so
+/// `A#42C` becomes `ABC`. A `#` without two hex digits after it is kept as it is, as
+/// written by PDF 1.1 producers.
+fn decode_name(raw_name: &str) -> String {
+    let mut name = String::with_capacity(raw_name.len());
+    let mut characters = raw_name.chars();
+    while let Some(character) = characters.next() {
+        if character == '#' {
+            let escape: String = characters.clone().take(2).collect();
+            if escape.len() == 2 && escape.chars().all(|digit| digit.is_ascii_hexdigit()) {
+                // Bytes map to chars one to one, as everywhere else in the tokenizer
+                name.push(char::from(u8::from_str_radix(&escape, 16).unwrap()));
+                characters.nth(1);
+                continue;
+            }
+        }
+        name.push(character);
+    }
+    name
 }
 
+impl<R: Read + Seek> Tokenizer<BufReader<R>> {
+    /// Tokenizes `reader` through a buffer. Use this for a `File` or anything else where
+    /// each read is a system call, as the tokenizer reads one byte at a time.
+    pub fn from_reader(reader: R) -> Self {
+        Tokenizer::new(BufReader::new(reader))
+    }
+}
 
 impl<T: Read + Seek> Tokenizer<T> {
+    /// Tokenizes `reader` as it is. Suited to in-memory input such as a `Cursor`; see
+    /// `from_reader` for files.
     pub fn new(reader: T) -> Self {
         Tokenizer {
             state_stack: vec![TokenizerState::Start],
-            reader: reader
+            reader
         }
     }
 
+    /// In the `Stream` state, moves past the data to its `endstream`, since stream data is
+    /// read with `get_stream` rather than tokenized. Does nothing in any other state.
+    fn skip_stream_data(&mut self) -> Result<(), String> {
+        if self.get_state()? == TokenizerState::Stream {
+            let length = self.scan_stream_length().ok_or("No endstream after the stream data")?;
+            self.get_stream(length)?;
+        }
+        Ok(())
+    }
+
+    /// Un-reads the last byte. Goes through `seek_relative` so a `BufReader` keeps its buffer.
+    fn step_back(&mut self) -> Result<(), String> {
+        self.reader.seek_relative(-1).map_err(|err| err.to_string())
+    }
+
+    /// Moves back to an `offset` saved a few bytes earlier, keeping any buffer like `step_back`
+    fn return_to(&mut self, offset: u64) -> Result<(), String> {
+        let current_offset = self.reader.stream_position().map_err(|err| err.to_string())?;
+        self.reader.seek_relative(offset as i64 - current_offset as i64).map_err(|err| err.to_string())
+    }
+
+    /// The entire input, read without moving the current position
+    fn read_whole_input(&mut self) -> Option<Vec<u8>> {
+        let offset_before_read = self.reader.stream_position().ok()?;
+        let mut bytes: Vec<u8> = vec![];
+        self.reader.seek(SeekFrom::Start(0)).ok()?;
+        self.reader.read_to_end(&mut bytes).ok()?;
+        self.reader.seek(SeekFrom::Start(offset_before_read)).ok()?;
+        Some(bytes)
+    }
+
+    /// The next byte as a char, or `None` at the end of the input. A read error is treated
+    /// like the end of the input, since either way nothing more can be tokenized.
     fn next_char(&mut self) -> Option<char> {
         let mut next_byte: [u8; 1] = [0];
-        match self.reader.read(&mut next_byte).unwrap() {
-            0 => None,
-            _ => Some(char::from_u32(next_byte[0].into()).unwrap())
+        match self.reader.read(&mut next_byte) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(char::from(next_byte[0]))
         }
     }
 
-    fn read_until(&mut self, until_chars: Vec<char>, seek_back: bool) -> String {
+    /// Like `next_char`, for callers that need more input: a truncated file is an error
+    fn expect_char(&mut self) -> Result<char, String> {
+        self.next_char().ok_or("Unexpected end of file".to_string())
+    }
+
+    fn read_until(&mut self, until_chars: Vec<char>, seek_back: bool) -> Result<String, String> {
         let mut result = String::new();
         while let Some(next_char) = self.next_char() {
             if until_chars.contains(&next_char) {
                 if seek_back {
-                    self.reader.seek(SeekFrom::Current(-1)).unwrap();
+                    self.step_back()?;
                 }
                 break;
             }
             result.push(next_char);
         }
-        result
+        Ok(result)
     }
 
-    fn read_number(&mut self) -> Result<f64, <f64 as FromStr>::Err> {
-        self.consume_whitespace();
-        self.read_until(vec![' ', '>', ']', '[', '/', '\n', '\r'], true).parse::<f64>()
+    fn read_number(&mut self) -> Result<f64, String> {
+        self.consume_whitespace()?;
+        let token = self.read_until(vec![' ', '>', ']', '[', '/', '\n', '\r'], true)?;
+        parse_number(&token).ok_or(format!("Invalid number '{token}'"))
     }
 
-    fn consume_whitespace(&mut self) {
-        loop {
-            match self.next_char().unwrap() {
-                ' ' | '\n' | '\r' => continue,
-                _ => {
-                    self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                    break;
+    /// Reads a non-negative integer such as an offset or object number straight into a u64,
+    /// since going through f64 loses precision past 2^53
+    fn read_u64_token(&mut self) -> Result<u64, String> {
+        self.consume_whitespace()?;
+        let token = self.read_until(vec![' ', '>', ']', '[', '/', '\n', '\r'], true)?;
+        token.parse::<u64>().map_err(|err| format!("Invalid integer '{token}': {err}"))
+    }
+
+    fn consume_whitespace(&mut self) -> Result<(), String> {
+        while let Some(next_char) = self.next_char() {
+            if !matches!(next_char, ' ' | '\n' | '\r') {
+                self.step_back()?;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `stream` keyword should be followed by CRLF or LF and then the data (7.3.8.1).
+    /// Skips that single end of line, tolerating a lone CR, trailing spaces before it, or no
+    /// end of line at all, without ever consuming bytes that belong to the data.
+    fn skip_stream_eol(&mut self) -> Result<(), String> {
+        let keyword_end = self.reader.stream_position().map_err(|err| err.to_string())?;
+
+        let mut next_char = self.next_char();
+        while next_char == Some(' ') {
+            next_char = self.next_char();
+        }
+
+        match next_char {
+            Some('\r') => {
+                if let Some(other) = self.next_char() {
+                    if other != '\n' {
+                        self.step_back()?;
+                    }
                 }
+            },
+            Some('\n') => {},
+            _ => {
+                self.return_to(keyword_end)?;
             }
         }
+        Ok(())
+    }
+
+    /// Reads a name after its `/`, up to but not including the whitespace or delimiter that
+    /// ends it, with any `#` escapes decoded
+    fn read_name(&mut self) -> Result<String, String> {
+        let name = self.read_until(NAME_TERMINATORS.to_vec(), true)?;
+        Ok(decode_name(&name))
     }
 
-    fn read_comment(&mut self) -> String {
+    /// Reads a comment after its `%`. A comment runs to the end of the line and, inside an
+    /// object, counts as whitespace (7.2.4).
+    fn read_comment(&mut self) -> Result<String, String> {
         self.read_until(vec!['\n','\r'], false)
     }
 
     fn read_n_chars(&mut self, num_chars: u32) -> String {
         let mut result = String::new();
+        // Stops short at the end of the file; callers compare against the expected keyword
         for _ in 0..num_chars {
-            result.push(self.next_char().unwrap());
+            match self.next_char() {
+                Some(next_char) => result.push(next_char),
+                None => break
+            }
         }
         result
     }
 
     fn read_object_header(&mut self) -> Result<PDFObjectHeader, String> {
-        let object_number = self.read_until(vec![' '], false).parse::<u64>().unwrap();
-        let generation_number = self.read_until(vec![' '], false).parse::<u64>().unwrap();
+        let object_number = self.read_until(vec![' '], false)?.parse::<u64>().map_err(|err| format!("Invalid object number: {err}"))?;
+        let generation_number = self.read_until(vec![' '], false)?.parse::<u64>().map_err(|err| format!("Invalid generation number: {err}"))?;
         
         match  self.read_n_chars(3).as_str() {
             "obj" => Ok(PDFObjectHeader {
@@ -241,21 +390,21 @@ impl<T: Read + Seek> Tokenizer<T> {
     }
 
     fn read_object_reference(&mut self) -> Result<PDFToken, String> {
-        let object_number = match self.read_until(vec![' '], false).parse::<u64>() {
+        let object_number = match self.read_until(vec![' '], false)?.parse::<u64>() {
             Ok(value) => value,
             Err(err) => {
                 return Err(err.to_string());
             }
         };
         
-        let generation_number = match self.read_until(vec![' '], false).parse::<u64>() {
+        let generation_number = match self.read_until(vec![' '], false)?.parse::<u64>() {
             Ok(value) => value,
             Err(err) => {
                 return Err(err.to_string());
             }
         };
 
-        match self.next_char().unwrap() {
+        match self.expect_char()? {
             'R' => {
                 Ok(PDFToken::ObjectReference(PDFObjectHeader {
                     object_number,
@@ -268,8 +417,8 @@ impl<T: Read + Seek> Tokenizer<T> {
         }
     }
 
-    fn get_state(&mut self) -> TokenizerState {
-        self.state_stack.last().unwrap().clone()
+    fn get_state(&mut self) -> Result<TokenizerState, String> {
+        self.state_stack.last().cloned().ok_or("Tokenizer state stack is empty".to_string())
     }
 
     fn push_state(&mut self, state: TokenizerState) {
@@ -277,15 +426,13 @@ impl<T: Read + Seek> Tokenizer<T> {
         self.state_stack.push(state);
     }
 
-    fn pop_state(&mut self) -> TokenizerState {
-        let popped_state = self.state_stack.pop().unwrap();
+    /// Leaves the current state. Unbalanced input, such as a `]` or `>>` with nothing open,
+    /// is an error rather than an empty stack.
+    fn pop_state(&mut self) -> Result<TokenizerState, String> {
+        let popped_state = self.state_stack.pop().ok_or("Tokenizer state stack is empty")?;
         debug!("Popping state: {:?}", popped_state);
-        if self.state_stack.is_empty() {
-            debug!("Current state: {:?}", self.state_stack.last());
-        } else {
-            debug!("Current state: {:?}", self.state_stack.last().unwrap());
-        }
-        popped_state
+        debug!("Current state: {:?}", self.state_stack.last());
+        Ok(popped_state)
     }
 
     fn read_literal_string(&mut self) -> Result<String, String> {
@@ -293,7 +440,7 @@ impl<T: Read + Seek> Tokenizer<T> {
         let mut literal_string = String::new();
 
         loop  {
-            let next_char = self.next_char().unwrap();
+            let next_char = self.expect_char()?;
             match next_char {
                 '(' => {
                     if !parenthesis_stack.is_empty() {
@@ -309,7 +456,7 @@ impl<T: Read + Seek> Tokenizer<T> {
                     literal_string.push(next_char);
                 },
                 '\\' => {
-                    let next_char = self.next_char().unwrap();
+                    let next_char = self.expect_char()?;
                     match next_char {
                         '\\' | '(' | ')' => {
                             literal_string.push(next_char);
@@ -334,10 +481,11 @@ impl<T: Read + Seek> Tokenizer<T> {
                         '0'..='9' => {
                             // Octal character code
                             let mut octal_string = next_char.to_string();
-                            octal_string.push(self.next_char().unwrap());
-                            octal_string.push(self.next_char().unwrap());
-                            let char_code = u32::from_str_radix(octal_string.as_str(), 8).unwrap();
-                            literal_string.push(char::from_u32(char_code).unwrap());
+                            octal_string.push(self.expect_char()?);
+                            octal_string.push(self.expect_char()?);
+                            let char_code = u8::from_str_radix(octal_string.as_str(), 8)
+                                .map_err(|err| format!("Invalid octal escape '\\{octal_string}' in literal string: {err}"))?;
+                            literal_string.push(char::from(char_code));
                         },
                         unhandled => {
                             return Err(format!("Unhandled escaped character '{unhandled}' in literal string"));
@@ -353,8 +501,16 @@ impl<T: Read + Seek> Tokenizer<T> {
         Ok(literal_string)
     }
 
+    /// Reads the rest of a hexadecimal string after its `<`, which may be empty or spread
+    /// over several lines, through the closing `>`
+    fn read_hex_string(&mut self) -> Result<Vec<u8>, String> {
+        let hex_string = self.read_until(vec!['>'], false)?;
+        self.hex_string_to_bytes(hex_string)
+    }
+
     fn hex_string_to_bytes(&mut self, hex_string: String) -> Result<Vec<u8>, String> {
-        let mut hex_string = hex_string;
+        // White-space inside a hexadecimal string is ignored, NUL included (7.2.3)
+        let mut hex_string: Vec<char> = hex_string.chars().filter(|digit| !digit.is_ascii_whitespace() && *digit != '\0').collect();
 
         if hex_string.len() % 2 == 1 {
             /*
@@ -367,11 +523,13 @@ impl<T: Read + Seek> Tokenizer<T> {
 
         let mut bytes: Vec<u8> = vec![];
 
-        let mut hex_string = hex_string.clone();
-        while !hex_string.is_empty() {
-            let mut hex_byte = hex_string.pop().unwrap().to_string();
-            hex_byte.push(hex_string.pop().unwrap());
-            bytes.push(u8::from_str_radix(hex_byte.as_str(), 16).unwrap())
+        // Paired up as chars, since a stray non-ASCII byte is two bytes long in a String
+        for pair in hex_string.chunks(2) {
+            let hex_byte: String = pair.iter().collect();
+            if !pair.iter().all(|digit| digit.is_ascii_hexdigit()) {
+                return Err(format!("Invalid hex string byte '{hex_byte}'"));
+            }
+            bytes.push(u8::from_str_radix(&hex_byte, 16).unwrap());
         }
 
         Ok(bytes)
@@ -381,13 +539,13 @@ impl<T: Read + Seek> Tokenizer<T> {
 impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
     fn next(&mut self) -> Result<PDFToken, String> {
 
-        let state = self.state_stack.last().expect("State stack is empty!").to_owned();
+        let state = self.state_stack.last().ok_or("Tokenizer state stack is empty")?.to_owned();
         loop {
             match state {
-                TokenizerState::Start => match self.next_char().unwrap() {
+                TokenizerState::Start => match self.expect_char()? {
                     ' ' | '\n' | '\r' => continue,
                     '%' => {
-                        let comment = self.read_comment().trim().to_string();
+                        let comment = self.read_comment()?.trim().to_string();
                         if comment == "%EOF" {
                             self.state_stack.pop();
                             self.state_stack.push(TokenizerState::DocumentEnd);
@@ -396,53 +554,57 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         return Ok(PDFToken::Comment(comment))
                     },
                     '1'..='9' => {
-                        self.pop_state();
+                        self.pop_state()?;
                         self.push_state(TokenizerState::Object);
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
+                        self.step_back()?;
                         return match self.read_object_header() {
                             Ok(object_header) => Ok(PDFToken::ObjectHeader(object_header)),
                             Err(err) => Err(err)
                         }
                     },
                     's' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![' ', '\n', '\r'], false).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![' ', '\n', '\r'], false)?.as_str() {
                             "startxref" => {
-                                let xref_offset = self.read_number().unwrap();
-                                return Ok(PDFToken::StartXRef(xref_offset as u64));
+                                let xref_offset = self.read_u64_token()?;
+                                return Ok(PDFToken::StartXRef(xref_offset));
                             }
-                            other => panic!("Found unexpected keyword '{other}' while reading object")
+                            other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                         }
                     },
                     'x' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![' ', '\n', '\r'], false).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![' ', '\n', '\r'], false)?.as_str() {
                             "xref" => {
                                 self.push_state(TokenizerState::XRefSection);
                                 return Ok(PDFToken::XRefSectionBegin);
                             }
-                            other => panic!("Found unexpected keyword '{other}' while reading object")
+                            other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                         }
                     },
                     't' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![' ', '\n', '\r'], false).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![' ', '\n', '\r'], false)?.as_str() {
                             "trailer" => {
                                 self.push_state(TokenizerState::Trailer);
                                 return Ok(PDFToken::TrailerBegin);
                             }
-                            other => panic!("Found unexpected keyword '{other}' while reading object")
+                            other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                         }
                     }
-                    unhandled_char => todo!("Top level char '{unhandled_char}' not handled")
+                    unhandled_char => return Err(format!("Top level char '{unhandled_char}' not handled"))
                 }
                 TokenizerState::DocumentEnd => {
                     return Err("End of document reached!".to_owned());
                 }
-                TokenizerState::Object => match self.next_char().unwrap() {
-                    ' ' | '\n' | '\r' => continue,
+                TokenizerState::Object => match self.expect_char()? {
+                    ' ' | '\t' | '\n' | '\x0C' | '\r' | '\0' => continue,
+                    '%' => {
+                        self.read_comment()?;
+                        continue;
+                    },
                     '<' => {
-                        let next = self.next_char().unwrap();
+                        let next = self.expect_char()?;
                         if next == '<' {
                             self.push_state(TokenizerState::DictionaryKey);
                             return Ok(PDFToken::DictionaryStart);
@@ -454,108 +616,128 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         return Ok(PDFToken::ArrayStart);
                     },
                     's' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![' ', '\n', '\r'], false).as_str() {
+                        self.step_back()?;
+                        // Read exactly the keyword: broken files may start the data right after it
+                        match self.read_n_chars(6).as_str() {
                             "stream" => {
-                                self.consume_whitespace();
+                                self.skip_stream_eol()?;
                                 self.push_state(TokenizerState::Stream);
                                 return Ok(PDFToken::StreamBegin);
                             }
-                            other => panic!("Found unexpected keyword '{other}' while reading object")
+                            other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                         }
                     },
                     'e' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![' ', '\n', '\r'], false).trim() {
+                        self.step_back()?;
+                        match self.read_until(vec![' ', '\n', '\r'], false)?.trim() {
                             "endobj" => {
-                                self.pop_state();
+                                self.pop_state()?;
                                 self.push_state(TokenizerState::Start);
                                 return Ok(PDFToken::ObjectEnd);
                             }
-                            other => panic!("Found unexpected keyword '{other}' while reading object")
+                            other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                         }
                     },
                     '(' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
+                        self.step_back()?;
                         return Ok(PDFToken::String(self.read_literal_string()?));
                     },
-                    unhandled_char => panic!("Unhandled char {unhandled_char} while looking for object")
+                    // A bare number object, such as the target of an indirect /Length
+                    '0'..='9' | '-' | '+' | '.' => {
+                        self.step_back()?;
+                        let offset: u64 = self.reader.stream_position().map_err(|err| err.to_string())?;
+                        let object_reference = self.read_object_reference();
+                        if object_reference.is_err() {
+                            self.return_to(offset)?;
+                            return Ok(PDFToken::Number(self.read_number()?));
+                        } else {
+                            return object_reference;
+                        }
+                    },
+                    unhandled_char => return Err(format!("Unhandled char {unhandled_char} while looking for object"))
                 },
-                TokenizerState::DictionaryKey => match self.next_char().unwrap() {
-                    ' ' | '\n' | '\r' => continue,
+                TokenizerState::DictionaryKey => match self.expect_char()? {
+                    ' ' | '\t' | '\n' | '\x0C' | '\r' | '\0' => continue,
+                    '%' => {
+                        self.read_comment()?;
+                        continue;
+                    },
                     '/' => {
-                        let name = self.read_until(vec![' ','/','<','[','(', '\r', '\n'], true);
+                        let name = self.read_name()?;
                         self.push_state(TokenizerState::DictionaryValue);
                         return Ok(PDFToken::Name(name));
                     },
                     '>' => {
-                        match self.next_char().unwrap() {
+                        match self.expect_char()? {
                             '>' => {
-                                self.pop_state();
-                                if self.state_stack.last().unwrap().clone() == TokenizerState::DictionaryValue {
-                                    // If this dictionary exists in another dictionary, then popping the 
-                                    self.pop_state();
+                                self.pop_state()?;
+                                // A dictionary that is itself a dictionary value also completes that value
+                                if self.state_stack.last() == Some(&TokenizerState::DictionaryValue) {
+                                    self.pop_state()?;
                                 }
                                 return Ok(PDFToken::DictionaryEnd)
                             },
                             other => return Err(format!("Found unexpected character '{other}' while parsing dictionary"))
                         }
                     },
-                    unhandled_char => panic!("Unhandled char '{unhandled_char}' while looking for dictionary key")
+                    unhandled_char => return Err(format!("Unhandled char '{unhandled_char}' while looking for dictionary key"))
                 },
-                TokenizerState::DictionaryValue => match self.next_char().unwrap() {
-                    ' ' | '\n' | '\r' => continue,
+                TokenizerState::DictionaryValue => match self.expect_char()? {
+                    ' ' | '\t' | '\n' | '\x0C' | '\r' | '\0' => continue,
+                    '%' => {
+                        self.read_comment()?;
+                        continue;
+                    },
                     '[' => {
-                        self.pop_state();
+                        self.pop_state()?;
                         self.push_state(TokenizerState::ListValue);
                         return Ok(PDFToken::ArrayStart);
                     },
                     '/' => {
-                        let name = self.read_until(vec![' ',']','/','\n', '>'], true);
-                        self.pop_state();
+                        let name = self.read_name()?;
+                        self.pop_state()?;
                         return Ok(PDFToken::Name(name));
                     },
                     't' | 'f' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec!['\n','/','>'], true).trim() {
+                        self.step_back()?;
+                        match self.read_until(vec!['\n','/','>'], true)?.trim() {
                             "true" => {
-                                self.pop_state();
+                                self.pop_state()?;
                                 return Ok(PDFToken::Boolean(true));
                             },
                             "false" => {
-                                self.pop_state();
+                                self.pop_state()?;
                                 return Ok(PDFToken::Boolean(false));
                             },
                             token => {
-                                panic!("Unexpected value '{token}' while parsing dictionary value")
+                                return Err(format!("Unexpected value '{token}' while parsing dictionary value"))
                             }
                         }
 
                     },
-                    '0'..='9' | '-' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        let offset = self.reader.stream_position().unwrap();
+                    '0'..='9' | '-' | '+' | '.' => {
+                        self.step_back()?;
+                        let offset = self.reader.stream_position().map_err(|err| err.to_string())?;
                         let object_reference = self.read_object_reference();
                         if object_reference.is_err() {
-                            self.reader.seek(SeekFrom::Start(offset)).unwrap();
-                            self.pop_state();
-                            return Ok(PDFToken::Number(self.read_number().unwrap()));
+                            self.return_to(offset)?;
+                            self.pop_state()?;
+                            return Ok(PDFToken::Number(self.read_number()?));
                         } else {
-                            self.pop_state();
+                            self.pop_state()?;
                             return object_reference;
                         }
                     },
                     '<' => {
-                        match self.next_char().unwrap() {
+                        match self.expect_char()? {
                             '<' => {
                                 self.push_state(TokenizerState::DictionaryKey);
                                 return Ok(PDFToken::DictionaryStart);
                             },
-                            'a'..='z' | 'A'..='Z' | '0'..='9' => {
-                                self.pop_state();
-                                let hex_string = self.read_until(vec!['>'], false);
-                                let bytes= self.hex_string_to_bytes(hex_string);
-                                return Ok(PDFToken::HexString(bytes.unwrap()));
+                            'a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '\t' | '\n' | '\r' | '\x0C' | '\0' | '>' => {
+                                self.pop_state()?;
+                                self.step_back()?;
+                                return Ok(PDFToken::HexString(self.read_hex_string()?));
                             },
                             other => {
                                 return Err(format!("Unexpected character `{other}` while parsing dictionary/hex-string start. State: {:?}", state));
@@ -563,8 +745,8 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         }
                     },
                     'n' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![']', ' ', '\n'], true).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![']', ' ', '\n'], true)?.as_str() {
                             "null" => {
                                 return Ok(PDFToken::Null);
                             },
@@ -574,17 +756,21 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         }
                     },
                     '(' => {
-                        self.pop_state();
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
+                        self.pop_state()?;
+                        self.step_back()?;
                         return Ok(PDFToken::String(self.read_literal_string()?));
                     },
                     unhandled_char => return Err(format!("Unhandled char '{unhandled_char}' while looking for dictionary value"))
                 },
-                TokenizerState::ListValue => match self.next_char().unwrap() {
-                    ' ' | '\n' | '\r' => continue,
+                TokenizerState::ListValue => match self.expect_char()? {
+                    ' ' | '\t' | '\n' | '\x0C' | '\r' | '\0' => continue,
+                    '%' => {
+                        self.read_comment()?;
+                        continue;
+                    },
                     ']' => {
                         // Pop List State
-                        self.pop_state();
+                        self.pop_state()?;
                         return Ok(PDFToken::ArrayEnd);
                     },
                     '[' => {
@@ -592,31 +778,28 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         return Ok(PDFToken::ArrayStart);
                     },
                     '/' => {
-                        let name = self.read_until(vec![' ',']'], true);
-                        return Ok(PDFToken::Name(name));
+                        return Ok(PDFToken::Name(self.read_name()?));
                     },
-                    '0'..='9' | '-' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        let offset: u64 = self.reader.stream_position().unwrap();
+                    '0'..='9' | '-' | '+' | '.' => {
+                        self.step_back()?;
+                        let offset: u64 = self.reader.stream_position().map_err(|err| err.to_string())?;
                         let object_reference = self.read_object_reference();
                         if object_reference.is_err() {
-                            self.reader.seek(SeekFrom::Start(offset)).unwrap();
-                            return Ok(PDFToken::Number(self.read_number().unwrap()));
+                            self.return_to(offset)?;
+                            return Ok(PDFToken::Number(self.read_number()?));
                         } else {
                             return object_reference;
                         }
                     },
                     '<' => {
-                        match self.next_char().unwrap() {
+                        match self.expect_char()? {
                             '<' => {
                                 self.push_state(TokenizerState::DictionaryKey);
                                 return Ok(PDFToken::DictionaryStart);
                             },
-                            'a'..='z' | 'A'..='Z' | '0'..='9' => {
-                                // self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                                let hex_string = self.read_until(vec!['>'], false);
-                                let bytes= self.hex_string_to_bytes(hex_string);
-                                return Ok(PDFToken::HexString(bytes.unwrap()));
+                            'a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '\t' | '\n' | '\r' | '\x0C' | '\0' | '>' => {
+                                self.step_back()?;
+                                return Ok(PDFToken::HexString(self.read_hex_string()?));
                             },
                             other => {
                                 return Err(format!("Unexpected character `{other}` while parsing dictionary/hex-string start. State: {:?}", state));
@@ -624,8 +807,8 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         }
                     },
                     'n' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![']', ' ', '\n'], true).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![']', ' ', '\n'], true)?.as_str() {
                             "null" => {
                                 return Ok(PDFToken::Null);
                             },
@@ -635,8 +818,8 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         }
                     },
                     't' | 'f' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                        match self.read_until(vec![']', ' ', '>', '\n'], true).as_str() {
+                        self.step_back()?;
+                        match self.read_until(vec![']', ' ', '>', '\n'], true)?.as_str() {
                             "true" => {
                                 return Ok(PDFToken::Boolean(true));
                             },
@@ -649,7 +832,7 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                         }
                     },
                     '(' => {
-                        self.reader.seek(SeekFrom::Current(-1)).unwrap();
+                        self.step_back()?;
                         return Ok(PDFToken::String(self.read_literal_string()?));
                     },
                     unhandled_char => return Err(format!("Unhandled char '{unhandled_char}' while looking for list value"))
@@ -658,35 +841,44 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                     return Err("next() called in Stream".to_string());
                 },
                 TokenizerState::StreamEnd => {
-                    match self.next_char().unwrap() {
+                    match self.next_char().ok_or("Reached the end of the file while looking for endstream")? {
                         ' ' | '\n' | '\r' => continue,
                         'e' => {
-                            self.reader.seek(SeekFrom::Current(-1)).unwrap();
-                            match self.read_until(vec![' ', '\n', '\r'], false).as_str() {
+                            self.step_back()?;
+                            match self.read_until(vec![' ', '\n', '\r'], false)?.as_str() {
                                 "endstream" => {
-                                    self.pop_state();
+                                    self.pop_state()?;
                                     return Ok(PDFToken::StreamEnd);
                                 },
-                                other => panic!("Found unexpected keyword '{other}' while reading object")
+                                other => return Err(format!("Found unexpected keyword '{other}' while reading object"))
                             }
                         },
                         unhandled_char => return Err(format!("Unhandled char '{unhandled_char}' expected 'streamend'"))
                     }
                 },
                 TokenizerState::XRefSection => {
-                    let first_object_number = self.read_number().unwrap() as u64;
+                    // A section holds any number of subsections, each starting with its first
+                    // object number and entry count, and ends where the trailer begins
+                    self.consume_whitespace()?;
+                    if !self.expect_char()?.is_ascii_digit() {
+                        self.step_back()?;
+                        self.pop_state()?;
+                        return Ok(PDFToken::XRefSectionEnd);
+                    }
+                    self.step_back()?;
+                    let first_object_number = self.read_u64_token()?;
                     self.next_char();
-                    let num_entries = self.read_number().unwrap() as u64;
-                    self.read_until(vec!['\n'], false);
+                    let num_entries = self.read_u64_token()?;
+                    self.read_until(vec!['\n'], false)?;
                     self.push_state(TokenizerState::XRefEntry);
                     return Ok(PDFToken::XRefSubSectionHeader(XRefHeader { first_object_number, num_entries }));
                 },
                 TokenizerState::XRefEntry => {
-                    let byte_offset = self.read_number().unwrap() as u64;
+                    let byte_offset = self.read_u64_token()?;
                     self.next_char();
-                    let generation_number = self.read_number().unwrap() as u64;
+                    let generation_number = self.read_u64_token()?;
 
-                    let free = match self.read_until(vec!['\n'], false).trim() {
+                    let free = match self.read_until(vec!['\n'], false)?.trim() {
                         "f" => true,
                         "n" => false,
                         other => {
@@ -702,12 +894,12 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
                 }
                 TokenizerState::Trailer => {
                     loop {
-                        match self.next_char().unwrap() {
+                        match self.expect_char()? {
                             '\n' => {},
                             '<' => {
-                                let next = self.next_char().unwrap();
+                                let next = self.expect_char()?;
                                 if next == '<' {
-                                    self.pop_state();
+                                    self.pop_state()?;
                                     self.push_state(TokenizerState::DictionaryKey);
                                     return Ok(PDFToken::DictionaryStart);
                                 }
@@ -723,54 +915,53 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
         }
     }
 
+    /// The token `next` would return, without consuming it. In the `Stream` state that is
+    /// the `StreamEnd` after the data, found by scanning for `endstream`.
     fn peak_next(&mut self) -> Result<PDFToken, String> {
         let state_stack_before_peak = self.state_stack.clone();
-        let offset_before_peak = self.reader.stream_position().unwrap();
-        let next_token = self.next();
+        let offset_before_peak = self.reader.stream_position().map_err(|err| err.to_string())?;
+        let next_token = self.skip_stream_data().and_then(|_| self.next());
         self.state_stack = state_stack_before_peak;
         debug!("Restoring state stack after peak: {:?}", self.state_stack.clone());
-        self.reader.seek(SeekFrom::Start(offset_before_peak)).unwrap();
+        self.return_to(offset_before_peak)?;
         next_token
     }
 
+    /// Like `peak_next` for several tokens. The position and state are restored even when
+    /// one of them fails to tokenize.
     fn peak_multiple(&mut self, num_tokens: u32) -> Result<Vec<PDFToken>, String> {
-        let offset_before_peak = self.reader.stream_position().unwrap();
+        let offset_before_peak = self.reader.stream_position().map_err(|err| err.to_string())?;
         let state_stack_before_peak = self.state_stack.clone();
 
-        let mut tokens = Vec::<PDFToken>::with_capacity(num_tokens as usize);
-        for _ in 1..num_tokens {
-            match self.next() {
-                Ok(token) => {
-                    tokens.push(token)
-                },
-                Err(err) => {
-                    return Err(err)
-                }
-            }
-        }
+        let tokens = self.skip_stream_data().and_then(|_| {
+            (1..num_tokens).map(|_| self.next()).collect::<Result<Vec<PDFToken>, String>>()
+        });
         self.state_stack = state_stack_before_peak;
-        println!("Restoring state stack after peak multiple: {:?}", self.state_stack.clone());
-        self.reader.seek(SeekFrom::Start(offset_before_peak));
-        Ok(tokens)
+        debug!("Restoring state stack after peak multiple: {:?}", self.state_stack);
+        self.return_to(offset_before_peak)?;
+        tokens
     }
 
-    fn get_offset(&mut self) -> u64 {
-        self.reader.stream_position().unwrap()
+    fn get_offset(&mut self) -> Result<u64, String> {
+        self.reader.stream_position().map_err(|err| err.to_string())
     }
 
-    fn get_stream(&mut self, num_bytes: usize) -> Vec<u8> {
-        let mut bytes = vec![0; num_bytes];
-
-        self.reader.read_exact(&mut bytes).unwrap();
+    fn get_stream(&mut self, num_bytes: usize) -> Result<Vec<u8>, String> {
+        // A /Length running past the end of the file leaves the stream short, and the
+        // missing endstream is reported by the caller
+        let mut bytes = vec![];
+        self.reader.by_ref().take(num_bytes as u64).read_to_end(&mut bytes).map_err(|err| err.to_string())?;
 
-        self.pop_state();
+        self.pop_state()?;
         self.push_state(TokenizerState::StreamEnd);
 
-        bytes
+        Ok(bytes)
     }
 
     fn get_xref_table(&mut self, num_entries: u64) -> Result<Vec<XRefEntry>, String> {
-        assert!(self.get_state() == TokenizerState::XRefEntry);
+        if self.get_state()? != TokenizerState::XRefEntry {
+            return Err(format!("Expected xref entries, tokenizer is in state {:?}", self.get_state()?));
+        }
         let mut entries: Vec<XRefEntry> = vec![];
 
         for _ in 0..num_entries {
@@ -788,9 +979,300 @@ impl<T: Read + Seek> PDFTokenize for Tokenizer<T> {
             entries.push(entry);
         }
 
-        self.state_stack.pop();
+        // Back to the section, which may have another subsection
         self.state_stack.pop();
 
         Ok(entries)
     }
-}
\ No newline at end of file
+
+    /// Offset just past the last `%%EOF` marker. Anything after it is not part of the document.
+    fn last_document_end(&mut self) -> Option<u64> {
+        const MARKER: &[u8] = b"%%EOF";
+        const CHUNK_SIZE: u64 = 1024;
+
+        let offset_before_search = self.reader.stream_position().ok()?;
+        let length = self.reader.seek(SeekFrom::End(0)).ok()?;
+
+        let mut document_end = None;
+        let mut chunk_end = length;
+        while chunk_end > 0 && document_end.is_none() {
+            let chunk_start = chunk_end.saturating_sub(CHUNK_SIZE);
+            // Overlap with the following chunk so a marker split between chunks is still found
+            let read_end = (chunk_end + MARKER.len() as u64 - 1).min(length);
+            let mut chunk = vec![0; (read_end - chunk_start) as usize];
+            self.reader.seek(SeekFrom::Start(chunk_start)).ok()?;
+            self.reader.read_exact(&mut chunk).ok()?;
+
+            document_end = chunk
+                .windows(MARKER.len())
+                .rposition(|window| window == MARKER)
+                .map(|position| chunk_start + (position + MARKER.len()) as u64);
+            chunk_end = chunk_start;
+        }
+
+        self.reader.seek(SeekFrom::Start(offset_before_search)).ok()?;
+        document_end
+    }
+
+    /// Continues tokenizing after a `%%EOF`, e.g. into an incremental update
+    fn resume(&mut self) {
+        self.state_stack = vec![TokenizerState::Start];
+    }
+
+    /// Jumps to the start of an object or xref section, for reading the file out of order
+    fn seek_to(&mut self, offset: u64) -> Result<(), String> {
+        self.reader.seek(SeekFrom::Start(offset)).map_err(|err| err.to_string())?;
+        self.resume();
+        Ok(())
+    }
+
+    /// Moves past the object starting at `offset` after it failed to parse: to just after
+    /// its `endobj`, or to the next object header if that comes first
+    fn skip_object(&mut self, offset: u64) -> Result<(), String> {
+        let object_header = &*OBJECT_HEADER_BYTES_PATTERN;
+
+        self.reader.seek(SeekFrom::Start(offset)).map_err(|err| err.to_string())?;
+        let mut rest: Vec<u8> = vec![];
+        self.reader.read_to_end(&mut rest).map_err(|err| err.to_string())?;
+
+        let body_start = object_header.find(&rest).map_or(0, |header| header.end());
+        let object_end = rest[body_start..]
+            .windows(b"endobj".len())
+            .position(|window| window == b"endobj")
+            .map(|position| body_start + position + b"endobj".len());
+        let next_header = object_header.find_at(&rest, body_start).map(|header| header.start());
+
+        let skip_to = match (object_end, next_header) {
+            (Some(object_end), Some(next_header)) => object_end.min(next_header),
+            (Some(skip_to), None) | (None, Some(skip_to)) => skip_to,
+            (None, None) => rest.len()
+        };
+
+        self.seek_to(offset + skip_to as u64)
+    }
+
+    /// The length of the stream data starting at the current position, found by searching
+    /// for `endstream` rather than trusting `/Length`. The position is left unchanged.
+    fn scan_stream_length(&mut self) -> Option<usize> {
+        const MARKER: &[u8] = b"endstream";
+        const CHUNK_SIZE: usize = 4096;
+
+        let stream_start = self.reader.stream_position().ok()?;
+        let mut data: Vec<u8> = vec![];
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let marker_position = loop {
+            let read = self.reader.read(&mut chunk).unwrap_or(0);
+            if read == 0 {
+                break None;
+            }
+            // Search again from just before the new chunk, in case it splits the marker
+            let search_start = data.len().saturating_sub(MARKER.len() - 1);
+            data.extend_from_slice(&chunk[..read]);
+            if let Some(position) = data[search_start..].windows(MARKER.len()).position(|window| window == MARKER) {
+                break Some(search_start + position);
+            }
+        };
+        self.reader.seek(SeekFrom::Start(stream_start)).ok()?;
+
+        // The end-of-line marker before endstream is not part of the data
+        let data = &data[..marker_position?];
+        let eol_length = if data.ends_with(b"\r\n") {
+            2
+        } else if data.ends_with(b"\n") || data.ends_with(b"\r") {
+            1
+        } else {
+            0
+        };
+        Some(data.len() - eol_length)
+    }
+
+    /// Every `N G obj` header in the file with its offset, in file order, found by scanning
+    /// the raw bytes instead of trusting the xref. The position is left unchanged.
+    fn find_object_headers(&mut self) -> Vec<(PDFObjectHeader, u64)> {
+        let object_header = &*OBJECT_HEADER_BYTES_PATTERN;
+        let bytes = match self.read_whole_input() {
+            Some(bytes) => bytes,
+            None => return vec![]
+        };
+
+        object_header
+            .captures_iter(&bytes)
+            .filter_map(|captures| {
+                let object_number = captures.get(1)?;
+                // A match starting inside a longer number is not a header
+                if object_number.start() > 0 && bytes[object_number.start() - 1].is_ascii_digit() {
+                    return None;
+                }
+                let header = PDFObjectHeader {
+                    object_number: std::str::from_utf8(object_number.as_bytes()).ok()?.parse().ok()?,
+                    generation_number: std::str::from_utf8(captures.get(2)?.as_bytes()).ok()?.parse().ok()?
+                };
+                Some((header, object_number.start() as u64))
+            })
+            .collect()
+    }
+
+    /// The offset of the last `trailer` keyword in the file. The position is left unchanged.
+    fn find_last_trailer(&mut self) -> Option<u64> {
+        const MARKER: &[u8] = b"trailer";
+
+        let bytes = self.read_whole_input()?;
+        bytes
+            .windows(MARKER.len())
+            .rposition(|window| window == MARKER)
+            .map(|position| position as u64)
+    }
+
+    /// The offset named by the last `startxref` in the file, found without reading the
+    /// rest of the document
+    fn last_startxref(&mut self) -> Option<u64> {
+        const MARKER: &[u8] = b"startxref";
+        const TAIL_SIZE: u64 = 1024;
+
+        let offset_before_search = self.reader.stream_position().ok()?;
+        let end = match self.last_document_end() {
+            Some(document_end) => document_end,
+            None => self.reader.seek(SeekFrom::End(0)).ok()?
+        };
+
+        let tail_start = end.saturating_sub(TAIL_SIZE);
+        let mut tail = vec![0; (end - tail_start) as usize];
+        self.reader.seek(SeekFrom::Start(tail_start)).ok()?;
+        self.reader.read_exact(&mut tail).ok()?;
+        self.reader.seek(SeekFrom::Start(offset_before_search)).ok()?;
+
+        let position = tail.windows(MARKER.len()).rposition(|window| window == MARKER)?;
+        let offset: String = tail[position + MARKER.len()..]
+            .iter()
+            .map(|byte| *byte as char)
+            .skip_while(|c| c.is_ascii_whitespace())
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        offset.parse::<u64>().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn tokenizer(source: &[u8]) -> Tokenizer<Cursor<Vec<u8>>> {
+        Tokenizer::new(Cursor::new(source.to_vec()))
+    }
+
+    /// The tokens of an object with `body`, between its header and `endobj`
+    fn object_tokens(body: &[u8]) -> Vec<PDFToken> {
+        let mut tokenizer = tokenizer(&[b"1 0 obj\n", body, b"\nendobj\n"].concat());
+        assert_eq!(tokenizer.next(), Ok(PDFToken::ObjectHeader(PDFObjectHeader { object_number: 1, generation_number: 0 })));
+        let mut tokens = vec![];
+        loop {
+            match tokenizer.next().unwrap() {
+                PDFToken::ObjectEnd => return tokens,
+                token => tokens.push(token)
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_in_sloppy_but_unambiguous_forms() {
+        assert_eq!(parse_number("1."), Some(1.0));
+        assert_eq!(parse_number("+5"), Some(5.0));
+        assert_eq!(parse_number("-.5"), Some(-0.5));
+        assert_eq!(parse_number("-12"), Some(-12.0));
+    }
+
+    #[test]
+    fn malformed_numbers_are_rejected() {
+        for token in ["1.2.3", "--5", "+-5", "-+5", "-", ".", "5-"] {
+            assert_eq!(parse_number(token), None, "{token}");
+        }
+        let mut tokenizer = tokenizer(b"1.2.3]");
+        assert_eq!(tokenizer.read_number(), Err("Invalid number '1.2.3'".to_string()));
+    }
+
+    #[test]
+    fn startxref_offsets_past_f64_precision_are_exact() {
+        // 2^53 + 1, which an f64 would round to 2^53
+        let source = b"startxref\n9007199254740993\n%%EOF\n";
+        assert!(matches!(tokenizer(source).next(), Ok(PDFToken::StartXRef(9_007_199_254_740_993))));
+        assert_eq!(tokenizer(source).last_startxref(), Some(9_007_199_254_740_993));
+    }
+
+    #[test]
+    fn hex_strings_may_hold_whitespace_or_nothing() {
+        use PDFToken::*;
+        assert_eq!(object_tokens(b"<< /X < 48 65 > /Y <> /Z <\n4865\n> >>"), vec![
+            DictionaryStart,
+            Name("X".to_string()), HexString(b"He".to_vec()),
+            Name("Y".to_string()), HexString(vec![]),
+            Name("Z".to_string()), HexString(b"He".to_vec()),
+            DictionaryEnd
+        ]);
+        assert_eq!(object_tokens(b"[< 48 65 > <> <4\r\n8>]"), vec![
+            ArrayStart, HexString(b"He".to_vec()), HexString(vec![]), HexString(b"H".to_vec()), ArrayEnd
+        ]);
+    }
+
+    #[test]
+    fn a_failed_peek_leaves_the_position_and_state_alone() {
+        let mut tokenizer = tokenizer(b"1 0 obj\n<< /A ) >>\nendobj\n");
+        tokenizer.next().unwrap();
+        let offset = tokenizer.get_offset().unwrap();
+
+        assert!(tokenizer.peak_multiple(4).is_err());
+        assert_eq!(tokenizer.get_offset(), Ok(offset));
+        assert_eq!(tokenizer.next(), Ok(PDFToken::DictionaryStart));
+    }
+
+    #[test]
+    fn unbalanced_dictionary_ends_are_errors() {
+        let mut tokenizer = tokenizer(b">> >>");
+        tokenizer.state_stack = vec![TokenizerState::DictionaryKey];
+
+        assert_eq!(tokenizer.next(), Ok(PDFToken::DictionaryEnd));
+        assert_eq!(tokenizer.next(), Err("Tokenizer state stack is empty".to_string()));
+        assert!(tokenizer.get_stream(1).is_err());
+    }
+
+    #[test]
+    fn names_end_at_any_whitespace_or_delimiter() {
+        use PDFToken::*;
+        let name = |name: &str| Name(name.to_string());
+        assert_eq!(object_tokens(b"[/Foo\n/Bar\r/Baz\t/Qux]"), vec![
+            ArrayStart, name("Foo"), name("Bar"), name("Baz"), name("Qux"), ArrayEnd
+        ]);
+        assert_eq!(object_tokens(b"<</ProcSet [/PDF\n/Text]/Filter[/AHx\r\n/Fl]/Type/Font/F(x)/H<41>/D<</K/V>>>>"), vec![
+            DictionaryStart,
+            name("ProcSet"), ArrayStart, name("PDF"), name("Text"), ArrayEnd,
+            name("Filter"), ArrayStart, name("AHx"), name("Fl"), ArrayEnd,
+            name("Type"), name("Font"),
+            name("F"), String("x".to_string()),
+            name("H"), HexString(b"A".to_vec()),
+            name("D"), DictionaryStart, name("K"), name("V"), DictionaryEnd,
+            DictionaryEnd
+        ]);
+    }
+
+    #[test]
+    fn comments_inside_dictionaries_and_arrays_are_whitespace() {
+        use PDFToken::*;
+        assert_eq!(object_tokens(b"% before\n<< /A % after a key\n1 % after a value\n/B [2 % in an array\n3]%tight\n>>"), vec![
+            DictionaryStart,
+            Name("A".to_string()), Number(1.0),
+            Name("B".to_string()), ArrayStart, Number(2.0), Number(3.0), ArrayEnd,
+            DictionaryEnd
+        ]);
+    }
+
+    #[test]
+    fn name_escapes_are_decoded() {
+        use PDFToken::*;
+        assert_eq!(object_tokens(b"<< /A#42C /Lime#20Green /K [/Paired#28#29 /Keep#2] >>"), vec![
+            DictionaryStart,
+            Name("ABC".to_string()), Name("Lime Green".to_string()),
+            Name("K".to_string()), ArrayStart, Name("Paired()".to_string()), Name("Keep#2".to_string()), ArrayEnd,
+            DictionaryEnd
+        ]);
+    }
+}